@@ -0,0 +1,68 @@
+//! Compression and decompression throughput at each level
+//!
+//! The compression ratio of each input is also printed before the measurements start.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fastlz_rs::*;
+
+mod corpus;
+
+const LEVELS: [CompressionLevel; 2] = [CompressionLevel::Level1, CompressionLevel::Level2];
+
+fn report_ratios(corpus: &[(String, Vec<u8>)]) {
+    let mut state = CompressState::new();
+    for (name, data) in corpus {
+        for level in LEVELS {
+            let comp = state.compress_to_vec(data, level).unwrap();
+            println!(
+                "{}/{:?}: {} -> {} bytes, ratio {:.3}",
+                name,
+                level,
+                data.len(),
+                comp.len(),
+                data.len() as f64 / comp.len() as f64
+            );
+        }
+    }
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let corpus = corpus::all();
+    report_ratios(&corpus);
+
+    let mut state = CompressState::new();
+    let mut group = c.benchmark_group("compress");
+    for (name, data) in &corpus {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for level in LEVELS {
+            let mut out = vec![0; compress_bound(data.len())];
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", level), name),
+                data,
+                |b, data| b.iter(|| state.compress_to_buf(black_box(data), &mut out, level)),
+            );
+        }
+    }
+    group.finish();
+
+    // throughput is measured in terms of the decompressed size, so that it is comparable
+    let mut group = c.benchmark_group("decompress");
+    for (name, data) in &corpus {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for level in LEVELS {
+            let comp = state.compress_to_vec(data, level).unwrap();
+            let mut out = vec![0; data.len()];
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", level), name),
+                &comp,
+                |b, comp| b.iter(|| decompress_to_buf(black_box(comp), &mut out)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_codec);
+criterion_main!(benches);
@@ -0,0 +1,107 @@
+//! Comparison with other fast pure-Rust codecs
+//!
+//! Each codec is used in its raw block mode (without framing or checksums),
+//! which is closest to a raw FastLZ stream. Compression ratios are printed before the measurements start.
+//!
+//! This is not run by a plain `cargo bench`. Use `cargo bench --bench codecs`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fastlz_rs::*;
+
+mod corpus;
+
+/// A codec being compared, which compresses into and decompresses from a preallocated buffer
+struct Codec {
+    name: &'static str,
+    bound: fn(usize) -> usize,
+    compress: fn(&[u8], &mut [u8]) -> usize,
+    decompress: fn(&[u8], &mut [u8]) -> usize,
+}
+
+// the compression state is created every time, as the other codecs' one-shot functions do
+const CODECS: [Codec; 4] = [
+    Codec {
+        name: "fastlz-rs-l1",
+        bound: compress_bound,
+        compress: |inp, outp| {
+            CompressState::new()
+                .compress_to_buf(inp, outp, CompressionLevel::Level1)
+                .unwrap()
+        },
+        decompress: |inp, outp| decompress_to_buf(inp, outp).unwrap(),
+    },
+    Codec {
+        name: "fastlz-rs-l2",
+        bound: compress_bound,
+        compress: |inp, outp| {
+            CompressState::new()
+                .compress_to_buf(inp, outp, CompressionLevel::Level2)
+                .unwrap()
+        },
+        decompress: |inp, outp| decompress_to_buf(inp, outp).unwrap(),
+    },
+    Codec {
+        name: "lz4_flex",
+        bound: lz4_flex::block::get_maximum_output_size,
+        compress: |inp, outp| lz4_flex::block::compress_into(inp, outp).unwrap(),
+        decompress: |inp, outp| lz4_flex::block::decompress_into(inp, outp).unwrap(),
+    },
+    Codec {
+        name: "snap",
+        bound: snap::raw::max_compress_len,
+        compress: |inp, outp| snap::raw::Encoder::new().compress(inp, outp).unwrap(),
+        decompress: |inp, outp| snap::raw::Decoder::new().decompress(inp, outp).unwrap(),
+    },
+];
+
+fn bench_codecs(c: &mut Criterion) {
+    let corpus = corpus::all();
+
+    let mut compressed = Vec::new();
+    for (name, data) in &corpus {
+        for codec in &CODECS {
+            let mut comp = vec![0; (codec.bound)(data.len())];
+            let len = (codec.compress)(data, &mut comp);
+            comp.truncate(len);
+            println!(
+                "{}/{}: {} -> {} bytes, ratio {:.3}",
+                name,
+                codec.name,
+                data.len(),
+                len,
+                data.len() as f64 / len as f64
+            );
+            compressed.push(comp);
+        }
+    }
+    let mut compressed = compressed.iter();
+
+    // throughput is always measured in terms of the decompressed size, so that it is comparable
+    for (name, data) in &corpus {
+        let mut group = c.benchmark_group(format!("codecs/compress/{}", name));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for codec in &CODECS {
+            let mut out = vec![0; (codec.bound)(data.len())];
+            group.bench_with_input(BenchmarkId::from_parameter(codec.name), data, |b, data| {
+                b.iter(|| (codec.compress)(black_box(data), &mut out))
+            });
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("codecs/decompress/{}", name));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        for codec in &CODECS {
+            let comp = compressed.next().unwrap();
+            let mut out = vec![0; data.len()];
+            group.bench_with_input(BenchmarkId::from_parameter(codec.name), comp, |b, comp| {
+                b.iter(|| (codec.decompress)(black_box(comp), &mut out))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_codecs);
+criterion_main!(benches);
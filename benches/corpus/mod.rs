@@ -0,0 +1,101 @@
+//! Inputs shared by the benchmarks
+//!
+//! The built-in inputs are:
+//! * `text`: this crate's own source code
+//! * `binary`: the WebAssembly build of the reference C code (which is small, so mostly measures overhead)
+//! * `logs`: generated log lines, which are very repetitive
+//! * `random`: generated random bytes, which are incompressible
+//!
+//! Every file in the directory named by the `FASTLZ_BENCH_CORPUS` environment variable
+//! (e.g. a copy of the Silesia corpus) is also included.
+
+use std::{env, fs, path::PathBuf};
+
+/// Size of each generated input
+const GENERATED_SZ: usize = 1 << 20;
+
+/// Simple LCG, so that the generated inputs are the same every time
+struct Lcg(u32);
+impl Lcg {
+    /// Only the upper bits are returned, since the lower bits repeat quickly
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+        self.0 >> 16
+    }
+}
+
+fn text() -> Vec<u8> {
+    let d = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src");
+    let mut paths: Vec<_> = fs::read_dir(d)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|x| x == "rs"))
+        .collect();
+    paths.sort();
+    paths.iter().flat_map(|p| fs::read(p).unwrap()).collect()
+}
+
+fn binary() -> Vec<u8> {
+    let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    fs::read(d.join("testtool/fastlz.wasm")).unwrap()
+}
+
+fn logs() -> Vec<u8> {
+    const LEVELS: [&str; 4] = ["INFO", "INFO", "DEBUG", "WARN"];
+    const MESSAGES: [&str; 5] = [
+        "accepted connection from",
+        "request completed for",
+        "cache miss for",
+        "retrying upload for",
+        "closed connection from",
+    ];
+    let mut rng = Lcg(1);
+    let mut time = 1_700_000_000_000u64;
+    let mut ret = Vec::new();
+    while ret.len() < GENERATED_SZ {
+        time += (rng.next() % 50) as u64;
+        let r = rng.next() as usize;
+        let line = format!(
+            "{}.{:03} [{:5}] worker-{}: {} 10.0.{}.{} (id={:04x})\n",
+            time / 1000,
+            time % 1000,
+            LEVELS[r % LEVELS.len()],
+            r % 8,
+            MESSAGES[r / 8 % MESSAGES.len()],
+            r / 64 % 4,
+            r / 256 % 256,
+            rng.next(),
+        );
+        ret.extend_from_slice(line.as_bytes());
+    }
+    ret.truncate(GENERATED_SZ);
+    ret
+}
+
+fn random() -> Vec<u8> {
+    let mut rng = Lcg(1);
+    (0..GENERATED_SZ).map(|_| (rng.next() >> 8) as u8).collect()
+}
+
+/// All of the inputs, along with their names
+pub fn all() -> Vec<(String, Vec<u8>)> {
+    let mut ret = vec![
+        ("text".to_string(), text()),
+        ("binary".to_string(), binary()),
+        ("logs".to_string(), logs()),
+        ("random".to_string(), random()),
+    ];
+    if let Some(d) = env::var_os("FASTLZ_BENCH_CORPUS") {
+        let mut paths: Vec<_> = fs::read_dir(d)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.is_file())
+            .collect();
+        paths.sort();
+        for p in paths {
+            let name = p.file_name().unwrap().to_string_lossy().into_owned();
+            ret.push((name, fs::read(&p).unwrap()));
+        }
+    }
+    ret
+}
@@ -0,0 +1,199 @@
+//! Comparison with the reference C code
+//!
+//! The C code is run from the WebAssembly build in `testtool/`, using the wasmi interpreter.
+//! Its compression ratios are exactly those of the native C code, but its throughput is
+//! much lower, so the throughput numbers are only useful for noticing changes over time.
+//! With the `c-reference` feature, the natively built C code is also measured.
+//!
+//! This is not run by a plain `cargo bench`. Use `cargo bench --bench reference`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fastlz_rs::*;
+
+mod corpus;
+
+const WASM_PAGE_SZ: usize = 65536;
+
+const LEVELS: [CompressionLevel; 2] = [CompressionLevel::Level1, CompressionLevel::Level2];
+
+fn c_level(level: CompressionLevel) -> u32 {
+    if level == CompressionLevel::Level1 {
+        1
+    } else {
+        2
+    }
+}
+
+/// The reference code, instantiated once with room for inputs and outputs up to a fixed size
+struct Reference {
+    store: wasmi::Store<()>,
+    memory: wasmi::Memory,
+    compress: wasmi::TypedFunc<(u32, u32, u32, u32), u32>,
+    decompress: wasmi::TypedFunc<(u32, u32, u32, u32), u32>,
+    /// Start of the input buffer, which is followed by the output buffer
+    base: usize,
+    /// Size of each buffer
+    cap: usize,
+}
+impl Reference {
+    fn new(max_len: usize) -> Self {
+        let engine = wasmi::Engine::default();
+        let module =
+            wasmi::Module::new(&engine, include_bytes!("../testtool/fastlz.wasm")).unwrap();
+        let linker = wasmi::Linker::new(&engine);
+        let mut store = wasmi::Store::new(&engine, ());
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        let compress = instance
+            .get_typed_func(&store, "fastlz_compress_level")
+            .unwrap();
+        let decompress = instance
+            .get_typed_func(&store, "fastlz_decompress")
+            .unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+
+        // [stack] [unused] | [input] [output] |
+        let cap = compress_bound(max_len);
+        let pages = (2 * cap).div_ceil(WASM_PAGE_SZ);
+        let base = memory.grow(&mut store, pages as u32).unwrap() as usize * WASM_PAGE_SZ;
+
+        Self {
+            store,
+            memory,
+            compress,
+            decompress,
+            base,
+            cap,
+        }
+    }
+
+    fn put_input(&mut self, inp: &[u8]) {
+        self.memory.data_mut(&mut self.store)[self.base..self.base + inp.len()]
+            .copy_from_slice(inp);
+    }
+
+    fn output(&self, len: usize) -> &[u8] {
+        let start = self.base + self.cap;
+        &self.memory.data(&self.store)[start..start + len]
+    }
+
+    /// Returns the compressed size
+    fn compress(&mut self, level: CompressionLevel, inp: &[u8]) -> usize {
+        self.put_input(inp);
+        let args = (
+            c_level(level),
+            self.base as u32,
+            inp.len() as u32,
+            (self.base + self.cap) as u32,
+        );
+        self.compress.call(&mut self.store, args).unwrap() as usize
+    }
+
+    /// Returns the decompressed size
+    fn decompress(&mut self, inp: &[u8]) -> usize {
+        self.put_input(inp);
+        let args = (
+            self.base as u32,
+            inp.len() as u32,
+            (self.base + self.cap) as u32,
+            self.cap as u32,
+        );
+        self.decompress.call(&mut self.store, args).unwrap() as usize
+    }
+}
+
+fn report_ratios(corpus: &[(String, Vec<u8>)], reference: &mut Reference) {
+    let mut state = CompressState::new();
+    let mut c_compatible = CompressOptions::default();
+    c_compatible.c_compatible = true;
+    for (name, data) in corpus {
+        for level in LEVELS {
+            let ours = state.compress_to_vec(data, level).unwrap();
+            let theirs_len = reference.compress(level, data);
+            // also check that the comparison is fair, in that both sides agree on the format
+            let identical = state
+                .compress_to_vec_with_options(data, level, &c_compatible)
+                .unwrap()
+                == reference.output(theirs_len);
+            assert!(
+                identical,
+                "{}/{:?}: c_compatible output differs",
+                name, level
+            );
+            println!(
+                "{}/{:?}: {} -> fastlz-rs {} bytes (ratio {:.3}), C {} bytes (ratio {:.3})",
+                name,
+                level,
+                data.len(),
+                ours.len(),
+                data.len() as f64 / ours.len() as f64,
+                theirs_len,
+                data.len() as f64 / theirs_len as f64
+            );
+        }
+    }
+}
+
+fn bench_reference(c: &mut Criterion) {
+    let corpus = corpus::all();
+    let max_len = corpus.iter().map(|(_, data)| data.len()).max().unwrap();
+    let mut reference = Reference::new(max_len);
+    report_ratios(&corpus, &mut reference);
+
+    let mut state = CompressState::new();
+    for level in LEVELS {
+        let mut group = c.benchmark_group(format!("reference/compress/{:?}", level));
+        for (name, data) in &corpus {
+            group.throughput(Throughput::Bytes(data.len() as u64));
+            let mut out = vec![0; compress_bound(data.len())];
+            group.bench_with_input(BenchmarkId::new("fastlz-rs", name), data, |b, data| {
+                b.iter(|| state.compress_to_buf(black_box(data), &mut out, level))
+            });
+            group.bench_with_input(BenchmarkId::new("c-wasm", name), data, |b, data| {
+                b.iter(|| reference.compress(level, black_box(data)))
+            });
+            #[cfg(feature = "c-reference")]
+            {
+                // (the C code wants more room than compress_bound)
+                let mut out = vec![0; usize::max(66, data.len() + data.len().div_ceil(20))];
+                group.bench_with_input(BenchmarkId::new("c-native", name), data, |b, data| {
+                    b.iter(|| {
+                        fastlz_rs::c_reference::compress_level(
+                            c_level(level),
+                            black_box(data),
+                            &mut out,
+                        )
+                    })
+                });
+            }
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("reference/decompress/{:?}", level));
+        for (name, data) in &corpus {
+            group.throughput(Throughput::Bytes(data.len() as u64));
+            let comp = state.compress_to_vec(data, level).unwrap();
+            let mut out = vec![0; data.len()];
+            group.bench_with_input(BenchmarkId::new("fastlz-rs", name), &comp, |b, comp| {
+                b.iter(|| decompress_to_buf(black_box(comp), &mut out))
+            });
+            group.bench_with_input(BenchmarkId::new("c-wasm", name), &comp, |b, comp| {
+                b.iter(|| reference.decompress(black_box(comp)))
+            });
+            #[cfg(feature = "c-reference")]
+            group.bench_with_input(BenchmarkId::new("c-native", name), &comp, |b, comp| {
+                b.iter(|| fastlz_rs::c_reference::decompress(black_box(comp), &mut out))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_reference);
+criterion_main!(benches);
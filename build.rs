@@ -0,0 +1,20 @@
+//! Builds the C reference code, only with the development-only `c-reference` feature
+
+fn main() {
+    #[cfg(feature = "c-reference")]
+    {
+        // (the same checkout that testtool/build-wasm.sh uses, unless pointed elsewhere)
+        println!("cargo::rerun-if-env-changed=FASTLZ_C_SRC");
+        let src = std::env::var_os("FASTLZ_C_SRC")
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| "testtool/FastLZ/fastlz.c".into());
+        assert!(
+            src.exists(),
+            "{} not found, clone https://github.com/ariya/FastLZ into testtool/ \
+             or set FASTLZ_C_SRC to its fastlz.c",
+            src.display()
+        );
+        println!("cargo::rerun-if-changed={}", src.display());
+        cc::Build::new().file(&src).opt_level(2).compile("fastlz");
+    }
+}
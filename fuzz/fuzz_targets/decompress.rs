@@ -0,0 +1,23 @@
+//! Arbitrary input to the decompressor must only ever produce errors, never panics
+//! (or unbounded allocations)
+//!
+//! Inputs which fail can be added to `tests/corpus/`, which the crate's own tests replay.
+
+#![no_main]
+
+use fastlz_rs::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let vec = decompress_to_vec(data, None);
+
+    // a buffer only needs to be as large as the output, whatever the input was
+    let mut buf = vec![0; 1 << 16];
+    let res = decompress_to_buf(data, &mut buf);
+    match (vec, res) {
+        (Ok(vec), Ok(len)) => assert_eq!(vec[..], buf[..len]),
+        (Ok(vec), Err(DecompressError::OutputTooSmall { .. })) => assert!(vec.len() > buf.len()),
+        (Err(_), Err(_)) => {}
+        (vec, res) => panic!("Vec gave {:?}, buffer gave {:?}", vec.map(|v| v.len()), res),
+    }
+});
@@ -0,0 +1,50 @@
+//! Streams must mean the same thing to this crate and to the reference C code
+//!
+//! Output from either compressor must decompress back to the input with the other decompressor,
+//! and with `c_compatible`, the compressed output must be identical.
+//! The C code is run from the WebAssembly build in `testtool/`, using the crate's `wasmtester`.
+
+#![no_main]
+
+use std::cell::RefCell;
+
+use fastlz_rs::wasmtester::FastLZWasm;
+use fastlz_rs::*;
+use libfuzzer_sys::fuzz_target;
+
+/// Longer inputs are skipped, to keep each run quick
+const MAX_LEN: usize = 1 << 16;
+
+thread_local! {
+    static REFERENCE: RefCell<FastLZWasm> = RefCell::new(FastLZWasm::new());
+}
+
+fuzz_target!(|data: &[u8]| {
+    // (the C code can't represent empty output)
+    if data.is_empty() || data.len() > MAX_LEN {
+        return;
+    }
+
+    let mut state = CompressState::new();
+    let mut c_compatible = CompressOptions::default();
+    c_compatible.c_compatible = true;
+    REFERENCE.with_borrow_mut(|reference| {
+        for (level, c_level) in [(CompressionLevel::Level1, 1), (CompressionLevel::Level2, 2)] {
+            let ours = state.compress_to_vec(data, level).unwrap();
+            assert_eq!(reference.fastlz_decompress(&ours), data, "{:?}", level);
+
+            let theirs = reference.fastlz_compress_level(c_level, data).to_vec();
+            assert_eq!(
+                decompress_to_vec(&theirs, Some(data.len())).unwrap(),
+                data,
+                "{:?}",
+                level
+            );
+
+            let ours = state
+                .compress_to_vec_with_options(data, level, &c_compatible)
+                .unwrap();
+            assert_eq!(ours, theirs, "{:?}", level);
+        }
+    });
+});
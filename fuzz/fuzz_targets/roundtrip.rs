@@ -0,0 +1,22 @@
+//! Anything compressed must decompress back to the original, at every level
+//!
+//! Inputs which fail can be added to `tests/corpus/`, which the crate's own tests replay.
+
+#![no_main]
+
+use fastlz_rs::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut state = CompressState::new();
+    for level in [
+        CompressionLevel::Level1,
+        CompressionLevel::Level2,
+        CompressionLevel::Default,
+    ] {
+        let comp = state.compress_to_vec(data, level).unwrap();
+        assert!(comp.len() <= compress_bound(data.len()));
+        let decomp = decompress_to_vec(&comp, Some(data.len())).unwrap();
+        assert_eq!(decomp, data, "{:?}", level);
+    }
+});
@@ -0,0 +1,417 @@
+//! Accelerated versions of the innermost loops
+//!
+//! With the `unsafe-perf` feature, vector instructions are used on x86-64 (SSE2, plus AVX2
+//! for comparisons where available) and little-endian AArch64 (NEON). With `std`, AVX2 support
+//! is detected at runtime. Otherwise, only instruction sets enabled at compile time
+//! (e.g. with `-C target-cpu=native`) are used. Everything else falls back to portable code
+//! (or `core::simd` with the `portable-simd` feature, which needs no unsafe code).
+
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+use core::mem::MaybeUninit;
+
+#[cfg(all(
+    feature = "unsafe-perf",
+    not(feature = "portable-simd"),
+    target_arch = "aarch64",
+    target_feature = "neon",
+    target_endian = "little"
+))]
+use aarch64 as vector;
+#[cfg(not(any(
+    feature = "portable-simd",
+    all(
+        feature = "unsafe-perf",
+        any(
+            target_arch = "x86_64",
+            all(
+                target_arch = "aarch64",
+                target_feature = "neon",
+                target_endian = "little"
+            )
+        )
+    )
+)))]
+use portable as vector;
+#[cfg(feature = "portable-simd")]
+use simd as vector;
+#[cfg(all(
+    feature = "unsafe-perf",
+    not(feature = "portable-simd"),
+    target_arch = "x86_64"
+))]
+use x86 as vector;
+
+/// Number of bytes handled at once by the vectorized paths
+const VECTOR_SZ: usize = 16;
+
+/// Count the number of identical bytes at the start of `a` and `b`
+#[cfg(feature = "compress")]
+#[inline]
+pub(crate) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let len = usize::min(a.len(), b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    // most comparisons are just checking whether a match exists at all
+    if len < VECTOR_SZ {
+        return portable::common_prefix_len(a, b);
+    }
+    // and most matches are short, so check one word before dispatching to the vector code
+    let diff = u64::from_le_bytes(a[..8].try_into().unwrap())
+        ^ u64::from_le_bytes(b[..8].try_into().unwrap());
+    if diff != 0 {
+        return diff.trailing_zeros() as usize / 8;
+    }
+    8 + vector::common_prefix_len(&a[8..], &b[8..])
+}
+
+/// The part of `s` from `pos` onwards, or nothing if `pos` is past the end
+///
+/// (For the remainder after a loop over whole chunks, without a bounds check that could panic.)
+#[cfg(feature = "compress")]
+#[inline(always)]
+fn rest(s: &[u8], pos: usize) -> &[u8] {
+    s.get(pos..).unwrap_or_default()
+}
+
+/// Fill `out` with `pattern` repeated, as for a backreference `pattern.len()` bytes back
+///
+/// `pattern` is the end of the output so far and `out` follows it, so if `out` is longer,
+/// the bytes being written are themselves repeated.
+///
+/// (Everything is sliced relative to the two arguments, so nothing can be out of bounds.)
+#[cfg(feature = "decompress")]
+#[inline]
+pub(crate) fn copy_backref(pattern: &[u8], out: &mut [u8]) {
+    copy_backref_with(pattern, out, |buf, dist| {
+        if dist >= VECTOR_SZ {
+            vector::repeat_prefix(buf, dist);
+        } else {
+            portable::repeat_prefix(buf, dist);
+        }
+    });
+}
+
+/// Same as [copy_backref], for possibly-uninitialized output
+///
+/// (The vectorized paths only handle initialized bytes, so this always uses the portable one.)
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+#[inline]
+pub(crate) fn copy_backref_uninit(pattern: &[MaybeUninit<u8>], out: &mut [MaybeUninit<u8>]) {
+    copy_backref_with(pattern, out, portable::repeat_prefix);
+}
+
+#[cfg(feature = "decompress")]
+#[inline(always)]
+fn copy_backref_with<T: Copy>(
+    pattern: &[T],
+    out: &mut [T],
+    repeat_prefix: impl FnOnce(&mut [T], usize),
+) {
+    let dist = pattern.len();
+    if let [c] = *pattern {
+        // a run of a single byte
+        out.fill(c);
+    } else if dist >= out.len() {
+        out.copy_from_slice(&pattern[..out.len()]);
+    } else if dist > 0 {
+        out[..dist].copy_from_slice(pattern);
+        repeat_prefix(out, dist);
+    }
+}
+
+mod portable {
+    /// Compares 8 bytes at a time, finding the first mismatch from the XOR of the two words
+    #[cfg(feature = "compress")]
+    pub(super) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+        let mut ret = 0;
+        for (a, b) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+            // little-endian so that the first byte is the least significant on any target
+            let diff = u64::from_le_bytes(a.try_into().unwrap())
+                ^ u64::from_le_bytes(b.try_into().unwrap());
+            if diff != 0 {
+                return ret + diff.trailing_zeros() as usize / 8;
+            }
+            ret += 8;
+        }
+        ret + super::rest(a, ret)
+            .iter()
+            .zip(super::rest(b, ret))
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// Copies a growing prefix of the repeating pattern at a time
+    ///
+    /// Requires the first `dist` (which must not be 0) bytes of `buf` to already hold the pattern,
+    /// and repeats it over the rest of `buf`. After `n` whole repetitions have been written,
+    /// the next `n` can be copied at once without overlapping what is being written.
+    ///
+    /// (With `unsafe-perf`, the copies are made without any further bounds checks.)
+    #[cfg(feature = "decompress")]
+    pub(super) fn repeat_prefix<T: Copy>(buf: &mut [T], dist: usize) {
+        let mut done = dist;
+        while done > 0 && done < buf.len() {
+            let n = usize::min(done, buf.len() - done);
+            #[cfg(not(feature = "unsafe-perf"))]
+            {
+                let (src, dst) = buf.split_at_mut(done);
+                dst[..n].copy_from_slice(&src[..n]);
+            }
+            #[cfg(feature = "unsafe-perf")]
+            {
+                let p = buf.as_mut_ptr();
+                // SAFETY: `done + n` is within the buffer, and `n <= done`, so the first `n`
+                // elements and the `n` starting at `done` don't overlap
+                unsafe { core::ptr::copy_nonoverlapping(p, p.add(done), n) };
+            }
+            done += n;
+        }
+    }
+}
+
+#[cfg(feature = "portable-simd")]
+mod simd {
+    use super::VECTOR_SZ;
+    use core::simd::prelude::*;
+
+    #[cfg(feature = "compress")]
+    pub(super) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+        let mut ret = 0;
+        for (a, b) in a.chunks_exact(VECTOR_SZ).zip(b.chunks_exact(VECTOR_SZ)) {
+            let ne = Simd::<u8, VECTOR_SZ>::from_slice(a).simd_ne(Simd::from_slice(b));
+            if ne.any() {
+                return ret + ne.to_bitmask().trailing_zeros() as usize;
+            }
+            ret += VECTOR_SZ;
+        }
+        ret + super::portable::common_prefix_len(super::rest(a, ret), super::rest(b, ret))
+    }
+
+    /// Requires `dist >= VECTOR_SZ`, so each chunk is read before it can be overwritten
+    #[cfg(feature = "decompress")]
+    pub(super) fn repeat_prefix(buf: &mut [u8], dist: usize) {
+        let mut i = dist;
+        while let Some(chunk) = buf.get(i - dist..i - dist + VECTOR_SZ) {
+            let v = Simd::<u8, VECTOR_SZ>::from_slice(chunk);
+            let Some(dst) = buf.get_mut(i..i + VECTOR_SZ) else {
+                break;
+            };
+            v.copy_to_slice(dst);
+            i += VECTOR_SZ;
+        }
+        if let Some(rest) = buf.get_mut(i - dist..) {
+            super::portable::repeat_prefix(rest, dist);
+        }
+    }
+}
+
+#[cfg(all(
+    feature = "unsafe-perf",
+    not(feature = "portable-simd"),
+    target_arch = "x86_64"
+))]
+mod x86 {
+    #[cfg(feature = "std")]
+    extern crate std;
+
+    #[cfg(feature = "decompress")]
+    use super::VECTOR_SZ;
+    use core::arch::x86_64::*;
+
+    #[cfg(feature = "compress")]
+    fn has_avx2() -> bool {
+        #[cfg(feature = "std")]
+        {
+            std::is_x86_feature_detected!("avx2")
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            cfg!(target_feature = "avx2")
+        }
+    }
+
+    #[cfg(feature = "compress")]
+    pub(super) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+        // (the vector code reads both at the same offsets)
+        let len = usize::min(a.len(), b.len());
+        let (a, b) = (&a[..len], &b[..len]);
+        if has_avx2() {
+            // SAFETY: AVX2 support was just checked, and the lengths are equal
+            unsafe { common_prefix_len_avx2(a, b) }
+        } else {
+            // SAFETY: SSE2 is part of the x86-64 baseline, and the lengths are equal
+            unsafe { common_prefix_len_sse2(a, b) }
+        }
+    }
+
+    /// Requires `a` and `b` to have the same length
+    #[cfg(feature = "compress")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn common_prefix_len_sse2(a: &[u8], b: &[u8]) -> usize {
+        let mut ret = 0;
+        while ret + 16 <= a.len() {
+            let va = _mm_loadu_si128(a.as_ptr().add(ret).cast());
+            let vb = _mm_loadu_si128(b.as_ptr().add(ret).cast());
+            let eq = _mm_movemask_epi8(_mm_cmpeq_epi8(va, vb)) as u32;
+            if eq != 0xffff {
+                return ret + (!eq).trailing_zeros() as usize;
+            }
+            ret += 16;
+        }
+        ret + super::portable::common_prefix_len(super::rest(a, ret), super::rest(b, ret))
+    }
+
+    /// Requires `a` and `b` to have the same length
+    #[cfg(feature = "compress")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn common_prefix_len_avx2(a: &[u8], b: &[u8]) -> usize {
+        let mut ret = 0;
+        while ret + 32 <= a.len() {
+            let va = _mm256_loadu_si256(a.as_ptr().add(ret).cast());
+            let vb = _mm256_loadu_si256(b.as_ptr().add(ret).cast());
+            let eq = _mm256_movemask_epi8(_mm256_cmpeq_epi8(va, vb)) as u32;
+            if eq != u32::MAX {
+                return ret + (!eq).trailing_zeros() as usize;
+            }
+            ret += 32;
+        }
+        common_prefix_len_sse2(super::rest(a, ret), super::rest(b, ret)) + ret
+    }
+
+    #[cfg(feature = "decompress")]
+    #[inline]
+    pub(super) fn repeat_prefix(buf: &mut [u8], dist: usize) {
+        if dist >= VECTOR_SZ && dist <= buf.len() {
+            // SAFETY: SSE2 is part of the x86-64 baseline, and the requirements were just checked
+            unsafe { repeat_prefix_sse2(buf, dist) }
+        }
+    }
+
+    /// Requires `dist >= 16`, so each chunk is read before it can be overwritten,
+    /// and `dist <= buf.len()`
+    #[cfg(feature = "decompress")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn repeat_prefix_sse2(buf: &mut [u8], dist: usize) {
+        let p = buf.as_mut_ptr();
+        let mut i = dist;
+        while i + 16 <= buf.len() {
+            let v = _mm_loadu_si128(p.add(i - dist).cast());
+            _mm_storeu_si128(p.add(i).cast(), v);
+            i += 16;
+        }
+        super::portable::repeat_prefix(buf.get_mut(i - dist..).unwrap_or_default(), dist);
+    }
+}
+
+#[cfg(all(
+    feature = "unsafe-perf",
+    not(feature = "portable-simd"),
+    target_arch = "aarch64",
+    target_feature = "neon",
+    target_endian = "little"
+))]
+mod aarch64 {
+    #[cfg(feature = "decompress")]
+    use super::VECTOR_SZ;
+    use core::arch::aarch64::*;
+
+    #[cfg(feature = "compress")]
+    pub(super) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+        // (the vector code reads both at the same offsets)
+        let len = usize::min(a.len(), b.len());
+        let (a, b) = (&a[..len], &b[..len]);
+        // SAFETY: NEON support is enabled at compile time, and the lengths are equal
+        unsafe { common_prefix_len_neon(a, b) }
+    }
+
+    /// Requires `a` and `b` to have the same length
+    #[cfg(feature = "compress")]
+    #[target_feature(enable = "neon")]
+    unsafe fn common_prefix_len_neon(a: &[u8], b: &[u8]) -> usize {
+        let mut ret = 0;
+        while ret + 16 <= a.len() {
+            let eq = vceqq_u8(vld1q_u8(a.as_ptr().add(ret)), vld1q_u8(b.as_ptr().add(ret)));
+            // there is no movemask, so narrow each byte of the comparison result to 4 bits
+            // (reading them back as a u64 is why this is only used on little-endian targets)
+            let eq = vshrn_n_u16::<4>(vreinterpretq_u16_u8(eq));
+            let eq = vget_lane_u64::<0>(vreinterpret_u64_u8(eq));
+            if eq != u64::MAX {
+                return ret + (!eq).trailing_zeros() as usize / 4;
+            }
+            ret += 16;
+        }
+        ret + super::portable::common_prefix_len(super::rest(a, ret), super::rest(b, ret))
+    }
+
+    #[cfg(feature = "decompress")]
+    #[inline]
+    pub(super) fn repeat_prefix(buf: &mut [u8], dist: usize) {
+        if dist >= VECTOR_SZ && dist <= buf.len() {
+            // SAFETY: NEON support is enabled at compile time, and the requirements were just checked
+            unsafe { repeat_prefix_neon(buf, dist) }
+        }
+    }
+
+    /// Requires `dist >= 16`, so each chunk is read before it can be overwritten,
+    /// and `dist <= buf.len()`
+    #[cfg(feature = "decompress")]
+    #[target_feature(enable = "neon")]
+    unsafe fn repeat_prefix_neon(buf: &mut [u8], dist: usize) {
+        let p = buf.as_mut_ptr();
+        let mut i = dist;
+        while i + 16 <= buf.len() {
+            vst1q_u8(p.add(i), vld1q_u8(p.add(i - dist)));
+            i += 16;
+        }
+        super::portable::repeat_prefix(buf.get_mut(i - dist..).unwrap_or_default(), dist);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn test_common_prefix_len() {
+        let a = [7u8; 100];
+        for len in [0, 1, 15, 16, 17, 31, 32, 33, 40, 100] {
+            for mismatch in 0..=len {
+                let mut b = a;
+                if mismatch < len {
+                    b[mismatch] = 8;
+                }
+                assert_eq!(common_prefix_len(&a[..len], &b[..len]), mismatch);
+            }
+        }
+        // only as long as the shorter one
+        assert_eq!(common_prefix_len(&a[..20], &a[..35]), 20);
+        assert_eq!(common_prefix_len(&a[..35], &a[..20]), 20);
+
+        for mismatch in 0..40 {
+            let mut b = a;
+            b[mismatch] = 8;
+            assert_eq!(portable::common_prefix_len(&a[..40], &b[..40]), mismatch);
+        }
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_copy_backref() {
+        let mut init = [0u8; 200];
+        for (i, x) in init.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        for dist in [1, 2, 3, 15, 16, 17, 40] {
+            for len in [0, 1, 15, 16, 17, 33, 100] {
+                let mut expected = init;
+                for i in 0..len {
+                    expected[50 + i] = expected[50 - dist + i];
+                }
+                let mut actual = init;
+                let (done, out) = actual.split_at_mut(50);
+                copy_backref(&done[50 - dist..], &mut out[..len]);
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! `fastlz-demo bench`, measuring how the codec performs on the user's own files
+
+use std::{
+    error::Error,
+    ffi::OsString,
+    hint::black_box,
+    mem,
+    time::{Duration, Instant},
+};
+
+use fastlz_rs::*;
+
+use crate::{file_error, usage_error};
+
+pub const USAGE: &str = "\
+Usage: fastlz-demo bench [--csv] <files...>
+
+Compresses and decompresses each file at each level, reporting the ratio, the throughput
+(in MB/s of uncompressed data), and the memory needed to compress it (the compressor's state
+plus an output buffer of the worst-case size; decompressing needs nothing besides the output).";
+
+/// Minimum time spent repeating each measurement, to smooth out noise for small files
+const MIN_TIME: Duration = Duration::from_millis(200);
+
+const LEVELS: &[(&str, CompressionLevel)] = &[
+    ("1", CompressionLevel::Level1),
+    #[cfg(feature = "level2")]
+    ("2", CompressionLevel::Level2),
+    ("auto", CompressionLevel::Default),
+];
+
+struct Row<'a> {
+    file: &'a str,
+    level: &'a str,
+    size: usize,
+    compressed: usize,
+    compress_mb_s: f64,
+    decompress_mb_s: f64,
+    memory: usize,
+}
+
+/// Run `f` repeatedly for at least [MIN_TIME], returning the throughput in MB/s for `len` bytes each time
+fn throughput(len: usize, mut f: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    let mut iters = 0u64;
+    while iters == 0 || start.elapsed() < MIN_TIME {
+        f();
+        iters += 1;
+    }
+    (len as f64 * iters as f64) / start.elapsed().as_secs_f64() / 1e6
+}
+
+pub fn run(args: impl IntoIterator<Item = OsString>) -> Result<(), Box<dyn Error>> {
+    let mut csv = false;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.to_str() {
+            Some("--csv") => csv = true,
+            Some("-h" | "--help") => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            Some(s) if s.starts_with('-') => {
+                return Err(usage_error(format!("unknown option {}", s)))
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        return Err(usage_error("no files to benchmark"));
+    }
+
+    if csv {
+        println!("file,level,size,compressed,ratio,compress_mb_s,decompress_mb_s,memory_bytes");
+    } else {
+        println!(
+            "{:<30} {:>5} {:>12} {:>12} {:>7} {:>10} {:>10} {:>9}",
+            "file", "level", "size", "compressed", "ratio", "comp MB/s", "dec MB/s", "mem KiB"
+        );
+    }
+
+    let mut state = CompressState::new_boxed();
+    for path in &files {
+        let inp = std::fs::read(path).map_err(|e| file_error(path, e))?;
+        let name = path.to_string_lossy();
+        let mut comp = vec![0; compress_bound(inp.len())];
+        let mut decomp = vec![0; inp.len()];
+
+        for &(level_name, level) in LEVELS {
+            let compressed = state.compress_to_buf(&inp, &mut comp, level)?;
+            let compress_mb_s = throughput(inp.len(), || {
+                black_box(state.compress_to_buf(black_box(&inp), &mut comp, level)).unwrap();
+            });
+            let decompress_mb_s = throughput(inp.len(), || {
+                black_box(decompress_to_buf(
+                    black_box(&comp[..compressed]),
+                    &mut decomp,
+                ))
+                .unwrap();
+            });
+            if decomp != inp {
+                return Err(format!("{} did not round-trip at level {}", name, level_name).into());
+            }
+
+            let row = Row {
+                file: &name,
+                level: level_name,
+                size: inp.len(),
+                compressed,
+                compress_mb_s,
+                decompress_mb_s,
+                memory: mem::size_of::<CompressState>() + comp.len(),
+            };
+            if csv {
+                print_csv(&row);
+            } else {
+                print_table(&row);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn ratio(row: &Row) -> f64 {
+    if row.size == 0 {
+        1.0
+    } else {
+        row.size as f64 / row.compressed as f64
+    }
+}
+
+fn print_table(row: &Row) {
+    println!(
+        "{:<30} {:>5} {:>12} {:>12} {:>7.3} {:>10.1} {:>10.1} {:>9}",
+        row.file,
+        row.level,
+        row.size,
+        row.compressed,
+        ratio(row),
+        row.compress_mb_s,
+        row.decompress_mb_s,
+        row.memory.div_ceil(1024)
+    );
+}
+
+fn print_csv(row: &Row) {
+    // (file names are quoted, doubling any quotes in them)
+    println!(
+        "\"{}\",{},{},{},{:.4},{:.2},{:.2},{}",
+        row.file.replace('"', "\"\""),
+        row.level,
+        row.size,
+        row.compressed,
+        ratio(row),
+        row.compress_mb_s,
+        row.decompress_mb_s,
+        row.memory
+    );
+}
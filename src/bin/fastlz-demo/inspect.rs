@@ -0,0 +1,113 @@
+//! `fastlz-demo inspect`, listing the instructions in a compressed stream
+
+use std::{
+    error::Error,
+    ffi::OsString,
+    io::{self, BufWriter, Write},
+};
+
+use fastlz_rs::*;
+
+use crate::{read_input, usage_error};
+
+pub const USAGE: &str = "\
+Usage: fastlz-demo inspect <file>
+
+Lists each instruction in a compressed stream: its offset in the input, the position in the
+output it decompresses to, and either the number of literals or the backreference's displacement
+(as encoded, i.e. 0 copies from the previous byte) and length. The file is read from stdin if it
+is `-`. Listing stops at the first invalid instruction, which is reported as an error.";
+
+#[derive(Default)]
+struct Totals {
+    lit_runs: usize,
+    lit_bytes: usize,
+    backrefs: usize,
+    backref_bytes: usize,
+}
+
+pub fn run(args: impl IntoIterator<Item = OsString>) -> Result<(), Box<dyn Error>> {
+    let mut input = None;
+    let mut flags_done = false;
+    for arg in args {
+        match arg.to_str() {
+            Some("-h" | "--help") if !flags_done => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            Some("--") if !flags_done => flags_done = true,
+            Some(s) if !flags_done && s.starts_with('-') && s != "-" => {
+                return Err(usage_error(format!("unknown option {}", s)))
+            }
+            _ => {
+                if input.replace(arg).is_some() {
+                    return Err(usage_error("more than one input given"));
+                }
+            }
+        }
+    }
+    let Some(input) = input else {
+        return Err(usage_error("no file to inspect"));
+    };
+    let inp = read_input(&input)?;
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    if !inp.is_empty() {
+        let level = match CompressionLevel::of_compressed(&inp)? {
+            CompressionLevel::Level1 => 1,
+            _ => 2,
+        };
+        writeln!(out, "level {}, {} bytes", level, inp.len())?;
+    }
+    writeln!(out, "{:>12} {:>12}  instruction", "offset", "out pos")?;
+
+    let mut totals = Totals::default();
+    let mut write_err = Ok(());
+    let res = decode_tokens(&inp, |token| {
+        if write_err.is_err() {
+            return;
+        }
+        write_err = match token {
+            Token::Literals {
+                input_offset,
+                output_pos,
+                len,
+            } => {
+                totals.lit_runs += 1;
+                totals.lit_bytes += len;
+                writeln!(
+                    out,
+                    "{:>12} {:>12}  literals len {}",
+                    input_offset, output_pos, len
+                )
+            }
+            Token::Backreference {
+                input_offset,
+                output_pos,
+                disp,
+                len,
+            } => {
+                totals.backrefs += 1;
+                totals.backref_bytes += len;
+                writeln!(
+                    out,
+                    "{:>12} {:>12}  backref  disp {} len {}",
+                    input_offset, output_pos, disp, len
+                )
+            }
+        };
+    });
+    write_err?;
+
+    writeln!(
+        out,
+        "{} literal runs ({} bytes), {} backreferences ({} bytes)",
+        totals.lit_runs, totals.lit_bytes, totals.backrefs, totals.backref_bytes
+    )?;
+    if let Ok(len) = res {
+        writeln!(out, "decompresses to {} bytes", len)?;
+    }
+    out.flush()?;
+    res?;
+    Ok(())
+}
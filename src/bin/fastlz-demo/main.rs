@@ -0,0 +1,443 @@
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    ops::{ControlFlow, Deref},
+    process::ExitCode,
+};
+
+use fastlz_rs::*;
+
+#[cfg(all(feature = "compress", feature = "decompress"))]
+mod bench;
+#[cfg(feature = "decompress")]
+mod inspect;
+
+const USAGE: &str = "\
+Usage: fastlz-demo [options] [input]
+       fastlz-demo bench [--csv] <files...>
+       fastlz-demo inspect <file>
+
+Options:
+  -c, --compress          compress the input (the default)
+  -d, --decompress        decompress the input
+  -l, --level <level>     compression level: 1, 2, auto (the default), or best
+  -o, --output <file>     write the output to <file> rather than stdout
+      --verify            after compressing, decompress the output and check it matches the input
+      --progress          show the percentage of the input processed so far on stderr
+      --max-size <bytes>  when decompressing, fail rather than produce more output than this
+      --size-hint <bytes> when decompressing, the expected output size, to allocate it up front
+      --framed            when decompressing, the input starts with a 4-byte size header
+                          (as written by compress_framed_*), which is checked and used as the hint
+  -h, --help              show this message
+
+The input is read from stdin if it is `-` or not given, and `-` as the output means stdout.
+
+Exits with 0 on success, 2 if the command line is invalid, 3 if decompressing would exceed
+--max-size, and 1 on any other error.";
+
+/// Exit code when decompressing would have exceeded `--max-size`
+const EXIT_MAX_SIZE: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Compress,
+    Decompress,
+}
+
+struct Args {
+    mode: Mode,
+    #[cfg_attr(not(feature = "compress"), allow(dead_code))]
+    level: CompressionLevel,
+    verify: bool,
+    progress: bool,
+    #[cfg_attr(not(feature = "decompress"), allow(dead_code))]
+    max_size: Option<usize>,
+    #[cfg_attr(not(feature = "decompress"), allow(dead_code))]
+    size_hint: Option<usize>,
+    #[cfg_attr(not(feature = "decompress"), allow(dead_code))]
+    framed: bool,
+    input: OsString,
+    output: OsString,
+}
+
+/// Error in the command line, which is reported along with the usage message
+#[derive(Debug)]
+struct UsageError(String);
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl Error for UsageError {}
+
+fn usage_error(msg: impl Into<String>) -> Box<dyn Error> {
+    Box::new(UsageError(msg.into()))
+}
+
+/// Describe an error opening or reading a file, along with its name
+fn file_error(path: &OsString, e: io::Error) -> Box<dyn Error> {
+    format!("{}: {}", path.to_string_lossy(), e).into()
+}
+
+/// The contents of an input file
+enum Input {
+    Read(Vec<u8>),
+    /// (with the `mmap` feature, so that large files don't need to be read into memory first)
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+impl Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Input::Read(v) => v,
+            #[cfg(feature = "mmap")]
+            Input::Mapped(m) => m,
+        }
+    }
+}
+
+/// Read the whole of a file, or stdin if the path is `-`
+fn read_input(path: &OsString) -> Result<Input, Box<dyn Error>> {
+    if path == "-" {
+        let mut inp = Vec::new();
+        io::stdin().lock().read_to_end(&mut inp)?;
+        return Ok(Input::Read(inp));
+    }
+
+    #[cfg(feature = "mmap")]
+    {
+        let mut f = File::open(path).map_err(|e| file_error(path, e))?;
+        let meta = f.metadata().map_err(|e| file_error(path, e))?;
+        // (pipes and devices can't be mapped, and files in e.g. /proc report a size of 0)
+        if !meta.is_file() || meta.len() == 0 {
+            let mut inp = Vec::new();
+            f.read_to_end(&mut inp).map_err(|e| file_error(path, e))?;
+            return Ok(Input::Read(inp));
+        }
+        // SAFETY: the file must not be modified while it is mapped, which is left up to the user
+        // (as with any other tool which maps its input)
+        let map = unsafe { memmap2::Mmap::map(&f) }.map_err(|e| file_error(path, e))?;
+        Ok(Input::Mapped(map))
+    }
+    #[cfg(not(feature = "mmap"))]
+    std::fs::read(path)
+        .map(Input::Read)
+        .map_err(|e| file_error(path, e))
+}
+
+fn parse_level(s: &OsString) -> Result<CompressionLevel, Box<dyn Error>> {
+    match s.to_str() {
+        Some("1") => Ok(CompressionLevel::Level1),
+        #[cfg(feature = "level2")]
+        Some("2") => Ok(CompressionLevel::Level2),
+        Some("auto") => Ok(CompressionLevel::Default),
+        Some("best") => Ok(CompressionLevel::Best),
+        _ => Err(usage_error(format!(
+            "invalid level {}",
+            s.to_string_lossy()
+        ))),
+    }
+}
+
+fn parse_size(flag: &str, s: &OsString) -> Result<usize, Box<dyn Error>> {
+    s.to_str()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| usage_error(format!("invalid size {} for {}", s.to_string_lossy(), flag)))
+}
+
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Option<Args>, Box<dyn Error>> {
+    let mut args = args.into_iter();
+    let mut mode = Mode::Compress;
+    let mut level = CompressionLevel::Default;
+    let mut input = None;
+    let mut output = None;
+    let mut verify = false;
+    let mut progress = false;
+    let mut max_size = None;
+    let mut size_hint = None;
+    let mut framed = false;
+    let mut flags_done = false;
+
+    while let Some(arg) = args.next() {
+        let s = arg.to_string_lossy();
+        if flags_done || !s.starts_with('-') || s == "-" {
+            if input.replace(arg).is_some() {
+                return Err(usage_error("more than one input given"));
+            }
+            continue;
+        }
+
+        // (`--flag=value` is the same as `--flag value`)
+        let (flag, mut value) = match s.split_once('=') {
+            Some((flag, value)) if s.starts_with("--") => (flag, Some(OsString::from(value))),
+            _ => (&*s, None),
+        };
+        let mut value = |name: &str| {
+            value
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| usage_error(format!("{} needs a value", name)))
+        };
+        match flag {
+            "-c" | "--compress" => mode = Mode::Compress,
+            "-d" | "--decompress" => mode = Mode::Decompress,
+            "-l" | "--level" => level = parse_level(&value(flag)?)?,
+            "-o" | "--output" => output = Some(value(flag)?),
+            "--verify" => verify = true,
+            "--progress" => progress = true,
+            "--max-size" => max_size = Some(parse_size(flag, &value(flag)?)?),
+            "--size-hint" => size_hint = Some(parse_size(flag, &value(flag)?)?),
+            "--framed" => framed = true,
+            "-h" | "--help" => return Ok(None),
+            "--" => flags_done = true,
+            _ => return Err(usage_error(format!("unknown option {}", flag))),
+        }
+    }
+
+    if verify && mode != Mode::Compress {
+        return Err(usage_error("--verify only applies when compressing"));
+    }
+    for (given, flag) in [
+        (max_size.is_some(), "--max-size"),
+        (size_hint.is_some(), "--size-hint"),
+        (framed, "--framed"),
+    ] {
+        if given && mode != Mode::Decompress {
+            return Err(usage_error(format!(
+                "{} only applies when decompressing",
+                flag
+            )));
+        }
+    }
+
+    Ok(Some(Args {
+        mode,
+        level,
+        verify,
+        progress,
+        max_size,
+        size_hint,
+        framed,
+        input: input.unwrap_or_else(|| "-".into()),
+        output: output.unwrap_or_else(|| "-".into()),
+    }))
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args_os().skip(1).peekable();
+    if args.next_if(|arg| arg == "bench").is_some() {
+        #[cfg(all(feature = "compress", feature = "decompress"))]
+        return bench::run(args);
+        #[cfg(not(all(feature = "compress", feature = "decompress")))]
+        return Err("bench is not supported in this build".into());
+    }
+    if args.next_if(|arg| arg == "inspect").is_some() {
+        #[cfg(feature = "decompress")]
+        return inspect::run(args);
+        #[cfg(not(feature = "decompress"))]
+        return Err("inspect is not supported in this build".into());
+    }
+
+    let Some(args) = parse_args(args)? else {
+        println!("{}", USAGE);
+        return Ok(());
+    };
+
+    let inp = read_input(&args.input)?;
+
+    let mut progress = ProgressLine {
+        enabled: args.progress,
+        label: match args.mode {
+            Mode::Compress => "compressing",
+            Mode::Decompress => "decompressing",
+        },
+        shown: None,
+    };
+    // (unused when built without either codec)
+    #[cfg_attr(
+        not(any(feature = "compress", feature = "decompress")),
+        allow(unused_variables)
+    )]
+    let update = |p| progress.update(p);
+    let res: Result<Vec<u8>, Box<dyn Error>> = match args.mode {
+        #[cfg(feature = "compress")]
+        Mode::Compress => CompressState::new_boxed()
+            .compress_to_vec_with_progress(&inp, args.level, &CompressOptions::default(), update)
+            .map_err(Into::into),
+        #[cfg(feature = "decompress")]
+        Mode::Decompress => decompress(&inp, &args, update),
+        #[allow(unreachable_patterns)]
+        mode => Err(format!("{:?} is not supported in this build", mode).into()),
+    };
+    progress.finish(res.is_ok());
+    let outp = res?;
+
+    if args.output == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&outp)?;
+        stdout.flush()?;
+    } else {
+        let outp_f = File::create(&args.output).map_err(|e| file_error(&args.output, e))?;
+        let mut outp_f = BufWriter::new(outp_f);
+        outp_f.write_all(&outp)?;
+        outp_f.flush()?;
+    }
+
+    if args.verify {
+        verify(&inp, &outp)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+fn decompress(
+    inp: &[u8],
+    args: &Args,
+    progress: impl FnMut(Progress) -> ControlFlow<()>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (payload, frame_len) = if args.framed {
+        let (len, payload) = frame_decompressed_len(inp)?;
+        (payload, Some(len))
+    } else {
+        (inp, None)
+    };
+
+    let mut opts = DecompressOptions::default();
+    opts.max_output_len = args.max_size;
+    // (a frame header is untrusted, so it can only ask for as much as the payload could expand to,
+    // i.e. at most 256 bytes per input byte, as for `decompress_framed_to_vec`)
+    let frame_hint = frame_len.map(|len| len.min(payload.len().saturating_mul(256)));
+    let hint = args
+        .size_hint
+        .or(frame_hint)
+        .map(|hint| hint.min(args.max_size.unwrap_or(usize::MAX)));
+    let outp = decompress_to_vec_with_progress(payload, hint, &opts, progress)?;
+
+    if frame_len.is_some_and(|len| len != outp.len()) {
+        return Err(DecompressError::FrameSizeMismatch.into());
+    }
+    Ok(outp)
+}
+
+/// Percentage of the input processed, shown on stderr with `--progress` and updated in place
+struct ProgressLine {
+    enabled: bool,
+    label: &'static str,
+    shown: Option<u64>,
+}
+impl ProgressLine {
+    fn update(&mut self, p: Progress) -> ControlFlow<()> {
+        let percent = p.input_processed as u64 * 100 / p.input_len.max(1) as u64;
+        if self.enabled && self.shown != Some(percent) {
+            eprint!("\r{} {:>3}%", self.label, percent);
+            self.shown = Some(percent);
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Complete the line, so that anything printed afterwards starts on a new one
+    fn finish(&mut self, ok: bool) {
+        if self.enabled {
+            let status = if ok { "100%" } else { "failed" };
+            eprintln!("\r{} {}", self.label, status);
+        }
+    }
+}
+
+/// Decompress the compressed output and check it against the input, reporting on stderr
+#[cfg_attr(not(feature = "decompress"), allow(unused_variables))]
+fn verify(inp: &[u8], comp: &[u8]) -> Result<(), Box<dyn Error>> {
+    #[cfg(not(feature = "decompress"))]
+    return Err("--verify is not supported in this build".into());
+
+    #[cfg(feature = "decompress")]
+    {
+        let decomp = decompress_to_vec(comp, Some(inp.len()))
+            .map_err(|e| format!("verification failed: {}", e))?;
+        if let Some(offset) = inp.iter().zip(&decomp).position(|(a, b)| a != b) {
+            return Err(format!(
+                "verification failed: output differs from the input at {}",
+                offset
+            )
+            .into());
+        }
+        if decomp.len() != inp.len() {
+            return Err(format!(
+                "verification failed: output decompresses to {} bytes rather than {}",
+                decomp.len(),
+                inp.len()
+            )
+            .into());
+        }
+
+        let ratio = if comp.is_empty() {
+            1.0
+        } else {
+            inp.len() as f64 / comp.len() as f64
+        };
+        eprintln!(
+            "verified: {} -> {} bytes (ratio {:.3}), crc32 {:08x}",
+            inp.len(),
+            comp.len(),
+            ratio,
+            crc32(&decomp)
+        );
+        Ok(())
+    }
+}
+
+/// CRC-32 (as used by gzip and zip), so the data can be compared against other tools' checksums
+#[cfg(feature = "decompress")]
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xedb88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0, |c, &b| {
+        TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<UsageError>() => {
+            eprintln!("fastlz-demo: {}\n\n{}", e, USAGE);
+            ExitCode::from(2)
+        }
+        Err(e)
+            if matches!(
+                e.downcast_ref(),
+                Some(DecompressError::OutputLimitExceeded { .. })
+            ) =>
+        {
+            eprintln!("fastlz-demo: {}", e);
+            ExitCode::from(EXIT_MAX_SIZE)
+        }
+        Err(e) => {
+            eprintln!("fastlz-demo: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
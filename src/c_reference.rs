@@ -0,0 +1,89 @@
+//! The C reference code, built natively by `build.rs` (development only)
+//!
+//! This is much faster than running the wasm build under wasmi, but needs a C compiler
+//! and a checkout of the C code. It is not part of the supported API.
+
+#[cfg(all(test, feature = "std"))]
+extern crate std;
+
+use core::ffi::{c_int, c_void};
+
+extern "C" {
+    fn fastlz_compress_level(
+        level: c_int,
+        input: *const c_void,
+        length: c_int,
+        output: *mut c_void,
+    ) -> c_int;
+    fn fastlz_decompress(
+        input: *const c_void,
+        length: c_int,
+        output: *mut c_void,
+        maxout: c_int,
+    ) -> c_int;
+}
+
+/// Compress with the C code at the given level (1 or 2)
+///
+/// Returns the compressed size. Panics if `outp` is smaller than the C code requires
+/// (at least 66 bytes and 5% larger than the input).
+pub fn compress_level(level: u32, inp: &[u8], outp: &mut [u8]) -> usize {
+    assert!(level == 1 || level == 2);
+    assert!(outp.len() >= usize::max(66, inp.len() + inp.len().div_ceil(20)));
+    let len = c_int::try_from(inp.len()).unwrap();
+    // SAFETY: the C code reads exactly `len` bytes of input,
+    // and writes no more than the bound which was just checked
+    let ret = unsafe {
+        fastlz_compress_level(
+            level as c_int,
+            inp.as_ptr().cast(),
+            len,
+            outp.as_mut_ptr().cast(),
+        )
+    };
+    ret as usize
+}
+
+/// Decompress with the C code
+///
+/// Returns the decompressed size, or `None` if the C code rejected the input
+pub fn decompress(inp: &[u8], outp: &mut [u8]) -> Option<usize> {
+    let len = c_int::try_from(inp.len()).unwrap();
+    let maxout = c_int::try_from(outp.len()).unwrap_or(c_int::MAX);
+    // SAFETY: the C code reads exactly `len` bytes of input, and writes at most `maxout` bytes
+    let ret =
+        unsafe { fastlz_decompress(inp.as_ptr().cast(), len, outp.as_mut_ptr().cast(), maxout) };
+    if ret > 0 || inp.is_empty() {
+        Some(ret as usize)
+    } else {
+        None
+    }
+}
+
+/// Same interface as the wasm reference, for use by the tests
+#[cfg(all(test, feature = "std"))]
+pub(crate) struct FastLZNative {
+    buf: std::vec::Vec<u8>,
+}
+#[cfg(all(test, feature = "std"))]
+impl FastLZNative {
+    pub fn new() -> Self {
+        Self {
+            buf: std::vec::Vec::new(),
+        }
+    }
+
+    pub fn fastlz_compress_level<'s>(&'s mut self, level: u32, input_data: &[u8]) -> &'s [u8] {
+        self.buf.resize(66 + input_data.len() * 2, 0);
+        let len = compress_level(level, input_data, &mut self.buf);
+        &self.buf[..len]
+    }
+
+    #[cfg(feature = "compress")]
+    pub fn fastlz_decompress<'s>(&'s mut self, input_data: &[u8]) -> &'s [u8] {
+        // same assumed maximum expansion as the wasm reference
+        self.buf.resize(input_data.len() * 1024, 0);
+        let len = decompress(input_data, &mut self.buf).unwrap();
+        &self.buf[..len]
+    }
+}
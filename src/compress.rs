@@ -1,6 +1,15 @@
 use core::fmt;
 use core::mem;
-
+#[cfg(feature = "unsafe-perf")]
+use core::mem::MaybeUninit;
+use core::ops::ControlFlow;
+
+use crate::decompress::{decompress_impl, DecompressOptions};
+use crate::input::*;
+use crate::level::CompressionLevel;
+#[cfg(feature = "paranoid")]
+use crate::paranoid::*;
+use crate::progress::*;
 use crate::util::*;
 
 #[cfg(feature = "alloc")]
@@ -9,97 +18,278 @@ extern crate alloc;
 #[cfg(feature = "std")]
 extern crate std;
 
-const HTAB_LOG2: usize = 13;
-const HTAB_SZ: usize = 1 << HTAB_LOG2;
+/// Number of hash table entries used by [CompressState::new], matching the reference C code
+///
+/// (Except on 16-bit targets, where a table that size wouldn't fit in the address space,
+/// so it is 1024 entries instead.)
+pub const DEFAULT_HTAB_SZ: usize = if usize::BITS < 32 { 1 << 10 } else { 1 << 13 };
+
+/// Size of each window checked for far-away matches by [CompressOptions::sample_default_level]
+#[cfg(feature = "level2")]
+const SAMPLE_WINDOW: usize = 4096;
+/// Maximum number of windows checked by [CompressOptions::sample_default_level]
+#[cfg(feature = "level2")]
+const SAMPLE_WINDOWS: usize = 4;
+
+/// Number of positions parsed at once by [CompressOptions::optimal_parse]
+#[cfg(feature = "alloc")]
+const OPTIMAL_BLOCK: usize = 1 << 14;
+/// Number of earlier positions checked by [CompressOptions::optimal_parse]
+/// (unless [CompressOptions::max_chain] is higher)
+#[cfg(feature = "alloc")]
+const OPTIMAL_CHAIN: usize = 16;
+/// Length of a match which [CompressOptions::optimal_parse] takes without considering alternatives
+///
+/// Otherwise, long runs would take time quadratic in their length.
+#[cfg(feature = "alloc")]
+const OPTIMAL_NICE_LEN: usize = 256;
 
 /// Compression errors
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
 pub enum CompressError {
     /// The output buffer was too small to hold all the output.
     ///
     /// The output that has been written *is* valid, but has been truncated.
-    OutputTooSmall,
+    ///
+    /// When compressing into a buffer, how much of the input was covered and an upper bound
+    /// on the additional space needed are also reported, so that the operation can be retried
+    /// once with a buffer which is large enough. Working this out only needs a pass over the
+    /// truncated output, not another one over the input.
+    ///
+    /// Other sinks (including `heapless::Vec` and `ArrayVec`) don't work these out,
+    /// and report both `input_processed` and `remaining` as 0.
+    OutputTooSmall {
+        /// Number of bytes which were written before running out of space
+        written: usize,
+        /// Number of input bytes fully represented by the output which was written
+        input_processed: usize,
+        /// Number of additional bytes which is enough to hold the entire output
+        remaining: usize,
+    },
+    /// The input is too large to be represented
+    InputTooLarge,
+    /// The progress callback requested that compression stop
+    Cancelled,
+    /// The output grew beyond [CompressOptions::incompressible_fraction] of the input
+    Incompressible,
 }
 impl fmt::Display for CompressError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CompressError::OutputTooSmall => write!(f, "output buffer was insufficient"),
+            CompressError::OutputTooSmall {
+                written, remaining, ..
+            } => write!(
+                f,
+                "output buffer was insufficient ({} bytes written, up to {} more needed)",
+                written, remaining
+            ),
+            CompressError::InputTooLarge => write!(f, "input was too large"),
+            CompressError::Cancelled => write!(f, "compression was cancelled"),
+            CompressError::Incompressible => write!(f, "input was not compressible enough"),
         }
     }
 }
-#[cfg(feature = "std")]
-impl std::error::Error for CompressError {}
+impl core::error::Error for CompressError {}
+
+/// Destination for compressed bytes
+///
+/// Implement this to stream compressed output directly to a custom target
+/// (e.g. a peripheral or a socket) without needing an intermediate buffer.
+/// Bytes are always written strictly sequentially and never revisited.
+///
+/// Sinks can use their own error type, which is passed through unchanged.
+/// Errors raised by the compressor itself are converted using [From].
+/// If the output has run out of space, the conventional error is [CompressError::OutputTooSmall].
+pub trait ByteSink {
+    /// Error type returned by the sink
+    type Error: From<CompressError>;
+
+    /// Add a single byte to the output
+    fn putc(&mut self, c: u8) -> Result<(), Self::Error> {
+        self.put_buf(&[c])
+    }
+    /// Add the given bytes to the output
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+    /// Called once all of the output has been successfully produced
+    ///
+    /// Sinks which buffer output should write out whatever remains here.
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+impl<T: ByteSink + ?Sized> ByteSink for &mut T {
+    type Error = T::Error;
 
-/// This is the actual abstraction over outputting to a slice vs a Vec
-trait OutputHelper {
-    fn putc(&mut self, c: u8) -> Result<(), CompressError>;
-    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError>;
-    fn poke_l2(&mut self);
+    fn putc(&mut self, c: u8) -> Result<(), T::Error> {
+        (**self).putc(c)
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), T::Error> {
+        (**self).put_buf(buf)
+    }
+    fn flush(&mut self) -> Result<(), T::Error> {
+        (**self).flush()
+    }
 }
-impl<'a> OutputHelper for BufOutput<'a> {
+
+impl<'a> ByteSink for BufOutput<'a> {
+    type Error = CompressError;
+
     fn putc(&mut self, c: u8) -> Result<(), CompressError> {
-        if self.pos + 1 <= self.buf.len() {
-            self.buf[self.pos] = c;
+        if let Some(dst) = self.buf.get_mut(self.pos) {
+            *dst = c;
             self.pos += 1;
             Ok(())
         } else {
-            Err(CompressError::OutputTooSmall)
+            Err(CompressError::OutputTooSmall {
+                written: self.pos,
+                input_processed: 0,
+                remaining: 0,
+            })
         }
     }
     fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
-        let mut len = buf.len();
-        let mut did_overflow = false;
-        if self.pos + len > self.buf.len() {
-            did_overflow = true;
-            len = self.buf.len() - self.pos;
-        }
-
-        self.buf[self.pos..self.pos + len].copy_from_slice(&buf[..len]);
+        let (_, spare) = self.split();
+        let len = usize::min(buf.len(), spare.len());
+        spare[..len].copy_from_slice(&buf[..len]);
         self.pos += len;
 
-        if did_overflow {
-            Err(CompressError::OutputTooSmall)
+        if len < buf.len() {
+            Err(CompressError::OutputTooSmall {
+                written: self.pos,
+                input_processed: 0,
+                remaining: 0,
+            })
         } else {
             Ok(())
         }
     }
+}
+
+#[cfg(feature = "unsafe-perf")]
+impl<'a> ByteSink for UninitBufOutput<'a> {
+    type Error = CompressError;
+
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+        let (_, spare) = self.split();
+        let len = usize::min(buf.len(), spare.len());
+        for (dst, &src) in spare[..len].iter_mut().zip(buf) {
+            dst.write(src);
+        }
+        self.pos += len;
 
-    fn poke_l2(&mut self) {
-        self.buf[0] |= 0b001_00000;
+        if len < buf.len() {
+            Err(CompressError::OutputTooSmall {
+                written: self.pos,
+                input_processed: 0,
+                remaining: 0,
+            })
+        } else {
+            Ok(())
+        }
     }
 }
 
-#[cfg(feature = "alloc")]
-impl OutputHelper for VecOutput {
-    fn putc(&mut self, c: u8) -> Result<(), CompressError> {
-        self.vec.push(c);
+impl ByteSink for CountOutput {
+    type Error = CompressError;
+
+    fn putc(&mut self, _c: u8) -> Result<(), CompressError> {
+        self.0 += 1;
         Ok(())
     }
     fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
-        self.vec.extend_from_slice(buf);
+        self.0 += buf.len();
         Ok(())
     }
+}
+
+/// Sets the level 2 indicator bits in the first byte which passes through
+#[cfg(feature = "level2")]
+struct MarkL2<O> {
+    inner: O,
+    marked: bool,
+}
+#[cfg(feature = "level2")]
+impl<O> MarkL2<O> {
+    fn new(inner: O) -> Self {
+        Self {
+            inner,
+            marked: false,
+        }
+    }
+}
+#[cfg(feature = "level2")]
+impl<O: ByteSink> ByteSink for MarkL2<O> {
+    type Error = O::Error;
 
-    fn poke_l2(&mut self) {
-        self.vec[0] |= 0b001_00000;
+    fn putc(&mut self, c: u8) -> Result<(), O::Error> {
+        if self.marked {
+            self.inner.putc(c)
+        } else {
+            self.marked = true;
+            self.inner.putc(c | 0b001_00000)
+        }
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), O::Error> {
+        match buf.split_first() {
+            Some((&c, rest)) if !self.marked => {
+                self.putc(c)?;
+                self.inner.put_buf(rest)
+            }
+            _ => self.inner.put_buf(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<(), O::Error> {
+        self.inner.flush()
     }
 }
 
 /// Level 1 output sink, to force code monomorphization
 struct L1Output<O>(O);
 /// Level 2 output sink, to force code monomorphization
+#[cfg(feature = "level2")]
 struct L2Output<O>(O);
 
-impl<O: OutputHelper> OutputSink<CompressError> for L1Output<O> {
-    fn put_lits(&mut self, mut lits: &[u8]) -> Result<(), CompressError> {
+/// With the `opt-size` feature, hide the type of a byte sink behind a trait object
+///
+/// The compressor is then only instantiated once per level and error type,
+/// rather than once for every kind of sink.
+#[cfg(feature = "opt-size")]
+#[inline(always)]
+fn erase_sink<O: ByteSink>(outp: &mut O) -> &mut dyn ByteSink<Error = O::Error> {
+    outp
+}
+#[cfg(not(feature = "opt-size"))]
+#[inline(always)]
+fn erase_sink<O: ByteSink>(outp: &mut O) -> &mut O {
+    outp
+}
+
+/// With the `opt-size` feature, hide the type of a progress callback behind a trait object
+#[cfg(feature = "opt-size")]
+#[inline(always)]
+fn erase_progress<F: FnMut(Progress) -> ControlFlow<()>>(
+    progress: &mut F,
+) -> &mut dyn FnMut(Progress) -> ControlFlow<()> {
+    progress
+}
+#[cfg(not(feature = "opt-size"))]
+#[inline(always)]
+fn erase_progress<F: FnMut(Progress) -> ControlFlow<()>>(progress: &mut F) -> &mut F {
+    progress
+}
+
+impl<O: ByteSink> OutputSink<O::Error> for L1Output<O> {
+    fn put_lits(&mut self, mut lits: &[u8]) -> Result<(), O::Error> {
         while lits.len() > 32 {
             self.0.putc(31)?;
             self.0.put_buf(&lits[..32])?;
             lits = &lits[32..];
         }
 
-        debug_assert!(lits.len() >= 1);
+        debug_assert!(!lits.is_empty());
         debug_assert!(lits.len() <= 32);
 
         // 1 byte opcode, len bytes literals
@@ -109,7 +299,7 @@ impl<O: OutputHelper> OutputSink<CompressError> for L1Output<O> {
         Ok(())
     }
 
-    fn put_backref(&mut self, disp: usize, mut len: usize) -> Result<(), CompressError> {
+    fn put_backref(&mut self, disp: usize, mut len: usize) -> Result<(), O::Error> {
         debug_assert!(disp <= 8191);
         debug_assert!(len >= 3);
 
@@ -148,15 +338,16 @@ impl<O: OutputHelper> OutputSink<CompressError> for L1Output<O> {
     }
 }
 
-impl<O: OutputHelper> OutputSink<CompressError> for L2Output<O> {
-    fn put_lits(&mut self, mut lits: &[u8]) -> Result<(), CompressError> {
+#[cfg(feature = "level2")]
+impl<O: ByteSink> OutputSink<O::Error> for L2Output<O> {
+    fn put_lits(&mut self, mut lits: &[u8]) -> Result<(), O::Error> {
         while lits.len() > 32 {
             self.0.putc(31)?;
             self.0.put_buf(&lits[..32])?;
             lits = &lits[32..];
         }
 
-        debug_assert!(lits.len() >= 1);
+        debug_assert!(!lits.is_empty());
         debug_assert!(lits.len() <= 32);
 
         // 1 byte opcode, len bytes literals
@@ -166,8 +357,8 @@ impl<O: OutputHelper> OutputSink<CompressError> for L2Output<O> {
         Ok(())
     }
 
-    fn put_backref(&mut self, disp: usize, mut len: usize) -> Result<(), CompressError> {
-        debug_assert!(disp <= 8191 + 65535);
+    fn put_backref(&mut self, disp: usize, mut len: usize) -> Result<(), O::Error> {
+        debug_assert!(disp <= <Self as CompressSink>::MAX_DISP);
         debug_assert!(len >= 3);
 
         let earlydisp = usize::min(disp, 8191);
@@ -202,534 +393,3552 @@ impl<O: OutputHelper> OutputSink<CompressError> for L2Output<O> {
 
 /// Additional parameters that need to be monomorphized into level 1 vs level 2 output
 trait CompressSink {
+    type Error: From<CompressError>;
     const MAX_DISP: usize;
     const IS_LEVEL2: bool;
-    fn poke_l2(&mut self);
+    /// Whether progress is reported as output is produced,
+    /// in which case long literal runs need to be output before they end
+    const REPORTS_PROGRESS: bool = false;
 }
-impl<O: OutputHelper> CompressSink for L1Output<O> {
+impl<O: ByteSink> CompressSink for L1Output<O> {
+    type Error = O::Error;
     const MAX_DISP: usize = 8191;
     const IS_LEVEL2: bool = false;
-    fn poke_l2(&mut self) {}
 }
-impl<O: OutputHelper> CompressSink for L2Output<O> {
-    const MAX_DISP: usize = 8191 + 65535;
+#[cfg(feature = "level2")]
+impl<O: ByteSink> CompressSink for L2Output<O> {
+    type Error = O::Error;
+    const MAX_DISP: usize = saturating_usize(8191 + 65535);
     const IS_LEVEL2: bool = true;
-    fn poke_l2(&mut self) {
-        self.0.poke_l2();
-    }
 }
 
-/// Compression level
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CompressionLevel {
-    /// Default compression (i.e. level 1 if the input is less than 64 KiB, level 2 otherwise)
-    Default,
-    /// Level 1
-    Level1,
-    /// Level 2
-    Level2,
+/// Additional options controlling compression
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CompressOptions {
+    /// Allow level 2 output to end on a backreference requiring extended displacement bytes
+    ///
+    /// The format does not permit this, so by default the final match is shortened by one byte.
+    /// Only enable this if the decoder is known to accept such streams.
+    pub allow_far_match_at_end: bool,
+    /// Make exactly the same decisions as the reference C encoder
+    ///
+    /// This produces output which is byte-for-byte identical to the C code,
+    /// at the cost of slightly worse compression. All other options affecting the output are ignored.
+    /// (With a non-default hash table size, the output matches the C code built with the same size.)
+    pub c_compatible: bool,
+    /// Collect [CompressStats] describing the output
+    ///
+    /// These are returned in [CompressOutcome::stats]. Collecting them requires a second pass
+    /// over the input, so this should only be enabled when tuning rather than in production.
+    pub collect_stats: bool,
+    /// Skip through incompressible data faster, at the cost of missing some matches
+    ///
+    /// After every 64 consecutive positions where no match is found,
+    /// the distance advanced before trying again increases by this amount,
+    /// going back to one byte at a time once a match is found.
+    /// With the default of 1, already-compressed or encrypted data passes through
+    /// with very few lookups. 0 always advances one byte at a time.
+    pub acceleration: usize,
+    /// Chooses the level used for [CompressionLevel::Default], given the length of the input
+    ///
+    /// This replaces the built-in rule (level 2 only for inputs of at least 64 KiB).
+    /// The policy may itself return [CompressionLevel::Default] or [CompressionLevel::Best],
+    /// e.g. to only defer to the built-in rule for some inputs.
+    pub default_level_policy: Option<fn(usize) -> CompressionLevel>,
+    /// Choose the level for [CompressionLevel::Default] by sampling the input
+    ///
+    /// Rather than going by length alone, a few windows of the input are checked for matches
+    /// which are too far away for level 1 to use. Level 2 is only chosen if these make up
+    /// a significant part of the samples, since level 1 is faster and compresses data
+    /// that only repeats locally nearly as well.
+    pub sample_default_level: bool,
+    /// Store two candidate positions in each hash table bucket, and use whichever gives the longer match
+    ///
+    /// The table then has half as many buckets, but far fewer matches are lost to collisions.
+    /// This generally improves compression at a modest cost in speed.
+    pub two_entry_buckets: bool,
+    /// Before using a match, check whether the next byte starts a much longer one
+    ///
+    /// If it does, the current byte is emitted as a literal instead.
+    /// This typically improves compression by a percent or two, at a moderate cost in speed.
+    pub lazy_matching: bool,
+    /// Check up to this many earlier occurrences of the next three bytes, and use the longest match
+    ///
+    /// Earlier occurrences are linked together into hash chains, which takes an extra allocation
+    /// of 4 bytes per position within reach (about 32 KiB for level 1, or 288 KiB for level 2).
+    /// This is much slower, and intended for data which is compressed once and decompressed many times.
+    /// The output is still in the normal level 1 or level 2 format.
+    ///
+    /// The default of 0 only checks the most recent occurrence, as does 1.
+    /// Anything more takes priority over [CompressOptions::two_entry_buckets].
+    #[cfg(feature = "alloc")]
+    pub max_chain: usize,
+    /// Choose between literals and matches so as to minimize the size of the output
+    ///
+    /// Rather than taking each match as it is found, the matches (from hash chains, see
+    /// [CompressOptions::max_chain]) for a whole block of the input are collected first,
+    /// and then the cheapest way of encoding the block is worked out.
+    /// This is very slow, and intended for e.g. asset pipelines which want the smallest possible
+    /// output that any FastLZ decoder can handle.
+    /// [CompressOptions::lazy_matching] and [CompressOptions::two_entry_buckets] have no effect.
+    #[cfg(feature = "alloc")]
+    pub optimal_parse: bool,
+    /// Give up with [CompressError::Incompressible] once the output is larger than this fraction
+    /// of the input it covers
+    ///
+    /// This is checked as compression goes along (once at least 4 KiB of input has been covered),
+    /// so time isn't wasted compressing the rest of data which isn't going to get much smaller.
+    /// There is no stored mode in the format, so the caller is expected to keep such data uncompressed.
+    /// For example, 0.9 stops as soon as it becomes clear that less than 10% would be saved.
+    ///
+    /// The default of `None` never gives up.
+    pub incompressible_fraction: Option<f32>,
 }
-impl Default for CompressionLevel {
+
+impl Default for CompressOptions {
     fn default() -> Self {
-        Self::Default
+        Self {
+            allow_far_match_at_end: false,
+            c_compatible: false,
+            collect_stats: false,
+            acceleration: 1,
+            default_level_policy: None,
+            sample_default_level: false,
+            two_entry_buckets: false,
+            lazy_matching: false,
+            #[cfg(feature = "alloc")]
+            max_chain: 0,
+            #[cfg(feature = "alloc")]
+            optimal_parse: false,
+            incompressible_fraction: None,
+        }
     }
 }
 
-fn fastlz_hash(v: u32) -> usize {
-    let h = v.wrapping_mul(2654435769);
-    let h = h >> (32 - HTAB_LOG2);
-    h as usize
+impl CompressOptions {
+    /// The same options, but without [CompressOptions::incompressible_fraction]
+    fn never_give_up(&self) -> Self {
+        Self {
+            incompressible_fraction: None,
+            ..self.clone()
+        }
+    }
+
+    /// Number of positions checked using hash chains, or 0 if they aren't used
+    fn chain_depth(&self) -> usize {
+        #[cfg(feature = "alloc")]
+        if self.max_chain > 1 {
+            return self.max_chain;
+        }
+        0
+    }
 }
 
-trait InputHelper {
-    fn inc(&mut self, n: usize);
-    fn peek4(&mut self) -> Option<u32>;
+/// Wraps another output sink, reporting how much of the input it has covered
+struct ProgressOutput<L, F> {
+    inner: L,
+    processed: usize,
+    reporter: ProgressReporter<F>,
+}
+impl<L: CompressSink, F: FnMut(Progress) -> ControlFlow<()>> ProgressOutput<L, F> {
+    fn advance(&mut self, len: usize) -> Result<(), L::Error> {
+        self.processed += len;
+        if self.reporter.update(self.processed).is_break() {
+            return Err(CompressError::Cancelled.into());
+        }
+        Ok(())
+    }
 }
-impl InputHelper for &[u8] {
-    fn inc(&mut self, n: usize) {
-        *self = &self[n..];
+impl<L, F> OutputSink<L::Error> for ProgressOutput<L, F>
+where
+    L: OutputSink<L::Error> + CompressSink,
+    F: FnMut(Progress) -> ControlFlow<()>,
+{
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), L::Error> {
+        self.inner.put_lits(lits)?;
+        self.advance(lits.len())
     }
-    fn peek4(&mut self) -> Option<u32> {
-        let ret = u32::from_le_bytes(*self.first_chunk::<4>()?);
-        Some(ret)
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), L::Error> {
+        self.inner.put_backref(disp, len)?;
+        self.advance(len)
     }
 }
+impl<L: CompressSink, F> CompressSink for ProgressOutput<L, F> {
+    type Error = L::Error;
+    const MAX_DISP: usize = L::MAX_DISP;
+    const IS_LEVEL2: bool = L::IS_LEVEL2;
+    const REPORTS_PROGRESS: bool = true;
+}
 
-/// Holds state for performing compression operations
+/// Amount of input covered before [CompressOptions::incompressible_fraction] is checked
 ///
-/// This is only needed just in case stack overflows occur because the object is too big
-pub struct CompressState {
-    htab: [usize; HTAB_SZ],
+/// Literal runs are also split into pieces of this size when checking or reporting progress,
+/// so that this can happen part way through them. (As a multiple of 32, this doesn't change the output.)
+const INCOMPRESSIBLE_MIN_INPUT: usize = 4096;
+
+/// Wraps another output sink, giving up once the output is too large compared to the input it covers
+struct RatioCheckOutput<L> {
+    inner: L,
+    /// Maximum output per byte of input, with 16 fractional bits, or `None` to never give up
+    limit: Option<u64>,
+    consumed: usize,
+    written: usize,
 }
-impl CompressState {
-    /// Allocate a new compression state
-    pub fn new() -> Self {
-        Self { htab: [0; HTAB_SZ] }
+impl<L: CompressSink> RatioCheckOutput<L> {
+    fn new(inner: L, opts: &CompressOptions) -> Self {
+        Self {
+            inner,
+            limit: opts.incompressible_fraction.map(|x| (x * 65536.0) as u64),
+            consumed: 0,
+            written: 0,
+        }
     }
-    #[cfg(feature = "alloc")]
-    /// Allocate a new compression state specifically on the heap
-    ///
-    /// This is a workaround for non-guaranteed copy elision / RVO.
-    pub fn new_boxed() -> alloc::boxed::Box<Self> {
-        // *sigh* workaround for lack of Box::new_zeroed
-        unsafe {
-            let self_ = alloc::alloc::alloc_zeroed(core::alloc::Layout::new::<Self>()) as *mut Self;
-            alloc::boxed::Box::from_raw(self_)
+
+    fn advance(&mut self, consumed: usize, written: usize) -> Result<(), L::Error> {
+        self.consumed += consumed;
+        self.written += written;
+        match self.limit {
+            Some(limit)
+                if self.consumed >= INCOMPRESSIBLE_MIN_INPUT
+                    && (self.written as u64) << 16
+                        > (self.consumed as u64).saturating_mul(limit) =>
+            {
+                Err(CompressError::Incompressible.into())
+            }
+            _ => Ok(()),
         }
     }
+}
+impl<L> OutputSink<L::Error> for RatioCheckOutput<L>
+where
+    L: OutputSink<L::Error> + CompressSink,
+{
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), L::Error> {
+        self.inner.put_lits(lits)?;
+        // one opcode for every 32 literals
+        self.advance(lits.len(), lits.len() + lits.len().div_ceil(32))
+    }
 
-    fn compress_impl<L: OutputSink<CompressError> + CompressSink>(
-        &mut self,
-        mut inp: &[u8],
-        outp: &mut L,
-    ) -> Result<(), CompressError> {
-        if inp.len() == 0 {
-            return Ok(());
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), L::Error> {
+        self.inner.put_backref(disp, len)?;
+        self.advance(len, backref_cost::<L>(disp, len))
+    }
+}
+impl<L: CompressSink> CompressSink for RatioCheckOutput<L> {
+    type Error = L::Error;
+    const MAX_DISP: usize = L::MAX_DISP;
+    const IS_LEVEL2: bool = L::IS_LEVEL2;
+    const REPORTS_PROGRESS: bool = L::REPORTS_PROGRESS;
+}
+
+/// Details about a successful compression operation
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CompressOutcome {
+    /// Number of bytes of compressed output
+    pub written: usize,
+    /// The level which was actually used
+    ///
+    /// This is never [CompressionLevel::Default] or [CompressionLevel::Best].
+    pub level_used: CompressionLevel,
+    /// Size of the input divided by size of the output (i.e. higher is better)
+    ///
+    /// An empty input is considered to have a ratio of 1.
+    pub ratio: f64,
+    /// Statistics about the output, if [CompressOptions::collect_stats] was set
+    pub stats: Option<CompressStats>,
+}
+
+impl CompressOutcome {
+    fn new(
+        inp_len: usize,
+        written: usize,
+        level_used: CompressionLevel,
+        stats: Option<CompressStats>,
+    ) -> Self {
+        let ratio = if inp_len == 0 {
+            1.0
+        } else {
+            inp_len as f64 / written as f64
+        };
+        Self {
+            written,
+            level_used,
+            ratio,
+            stats,
         }
+    }
+}
 
-        self.htab.fill(0);
+/// Number of buckets in each [CompressStats] histogram
+pub const STATS_BUCKETS: usize = 32;
 
-        let orig_inp = inp;
-        let mut lits_start_anchor_pos = 0;
+/// Statistics about the contents of compressed output
+///
+/// This can help with laying out data so that it compresses better,
+/// e.g. by showing whether matches are being found mostly nearby or far away.
+///
+/// The histograms are bucketed by powers of two.
+/// Bucket `i` counts values in the range `2^i..2^(i+1)`, with the last bucket also counting anything larger.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct CompressStats {
+    /// Number of runs of literal bytes
+    pub literal_runs: usize,
+    /// Total number of literal bytes
+    pub literal_bytes: usize,
+    /// Number of backreferences
+    pub matches: usize,
+    /// Total number of bytes covered by backreferences
+    pub match_bytes: usize,
+    /// Histogram of backreference lengths
+    pub match_len_hist: [usize; STATS_BUCKETS],
+    /// Histogram of backreference distances (where a distance of 1 repeats the previous byte)
+    pub match_dist_hist: [usize; STATS_BUCKETS],
+}
+impl CompressStats {
+    fn bucket(x: usize) -> usize {
+        usize::min(x.ilog2() as usize, STATS_BUCKETS - 1)
+    }
+}
 
-        // we need to output at least 1 literal
-        // (unclear why C code skips 2?)
-        inp.inc(1);
-
-        while let Some(hash_head) = inp.peek4() {
-            let hash = fastlz_hash(hash_head & 0xffffff);
-            let cur_pos = inp.as_ptr() as usize - orig_inp.as_ptr() as usize;
-            let ref_pos = mem::replace(&mut self.htab[hash], cur_pos);
-            let ref_ = &orig_inp[ref_pos..];
-            debug_assert!(cur_pos > ref_pos);
-            let disp = cur_pos - ref_pos - 1;
-
-            if disp <= L::MAX_DISP && inp[..3] == ref_[..3] {
-                // we have a match of at least three bytes
-
-                if L::IS_LEVEL2 {
-                    if disp >= 8191 {
-                        // if this is a far-away match, we want at least 5 bytes to make it worthwhile
-                        if inp.len() < 5 {
-                            break;
-                        }
-                        if inp[3..5] != ref_[3..5] {
-                            inp.inc(1);
-                            continue;
-                        }
-                    }
-                }
+/// Wraps another output sink, recording statistics about what is sent to it
+struct StatsOutput<'a, L> {
+    inner: L,
+    stats: &'a mut CompressStats,
+}
+impl<L: OutputSink<E>, E> OutputSink<E> for StatsOutput<'_, L> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), E> {
+        self.stats.literal_runs += 1;
+        self.stats.literal_bytes += lits.len();
+        self.inner.put_lits(lits)
+    }
 
-                // compute the full match length
-                let mut len = 3 + inp[3..]
-                    .iter()
-                    .zip(ref_[3..].iter())
-                    .map_while(|(a, b)| if a == b { Some(1) } else { None })
-                    .fold(0, |a, x| a + x);
-
-                if L::IS_LEVEL2 {
-                    // for some reason, level2 doesn't allow *ending* a file on a far-away match
-                    if disp >= 8191 {
-                        if len == inp.len() {
-                            len -= 1;
-                        }
-                    }
-                }
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), E> {
+        self.stats.matches += 1;
+        self.stats.match_bytes += len;
+        self.stats.match_len_hist[CompressStats::bucket(len)] += 1;
+        self.stats.match_dist_hist[CompressStats::bucket(disp + 1)] += 1;
+        self.inner.put_backref(disp, len)
+    }
+}
+impl<L: CompressSink> CompressSink for StatsOutput<'_, L> {
+    type Error = L::Error;
+    const MAX_DISP: usize = L::MAX_DISP;
+    const IS_LEVEL2: bool = L::IS_LEVEL2;
+    const REPORTS_PROGRESS: bool = L::REPORTS_PROGRESS;
+}
 
-                // any accumulated lits?
-                let lits = &orig_inp[lits_start_anchor_pos..cur_pos];
-                if lits.len() > 0 {
-                    outp.put_lits(lits)?;
-                }
+/// Maximum possible size of the compressed output for an input of the given length
+///
+/// A buffer of this size can never be too small, at any level and with any options.
+///
+/// In the worst case, the input is stored as literals, which need an opcode for every 32 bytes.
+/// Every backreference saves at least the cost of the opcode of the literal run following it,
+/// except possibly a final level 2 match which had to be shortened (see [CompressOptions::allow_far_match_at_end]).
+pub const fn compress_bound(len: usize) -> usize {
+    len + len.div_ceil(32) + 1
+}
 
-                // now we can finally put in the match
-                outp.put_backref(disp, len)?;
-                lits_start_anchor_pos = cur_pos + len;
+/// Length (up to `max`) of the match between `pos` and `ref_pos`, or 0 if it is too short to use
+fn usable_len<L: CompressSink>(
+    inp: &mut impl CompressInput,
+    pos: usize,
+    ref_pos: usize,
+    max: usize,
+) -> usize {
+    let disp = pos - ref_pos - 1;
+    let len = inp.match_len(pos, ref_pos, max);
+    if len < 3 || (L::IS_LEVEL2 && disp >= 8191 && len < 5) {
+        0
+    } else {
+        len
+    }
+}
 
-                // update hashes at the boundary
-                inp.inc(len - 2);
-                if let Some(hash_head) = inp.peek4() {
-                    let hash = fastlz_hash(hash_head & 0xffffff);
-                    let cur_pos = inp.as_ptr() as usize - orig_inp.as_ptr() as usize;
-                    self.htab[hash] = cur_pos;
+/// Number of bytes needed to encode a backreference
+fn backref_cost<L: CompressSink>(disp: usize, len: usize) -> usize {
+    if L::IS_LEVEL2 {
+        // extended lengths take one byte for every 255, plus a terminating byte
+        let len_bytes = if len >= 9 { (len - 9) / 255 + 1 } else { 0 };
+        let disp_bytes = if disp >= 8191 { 3 } else { 1 };
+        1 + len_bytes + disp_bytes
+    } else {
+        // long matches are split into chunks of 262 bytes, each encoded with 3 bytes
+        let chunks = if len > 264 {
+            (len - 264).div_ceil(262)
+        } else {
+            0
+        };
+        let rest = len - chunks * 262;
+        chunks * 3 + if rest <= 8 { 2 } else { 3 }
+    }
+}
 
-                    let hash = fastlz_hash((hash_head >> 8) & 0xffffff);
-                    self.htab[hash] = cur_pos + 1;
+/// Choose whichever of two earlier positions gives the longer usable match, preferring `newer` on ties
+///
+/// Only as many bytes are compared as can be without reading more than [MAX_LOOKBACK]
+/// ahead of either candidate.
+fn longer_candidate<L: CompressSink>(
+    inp: &mut impl CompressInput,
+    pos: usize,
+    newer: usize,
+    older: usize,
+) -> usize {
+    let furthest = usize::min(newer, older);
+    if pos - furthest - 1 > L::MAX_DISP {
+        // can't be used anyways, so the main loop will check the other one
+        return usize::max(newer, older);
+    }
+    let max = usize::min(inp.len() - pos, furthest.saturating_add(MAX_LOOKBACK) - pos);
+    if usable_len::<L>(inp, pos, older, max) > usable_len::<L>(inp, pos, newer, max) {
+        older
+    } else {
+        newer
+    }
+}
 
-                    inp.inc(2);
-                } else {
-                    break;
-                }
-            } else {
-                // no match
-                inp.inc(1);
-            }
-        }
+/// Minimum length of a run which is matched without going through the hash table
+///
+/// Shorter runs are left to the hash table, which might find a longer match elsewhere.
+const MIN_RUN_LEN: usize = 16;
 
-        // if there's anything leftover, output it
-        let lits = &orig_inp[lits_start_anchor_pos..];
-        if lits.len() > 0 {
-            outp.put_lits(lits)?;
-        }
+/// Number of bytes by which the match at the next position has to be longer
+/// for [CompressOptions::lazy_matching] to emit a literal instead
+///
+/// A match only pays for the literal if it also saves the match following the shorter one,
+/// and in text-like input there usually is such a match which is already cheap.
+const LAZY_MIN_GAIN: usize = 7;
 
-        outp.poke_l2();
+/// Check whether `pos` is inside a long run of a repeating 1, 2, or 4 byte pattern
+///
+/// `head` is the next 4 bytes, which must already repeat with the pattern's period.
+/// Returns the (displacement, length) of a match against the previous repetition.
+#[inline(always)]
+fn find_run(inp: &mut impl CompressInput, pos: usize, head: u32) -> Option<(usize, usize)> {
+    let period = if head == head.rotate_left(8) {
+        1
+    } else if head == head.rotate_left(16) {
+        2
+    } else {
+        4
+    };
+    if pos < period || inp.peek4(pos - period) != Some(head) {
+        return None;
+    }
+    let len = inp.match_len(pos, pos - period, inp.len() - pos);
+    (len >= MIN_RUN_LEN).then_some((period - 1, len))
+}
 
-        Ok(())
+fn fastlz_hash(htab_log2: u32, v: u32) -> usize {
+    let h = v.wrapping_mul(2654435769);
+    let h = h >> (32 - htab_log2);
+    h as usize
+}
+
+/// Storage for the hash table of a [GenericCompressState]
+///
+/// This is implemented for anything which can be viewed as a slice of `u32`,
+/// e.g. arrays, `&mut [u32]`, or `Box<[u32]>`.
+pub trait HashTableStorage: AsRef<[u32]> + AsMut<[u32]> {}
+impl<T: AsRef<[u32]> + AsMut<[u32]> + ?Sized> HashTableStorage for T {}
+
+/// Holds state for performing compression operations
+///
+/// This is only needed just in case stack overflows occur because the object is too big
+///
+/// `HTAB_SZ` is the number of entries in the hash table, which must be a power of two
+/// (at least 2). Each entry takes 4 bytes. A smaller table saves memory
+/// at the cost of missing more matches, while a larger one finds more matches.
+///
+/// To choose the size at runtime, see [GenericCompressState::with_table].
+pub type CompressState<const HTAB_SZ: usize = DEFAULT_HTAB_SZ> =
+    GenericCompressState<[u32; HTAB_SZ]>;
+
+/// Compression state with its hash table stored in `T`
+///
+/// This is usually used through [CompressState], where the hash table is an array
+/// contained directly within the state.
+#[derive(Clone)]
+pub struct GenericCompressState<T: HashTableStorage> {
+    /// Positions in the input, offset by `htab_base` (wrapping for inputs of 4 GiB or more)
+    htab: T,
+    /// Offset added to positions stored during the current (or most recent) call
+    ///
+    /// Anything below this was stored by an earlier call and reads as position 0,
+    /// which is what a freshly cleared table would contain.
+    htab_base: u32,
+    /// Offset to be used by the next call, past every position the current call can store
+    htab_next_base: u32,
+}
+impl<T: HashTableStorage> fmt::Debug for GenericCompressState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the hash table is far too large to print in full
+        f.debug_struct("CompressState")
+            .field("htab_size", &self.htab.as_ref().len())
+            .field(
+                "htab_used",
+                &self
+                    .htab
+                    .as_ref()
+                    .iter()
+                    .filter(|&&x| x > self.htab_base)
+                    .count(),
+            )
+            .finish()
+    }
+}
+impl Default for CompressState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// Clears the hash table, which holds positions of (and so reveals information about) previous input
+#[cfg(feature = "zeroize")]
+impl<T: HashTableStorage> zeroize::Zeroize for GenericCompressState<T> {
+    fn zeroize(&mut self) {
+        self.htab.as_mut().zeroize();
+        self.htab_base.zeroize();
+        self.htab_next_base.zeroize();
+    }
+}
+#[cfg(feature = "zeroize")]
+impl<T: HashTableStorage> Drop for GenericCompressState<T> {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
     }
+}
+#[cfg(feature = "zeroize")]
+impl<T: HashTableStorage> zeroize::ZeroizeOnDrop for GenericCompressState<T> {}
+impl CompressState {
+    /// Allocate a new compression state with the default hash table size
+    ///
+    /// This is a `const fn`, so the state can be placed in a `static` rather than on the stack.
+    pub const fn new() -> Self {
+        Self::new_sized()
+    }
+    #[cfg(feature = "alloc")]
+    /// Allocate a new compression state with the default hash table size, with the table on the heap
+    ///
+    /// The table is allocated directly on the heap, so this never needs a large amount of stack
+    /// (unlike `Box::new(CompressState::new())`, which may build the state on the stack first,
+    /// as copy elision isn't guaranteed).
+    pub fn new_boxed() -> GenericCompressState<alloc::boxed::Box<[u32]>> {
+        Self::new_sized_boxed()
+    }
+}
+impl<const HTAB_SZ: usize> CompressState<HTAB_SZ> {
+    const VALID_HTAB_SZ: () = assert!(
+        HTAB_SZ.is_power_of_two() && HTAB_SZ >= 2 && HTAB_SZ.trailing_zeros() <= 32,
+        "hash table size must be a power of two"
+    );
 
-    /// Compress the input into a preallocated buffer
+    /// Allocate a new compression state with a hash table of `HTAB_SZ` entries
     ///
-    /// Returns the compressed size on success, or an error otherwise
-    pub fn compress_to_buf(
-        &mut self,
-        inp: &[u8],
-        outp: &mut [u8],
-        mut level: CompressionLevel,
-    ) -> Result<usize, CompressError> {
-        if level == CompressionLevel::Default {
-            if inp.len() < 65536 {
-                level = CompressionLevel::Level1;
-            } else {
-                level = CompressionLevel::Level2;
-            }
+    /// e.g. `CompressState::<1024>::new_sized()` for a 4 KiB table
+    pub const fn new_sized() -> Self {
+        let () = Self::VALID_HTAB_SZ;
+        Self {
+            htab: [0; HTAB_SZ],
+            htab_base: 0,
+            htab_next_base: 0,
         }
-
-        if level == CompressionLevel::Level1 {
-            let mut outp: L1Output<BufOutput> = L1Output(outp.into());
-            self.compress_impl(inp, &mut outp)?;
-            Ok(outp.0.pos)
-        } else {
-            let mut outp: L2Output<BufOutput> = L2Output(outp.into());
-            self.compress_impl(inp, &mut outp)?;
-            Ok(outp.0.pos)
+    }
+    #[cfg(feature = "alloc")]
+    /// Allocate a new compression state with a hash table of `HTAB_SZ` entries on the heap
+    ///
+    /// (See [CompressState::new_boxed].)
+    pub fn new_sized_boxed() -> GenericCompressState<alloc::boxed::Box<[u32]>> {
+        let () = Self::VALID_HTAB_SZ;
+        GenericCompressState::with_table(alloc::vec![0; HTAB_SZ].into_boxed_slice())
+    }
+}
+#[cfg(feature = "alloc")]
+impl GenericCompressState<alloc::boxed::Box<[u32]>> {
+    /// Allocate a new compression state with a hash table of 2<sup>`htab_log2`</sup> entries on the heap
+    ///
+    /// # Panics
+    ///
+    /// Panics if `htab_log2` is 0 or more than 32, or if the table would not fit in memory.
+    pub fn with_hash_bits(htab_log2: u32) -> Self {
+        assert!(
+            (1..=32).contains(&htab_log2) && htab_log2 < usize::BITS,
+            "invalid number of hash bits"
+        );
+        Self::with_table(alloc::vec![0; 1 << htab_log2].into_boxed_slice())
+    }
+}
+impl<T: HashTableStorage> GenericCompressState<T> {
+    /// Create a new compression state using `htab` to store the hash table
+    ///
+    /// This allows the table size to be chosen at runtime, and the table to be placed anywhere
+    /// (e.g. borrowed from a buffer that is shared with something else).
+    /// Any existing contents are cleared.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of entries isn't a power of two (of at least 2 and at most 2<sup>32</sup>).
+    pub fn with_table(mut htab: T) -> Self {
+        let len = htab.as_ref().len();
+        assert!(
+            len.is_power_of_two() && len >= 2 && len.trailing_zeros() <= 32,
+            "hash table size must be a power of two"
+        );
+        htab.as_mut().fill(0);
+        Self {
+            htab,
+            htab_base: 0,
+            htab_next_base: 0,
         }
     }
 
-    #[cfg(feature = "alloc")]
-    /// Decompress the input into a [Vec](alloc::vec::Vec)
+    /// Hash the next three bytes of input into an index into the hash table
+    #[inline(always)]
+    fn hash(&self, v: u32) -> usize {
+        fastlz_hash(self.htab.as_ref().len().trailing_zeros(), v)
+    }
+
+    /// Invalidate all hash table entries left over from previous calls
     ///
-    /// Returns the result on success, or an error otherwise
-    pub fn compress_to_vec(
-        &mut self,
-        inp: &[u8],
-        mut level: CompressionLevel,
-    ) -> Result<alloc::vec::Vec<u8>, CompressError> {
-        let ret = alloc::vec::Vec::new();
-        if level == CompressionLevel::Default {
-            if inp.len() < 65536 {
-                level = CompressionLevel::Level1;
-            } else {
-                level = CompressionLevel::Level2;
+    /// Rather than clearing the table every time, entries are offset so that
+    /// stale ones can be recognized when they are looked up.
+    /// The table only has to actually be cleared once the offset would overflow.
+    fn reset_htab(&mut self, inp_len: usize) {
+        let next_base = u32::try_from(inp_len)
+            .ok()
+            .and_then(|len| self.htab_next_base.checked_add(len));
+        match next_base {
+            Some(next_base) => {
+                self.htab_base = self.htab_next_base;
+                self.htab_next_base = next_base;
+            }
+            None => {
+                self.htab.as_mut().fill(0);
+                self.htab_base = 0;
+                // if the input doesn't fit, the table has to be cleared again next time
+                self.htab_next_base = u32::try_from(inp_len).unwrap_or(u32::MAX);
             }
         }
+    }
+
+    /// Store `pos` in the hash table, returning the position previously stored there
+    ///
+    /// Positions are stored modulo 2<sup>32</sup>, so in inputs of 4 GiB or more the returned
+    /// position may be unrelated to what was stored (but always comes before `pos`).
+    /// This is harmless because every candidate match is checked against the input anyways.
+    #[inline(always)]
+    fn htab_replace(&mut self, hash: usize, pos: usize) -> usize {
+        let cur = self.htab_base.wrapping_add(pos as u32);
+        let old = mem::replace(&mut self.htab.as_mut()[hash], cur);
+        self.htab_decode(old, pos)
+    }
+
+    /// Look up the position stored in the hash table, without replacing it
+    #[inline(always)]
+    fn htab_get(&self, hash: usize, pos: usize) -> usize {
+        self.htab_decode(self.htab.as_ref()[hash], pos)
+    }
 
-        if level == CompressionLevel::Level1 {
-            let mut ret: L1Output<VecOutput> = L1Output(ret.into());
-            self.compress_impl(inp, &mut ret)?;
-            Ok(ret.0.vec)
+    /// Convert a stored hash table entry back into a position before `pos`
+    #[inline(always)]
+    fn htab_decode(&self, old: u32, pos: usize) -> usize {
+        let cur = self.htab_base.wrapping_add(pos as u32);
+        // (on 16-bit targets, anything which doesn't fit is too far back anyway)
+        let dist = usize::try_from(cur.wrapping_sub(old)).unwrap_or(usize::MAX);
+        if old < self.htab_base || dist == 0 || dist > pos {
+            0
         } else {
-            let mut ret: L2Output<VecOutput> = L2Output(ret.into());
-            self.compress_impl(inp, &mut ret)?;
-            Ok(ret.0.vec)
+            pos - dist
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_lv1_encoding_lit() {
-        {
-            let mut out = [0u8; 3];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_lits(&[1, 2]).unwrap();
-            assert_eq!(outbuf.0.buf, [0x01, 1, 2]);
+    /// Index of the first entry of the two-entry bucket for the next three bytes of input
+    #[inline(always)]
+    fn bucket(&self, v: u32) -> usize {
+        match self.htab.as_ref().len().trailing_zeros() - 1 {
+            0 => 0,
+            bits => fastlz_hash(bits, v) * 2,
         }
+    }
 
-        {
-            let mut out = [0u8; 2];
+    /// Store `pos` as the newest entry in a two-entry bucket,
+    /// returning the (newest, oldest) positions previously stored there
+    #[inline(always)]
+    fn bucket_push(&mut self, bucket: usize, pos: usize) -> (usize, usize) {
+        let cur = self.htab_base.wrapping_add(pos as u32);
+        let entries = &mut self.htab.as_mut()[bucket..bucket + 2];
+        let newer = mem::replace(&mut entries[0], cur);
+        let older = mem::replace(&mut entries[1], newer);
+        (self.htab_decode(newer, pos), self.htab_decode(older, pos))
+    }
+
+    /// Look up the (newest, oldest) positions in a two-entry bucket, without adding to it
+    #[inline(always)]
+    fn bucket_get(&self, bucket: usize, pos: usize) -> (usize, usize) {
+        let entries = &self.htab.as_ref()[bucket..bucket + 2];
+        (
+            self.htab_decode(entries[0], pos),
+            self.htab_decode(entries[1], pos),
+        )
+    }
+
+    /// Store `pos` at the head of the hash chain for the three bytes `v`,
+    /// returning the position previously at the head
+    ///
+    #[inline(always)]
+    fn chain_push(&mut self, chain: &mut [u32], v: u32, pos: usize) -> usize {
+        let hash = self.hash(v);
+        let old = mem::replace(
+            &mut self.htab.as_mut()[hash],
+            self.htab_base.wrapping_add(pos as u32),
+        );
+        chain[pos % chain.len()] = old;
+        self.htab_decode(old, pos)
+    }
+
+    /// Follow the hash chain from `first` (which must be within reach of `pos`),
+    /// returning whichever of the positions gives the longest usable match
+    ///
+    /// At most `depth` positions are checked, and none before `min_ref`.
+    /// As in [longer_candidate], comparisons are limited so that nothing is read
+    /// more than [MAX_LOOKBACK] ahead of the oldest position.
+    fn longest_in_chain<L: CompressSink>(
+        &self,
+        inp: &mut impl CompressInput,
+        chain: &[u32],
+        pos: usize,
+        first: usize,
+        min_ref: usize,
+        depth: usize,
+    ) -> usize {
+        let next = |cand: usize| {
+            let next = self.htab_decode(chain[cand % chain.len()], cand);
+            (next < cand && next >= min_ref && pos - next - 1 <= L::MAX_DISP).then_some(next)
+        };
+
+        // find how far back the search will go before reading anything
+        let mut oldest = first;
+        let mut n = 1;
+        while n < depth {
+            let Some(cand) = next(oldest) else {
+                break;
+            };
+            oldest = cand;
+            n += 1;
+        }
+
+        let max = usize::min(inp.len() - pos, oldest.saturating_add(MAX_LOOKBACK) - pos);
+        let mut best = first;
+        let mut best_len = usable_len::<L>(inp, pos, first, max);
+        // i.e. the displacement is at least 8191
+        let is_far = |ref_pos: usize| L::IS_LEVEL2 && pos - ref_pos > 8191;
+        let mut cand = first;
+        for _ in 1..n {
+            // (the same candidates as were just counted)
+            let Some(next_cand) = next(cand) else {
+                break;
+            };
+            cand = next_cand;
+            let len = usable_len::<L>(inp, pos, cand, max);
+            // closer matches are never more expensive, so only switch for a longer one,
+            // and only if it makes up for the extra displacement bytes
+            let extra = if is_far(cand) && !is_far(best) { 2 } else { 0 };
+            if len > best_len + extra {
+                best = cand;
+                best_len = len;
+            }
+        }
+        best
+    }
+
+    /// Record that the three bytes `v` occur at `pos`
+    #[inline(always)]
+    fn htab_insert(&mut self, chain: &mut [u32], v: u32, pos: usize, opts: &CompressOptions) {
+        if !chain.is_empty() {
+            self.chain_push(chain, v, pos);
+        } else if opts.two_entry_buckets {
+            self.bucket_push(self.bucket(v), pos);
+        } else {
+            self.htab_set(self.hash(v), pos);
+        }
+    }
+
+    /// Store `pos` in the hash table
+    #[inline(always)]
+    fn htab_set(&mut self, hash: usize, pos: usize) {
+        self.htab.as_mut()[hash] = self.htab_base.wrapping_add(pos as u32);
+    }
+
+    /// Look for a match starting at `pos`, where the next 4 bytes are `head`
+    ///
+    /// Earlier occurrences before `min_ref` are not considered.
+    /// Unless `pos` is inside a run, it is also recorded in the hash table.
+    /// Returns the displacement and length of the match.
+    #[inline(always)]
+    fn find_match<I: CompressInput, L: CompressSink>(
+        &mut self,
+        inp: &mut I,
+        chain: &mut [u32],
+        pos: usize,
+        head: u32,
+        min_ref: usize,
+        opts: &CompressOptions,
+    ) -> Option<(usize, usize)> {
+        // inside a run, there's no need to hash every position
+        if let Some(run) = find_run(inp, pos, head) {
+            return Some(run);
+        }
+        self.find_hashed_match::<I, L>(inp, chain, pos, head, min_ref, true, opts)
+    }
+
+    /// Look for a match starting at `pos` using the hash table (see [Self::find_match])
+    ///
+    /// `pos` is only recorded in the hash table if `record` is set. Otherwise, the table is
+    /// left unchanged, and the result is the same as if it had been (for [Self::htab_insert]
+    /// to record `pos` later).
+    #[allow(clippy::too_many_arguments)]
+    #[inline(always)]
+    fn find_hashed_match<I: CompressInput, L: CompressSink>(
+        &mut self,
+        inp: &mut I,
+        chain: &mut [u32],
+        pos: usize,
+        head: u32,
+        min_ref: usize,
+        record: bool,
+        opts: &CompressOptions,
+    ) -> Option<(usize, usize)> {
+        let ref_pos = if !chain.is_empty() {
+            let first = if record {
+                self.chain_push(chain, head & 0xffffff, pos)
+            } else {
+                self.htab_get(self.hash(head & 0xffffff), pos)
+            };
+            if first < min_ref || pos - first - 1 > L::MAX_DISP {
+                return None;
+            }
+            self.longest_in_chain::<L>(inp, chain, pos, first, min_ref, opts.chain_depth())
+        } else if opts.two_entry_buckets {
+            let bucket = self.bucket(head & 0xffffff);
+            let entries = if record {
+                self.bucket_push(bucket, pos)
+            } else {
+                self.bucket_get(bucket, pos)
+            };
+            let (newer, older) = match entries {
+                (newer, older) if older >= min_ref => (newer, older),
+                (newer, _) => (newer, newer),
+            };
+            if newer < min_ref {
+                return None;
+            }
+            longer_candidate::<L>(inp, pos, newer, older)
+        } else {
+            let hash = self.hash(head & 0xffffff);
+            if record {
+                self.htab_replace(hash, pos)
+            } else {
+                self.htab_get(hash, pos)
+            }
+        };
+        if ref_pos < min_ref {
+            return None;
+        }
+        debug_assert!(pos > ref_pos);
+        let disp = pos - ref_pos - 1;
+
+        // (`ref_pos` is before `pos`, so there are always 4 bytes to read)
+        if disp > L::MAX_DISP || inp.peek4(ref_pos).map(|v| v & 0xffffff) != Some(head & 0xffffff) {
+            return None;
+        }
+        // we have a match of at least three bytes
+
+        let inp_len = inp.len();
+        if L::IS_LEVEL2
+            && disp >= 8191
+            && (inp_len - pos < 5 || inp.match_len(pos, ref_pos, 5) != 5)
+        {
+            // if this is a far-away match, we want at least 5 bytes to make it worthwhile
+            // (reading from `pos` rather than `pos + 3`, so that nothing is read ahead of
+            // candidates which get rejected, see [InputSource])
+            return None;
+        }
+
+        // compute the full match length
+        let mut len = 3 + inp.match_len(pos + 3, ref_pos + 3, inp_len - pos - 3);
+
+        // for some reason, level2 doesn't allow *ending* a file on a far-away match
+        if L::IS_LEVEL2 && disp >= 8191 && len == inp_len - pos && !opts.allow_far_match_at_end {
+            len -= 1;
+        }
+        Some((disp, len))
+    }
+
+    fn compress_impl<I: CompressInput, L: OutputSink<L::Error> + CompressSink>(
+        &mut self,
+        inp: &mut I,
+        outp: &mut L,
+        opts: &CompressOptions,
+    ) -> Result<(), L::Error> {
+        if opts.c_compatible {
+            return self.compress_impl_c(inp, outp, opts);
+        }
+        #[cfg(feature = "alloc")]
+        if opts.optimal_parse {
+            return self.compress_impl_optimal(inp, outp, opts);
+        }
+
+        let inp_len = inp.len();
+        if inp_len == 0 {
+            return Ok(());
+        }
+
+        self.reset_htab(inp_len);
+        let split_lits = opts.incompressible_fraction.is_some() || L::REPORTS_PROGRESS;
+
+        let mut lits_start_anchor_pos = 0;
+
+        // we need to output at least 1 literal
+        // (unclear why C code skips 2?)
+        let mut cur_pos = 1;
+        // number of positions tried since the last match, for acceleration
+        let mut misses = 0;
+        // match already found at `cur_pos` while looking ahead
+        let mut pending = None;
+        // links for hash chains, with one entry for every position within reach
+        #[cfg(feature = "alloc")]
+        let mut chain = alloc::vec![0; if opts.chain_depth() > 0 { L::MAX_DISP + 2 } else { 0 }];
+        #[cfg(not(feature = "alloc"))]
+        let mut chain = [0u32; 0];
+
+        while let Some(hash_head) = inp.peek4(cur_pos) {
+            // keep the amount of history which needs to be retained bounded
+            while I::EAGER_LITS && cur_pos - lits_start_anchor_pos > 32 {
+                inp.put_lits(lits_start_anchor_pos, lits_start_anchor_pos + 32, outp)?;
+                lits_start_anchor_pos += 32;
+            }
+            // long literal runs need to be checked (or reported as progress) before they end
+            while split_lits && cur_pos - lits_start_anchor_pos > INCOMPRESSIBLE_MIN_INPUT {
+                let end = lits_start_anchor_pos + INCOMPRESSIBLE_MIN_INPUT;
+                inp.put_lits(lits_start_anchor_pos, end, outp)?;
+                lits_start_anchor_pos = end;
+            }
+
+            let found = match pending.take() {
+                Some((m, record)) => {
+                    // the lookahead only probed the hash table, so record this position now
+                    if record {
+                        self.htab_insert(&mut chain, hash_head & 0xffffff, cur_pos, opts);
+                    }
+                    Some(m)
+                }
+                None => self.find_match::<I, L>(inp, &mut chain, cur_pos, hash_head, 0, opts),
+            };
+
+            if let Some((disp, len)) = found {
+                if opts.lazy_matching {
+                    // if the next byte starts a match which is much longer,
+                    // this byte is better off as a literal (see [LAZY_MIN_GAIN])
+                    // (candidates too far behind what was just read are skipped, see [InputSource])
+                    // (this only probes the hash table, as recording the next position would
+                    // evict an older candidate, only for it to be covered by this match)
+                    let min_ref = (cur_pos + len).saturating_sub(MAX_LOOKBACK);
+                    let next = inp.peek4(cur_pos + 1).and_then(|head| {
+                        let pos = cur_pos + 1;
+                        match find_run(inp, pos, head) {
+                            Some(run) => Some((run, false)),
+                            None => self
+                                .find_hashed_match::<I, L>(
+                                    inp, &mut chain, pos, head, min_ref, false, opts,
+                                )
+                                .map(|m| (m, true)),
+                        }
+                    });
+                    if next.is_some_and(|((_, next_len), _)| next_len > len + LAZY_MIN_GAIN) {
+                        pending = next;
+                        cur_pos += 1;
+                        continue;
+                    }
+                }
+
+                // any accumulated lits?
+                if cur_pos > lits_start_anchor_pos {
+                    inp.put_lits(lits_start_anchor_pos, cur_pos, outp)?;
+                }
+
+                // now we can finally put in the match
+                outp.put_backref(disp, len)?;
+                lits_start_anchor_pos = cur_pos + len;
+                misses = 0;
+
+                // update hashes at the boundary
+                cur_pos += len - 2;
+                if let Some(hash_head) = inp.peek4(cur_pos) {
+                    self.htab_insert(&mut chain, hash_head & 0xffffff, cur_pos, opts);
+                    self.htab_insert(&mut chain, (hash_head >> 8) & 0xffffff, cur_pos + 1, opts);
+
+                    cur_pos += 2;
+                } else {
+                    break;
+                }
+            } else {
+                // no match
+                // (saturating and clamped, as any acceleration is allowed)
+                let skip = usize::saturating_mul(misses >> 6, opts.acceleration);
+                cur_pos = cur_pos.saturating_add(skip).saturating_add(1).min(inp_len);
+                misses += 1;
+            }
+        }
+
+        // if there's anything leftover, output it
+        if inp_len > lits_start_anchor_pos {
+            inp.put_lits(lits_start_anchor_pos, inp_len, outp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compression loop for [CompressOptions::optimal_parse]
+    ///
+    /// Each block is parsed by finding the cheapest way of encoding every prefix of it,
+    /// given the cheapest ways of encoding the shorter prefixes.
+    #[cfg(feature = "alloc")]
+    fn compress_impl_optimal<I: CompressInput, L: OutputSink<L::Error> + CompressSink>(
+        &mut self,
+        inp: &mut I,
+        outp: &mut L,
+        opts: &CompressOptions,
+    ) -> Result<(), L::Error> {
+        use alloc::vec;
+
+        let inp_len = inp.len();
+        if inp_len == 0 {
+            return Ok(());
+        }
+
+        self.reset_htab(inp_len);
+        let split_lits = opts.incompressible_fraction.is_some() || L::REPORTS_PROGRESS;
+
+        let depth = usize::max(opts.chain_depth(), OPTIMAL_CHAIN);
+        let mut chain = vec![0; L::MAX_DISP + 2];
+        // cheapest cost of encoding the first `i` bytes of the block
+        let mut cost = vec![0u32; OPTIMAL_BLOCK + 1];
+        // (length, displacement) of the final step on the cheapest path, with a length of 1 for literals
+        let mut step = vec![(0u32, 0u32); OPTIMAL_BLOCK + 1];
+        // number of literals at the end of the cheapest path, which decides when opcodes are needed
+        let mut lit_run = vec![0u32; OPTIMAL_BLOCK + 1];
+        let mut path = vec::Vec::with_capacity(OPTIMAL_BLOCK);
+
+        let mut lits_start_anchor_pos = 0;
+        let mut block_start = 0;
+        while block_start < inp_len {
+            let n = usize::min(OPTIMAL_BLOCK, inp_len - block_start);
+            cost[1..=n].fill(u32::MAX);
+
+            // a long match running past the end of the block, which the block's path ends with
+            let mut tail = None;
+            let mut i = 0;
+            while i < n {
+                let pos = block_start + i;
+                let lit_cost = cost[i] + 1 + (lit_run[i] % 32 == 0) as u32;
+                if lit_cost < cost[i + 1] {
+                    cost[i + 1] = lit_cost;
+                    step[i + 1] = (1, 0);
+                    lit_run[i + 1] = lit_run[i] + 1;
+                }
+
+                let Some(head) = inp.peek4(pos) else {
+                    i += 1;
+                    continue;
+                };
+                let mut cand = self.chain_push(&mut chain, head & 0xffffff, pos);
+                // the first byte is always a literal
+                if pos == 0 {
+                    i += 1;
+                    continue;
+                }
+
+                let mut best_len = 0;
+                let mut best_disp = 0;
+                for _ in 0..depth {
+                    let disp = pos - cand - 1;
+                    if disp > L::MAX_DISP {
+                        break;
+                    }
+                    let mut len = inp.match_len(pos, cand, inp_len - pos);
+                    // level2 doesn't allow *ending* a file on a far-away match
+                    if L::IS_LEVEL2
+                        && disp >= 8191
+                        && pos + len == inp_len
+                        && !opts.allow_far_match_at_end
+                    {
+                        len -= 1;
+                    }
+                    // older candidates are never cheaper, so only bother with longer matches
+                    if len > best_len && len >= 3 {
+                        best_len = len;
+                        best_disp = disp;
+                        // every length up to where the encoding gets longer, and the full length
+                        let full = usize::min(len, n - i);
+                        for len in (3..usize::min(full, 10)).chain([full]) {
+                            if len < 3 {
+                                break;
+                            }
+                            // (`len` is at most `n - i`, so this is always within the block)
+                            let Some(&old) = cost.get(i + len) else {
+                                break;
+                            };
+                            let c = cost[i] + backref_cost::<L>(disp, len) as u32;
+                            if c < old {
+                                cost[i + len] = c;
+                                step[i + len] = (len as u32, disp as u32);
+                                lit_run[i + len] = 0;
+                            }
+                        }
+                    }
+                    if best_len >= OPTIMAL_NICE_LEN {
+                        break;
+                    }
+                    let next = self.htab_decode(chain[cand % chain.len()], cand);
+                    if next >= cand {
+                        break;
+                    }
+                    cand = next;
+                }
+
+                if best_len >= OPTIMAL_NICE_LEN {
+                    // don't bother with anything else starting within this match
+                    if i + best_len > n {
+                        tail = Some((best_len, best_disp));
+                        break;
+                    }
+                    i += best_len;
+                } else {
+                    i += 1;
+                }
+            }
+
+            // follow the cheapest path back from the end of the block (or the start of the tail)
+            path.clear();
+            if let Some(tail) = tail {
+                path.push(tail);
+            } else {
+                i = n;
+            }
+            while i > 0 {
+                // (always within the block, as steps only ever go backwards)
+                let Some(&(len, disp)) = step.get(i) else {
+                    break;
+                };
+                path.push((len as usize, disp as usize));
+                i = i.saturating_sub(len as usize);
+            }
+
+            let mut pos = block_start;
+            for &(len, disp) in path.iter().rev() {
+                if len > 1 {
+                    if pos > lits_start_anchor_pos {
+                        inp.put_lits(lits_start_anchor_pos, pos, outp)?;
+                    }
+                    outp.put_backref(disp, len)?;
+                    lits_start_anchor_pos = pos + len;
+                }
+                pos += len;
+            }
+            while I::EAGER_LITS && pos - lits_start_anchor_pos > 32 {
+                inp.put_lits(lits_start_anchor_pos, lits_start_anchor_pos + 32, outp)?;
+                lits_start_anchor_pos += 32;
+            }
+            // long literal runs need to be checked (or reported as progress) before they end
+            while split_lits && pos - lits_start_anchor_pos > INCOMPRESSIBLE_MIN_INPUT {
+                let end = lits_start_anchor_pos + INCOMPRESSIBLE_MIN_INPUT;
+                inp.put_lits(lits_start_anchor_pos, end, outp)?;
+                lits_start_anchor_pos = end;
+            }
+
+            lit_run[0] = if tail.is_some() { 0 } else { lit_run[n] };
+            block_start = pos;
+        }
+
+        // if there's anything leftover, output it
+        if inp_len > lits_start_anchor_pos {
+            inp.put_lits(lits_start_anchor_pos, inp_len, outp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compression loop mirroring the reference C code, quirks and all
+    ///
+    /// Of the options, only [CompressOptions::incompressible_fraction] is used.
+    fn compress_impl_c<I: CompressInput, L: OutputSink<L::Error> + CompressSink>(
+        &mut self,
+        inp: &mut I,
+        outp: &mut L,
+        opts: &CompressOptions,
+    ) -> Result<(), L::Error> {
+        let inp_len = inp.len();
+        if inp_len == 0 {
+            return Ok(());
+        }
+
+        self.reset_htab(inp_len);
+        let split_lits = opts.incompressible_fraction.is_some() || L::REPORTS_PROGRESS;
+
+        // only ever used where at least 4 bytes remain
+        fn read3(inp: &mut impl CompressInput, pos: usize) -> u32 {
+            inp.peek4(pos).unwrap_or_default() & 0xffffff
+        }
+
+        // the C code stops looking for matches well before the end of the input
+        let ip_bound = inp_len.saturating_sub(4);
+        let ip_limit = inp_len.saturating_sub(13);
+        // the C code compares `ip - ref`, which is one more than our `disp`
+        let max_distance = if L::IS_LEVEL2 {
+            saturating_usize(8191 + 65535 - 1)
+        } else {
+            8192
+        };
+
+        let mut anchor = 0;
+        // the C code always starts with two literals
+        let mut ip = 2;
+
+        while ip < ip_limit {
+            let mut ref_pos;
+            let mut distance;
+            loop {
+                // keep the amount of history which needs to be retained bounded
+                if I::EAGER_LITS && ip - anchor > 32 {
+                    inp.put_lits(anchor, anchor + 32, outp)?;
+                    anchor += 32;
+                }
+                // long literal runs need to be checked (or reported as progress) before they end
+                if split_lits && ip - anchor > INCOMPRESSIBLE_MIN_INPUT {
+                    inp.put_lits(anchor, anchor + INCOMPRESSIBLE_MIN_INPUT, outp)?;
+                    anchor += INCOMPRESSIBLE_MIN_INPUT;
+                }
+
+                let seq = read3(inp, ip);
+                let hash = self.hash(seq);
+                ref_pos = self.htab_replace(hash, ip);
+                distance = ip - ref_pos;
+                let is_match = distance < max_distance && read3(inp, ref_pos) == seq;
+                if ip >= ip_limit {
+                    break;
+                }
+                ip += 1;
+                if is_match {
+                    break;
+                }
+            }
+            if ip >= ip_limit {
+                break;
+            }
+            ip -= 1;
+
+            // far matches need at least 5 bytes (including, unlike us, disp == 8190)
+            if L::IS_LEVEL2 && distance >= 8191 && inp.match_len(ip, ref_pos, 5) != 5 {
+                ip += 1;
+                continue;
+            }
+
+            if ip > anchor {
+                inp.put_lits(anchor, ip, outp)?;
+            }
+
+            // the C code counts the first mismatching byte, but then encodes this as len - 2
+            // (if the match extends all the way up to the bound, this loses a byte)
+            let max_cmp = ip_bound - (ip + 3);
+            let mut cmp_len = inp.match_len(ip + 3, ref_pos + 3, max_cmp);
+            if cmp_len < max_cmp {
+                cmp_len += 1;
+            }
+            outp.put_backref(distance - 1, cmp_len + 2)?;
+
+            // update hashes at the boundary
+            ip += cmp_len;
+            self.htab_set(self.hash(read3(inp, ip)), ip);
+            self.htab_set(self.hash(read3(inp, ip + 1)), ip + 1);
+            ip += 2;
+            anchor = ip;
+        }
+
+        if inp_len > anchor {
+            inp.put_lits(anchor, inp_len, outp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve [CompressionLevel::Default] and [CompressionLevel::Best] into an actual level
+    ///
+    /// (Always inlined, so that when there is no [CompressOptions::default_level_policy]
+    /// the compiler can see that the codec never calls out to anything which might panic.)
+    #[cfg(feature = "level2")]
+    #[inline(always)]
+    fn resolve_level(
+        &mut self,
+        inp: &mut impl CompressInput,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> CompressionLevel {
+        let level = match (level, opts.default_level_policy) {
+            (CompressionLevel::Default, Some(policy)) => policy(inp.len()),
+            _ => level,
+        };
+        self.resolve_builtin_level(inp, level, opts)
+    }
+
+    /// Resolve the levels left after applying any [CompressOptions::default_level_policy]
+    #[cfg(feature = "level2")]
+    fn resolve_builtin_level(
+        &mut self,
+        inp: &mut impl CompressInput,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> CompressionLevel {
+        match level {
+            CompressionLevel::Default if opts.sample_default_level => {
+                if self.sample_far_matches(inp) {
+                    CompressionLevel::Level2
+                } else {
+                    CompressionLevel::Level1
+                }
+            }
+            CompressionLevel::Default => {
+                if inp.len() < 65536 {
+                    CompressionLevel::Level1
+                } else {
+                    CompressionLevel::Level2
+                }
+            }
+            CompressionLevel::Best => {
+                let (mut l1_len, mut l2_len) = (CountOutput(0), CountOutput(0));
+                // wrapped the same way as the real output, so that the compressor can be shared
+                // with `opt-size` (counting can never fail)
+                let counting_opts = opts.never_give_up();
+                let mut l1 =
+                    RatioCheckOutput::new(L1Output(erase_sink(&mut l1_len)), &counting_opts);
+                let l2 = L2Output(MarkL2::new(erase_sink(&mut l2_len)));
+                let mut l2 = RatioCheckOutput::new(l2, &counting_opts);
+                let _ = self.compress_impl(inp, &mut l1, opts);
+                let _ = self.compress_impl(inp, &mut l2, opts);
+                if l2_len.0 < l1_len.0 {
+                    CompressionLevel::Level2
+                } else {
+                    CompressionLevel::Level1
+                }
+            }
+            _ => level,
+        }
+    }
+
+    /// Without level 2, everything resolves to level 1
+    #[cfg(not(feature = "level2"))]
+    fn resolve_level(
+        &mut self,
+        _inp: &mut impl CompressInput,
+        _level: CompressionLevel,
+        _opts: &CompressOptions,
+    ) -> CompressionLevel {
+        CompressionLevel::Level1
+    }
+
+    /// Check whether a significant fraction of sampled windows can only be matched further back
+    /// than level 1 can reach
+    ///
+    /// Reads only ever move forwards through the input.
+    #[cfg(feature = "level2")]
+    fn sample_far_matches(&mut self, inp: &mut impl CompressInput) -> bool {
+        const L1_MAX_DISP: usize = <L1Output<CountOutput> as CompressSink>::MAX_DISP;
+        const L2_MAX_DISP: usize = <L2Output<CountOutput> as CompressSink>::MAX_DISP;
+
+        let inp_len = inp.len();
+        // windows start far enough in for there to be anything beyond level 1's reach
+        let first = L1_MAX_DISP + 1;
+        if inp_len < first + SAMPLE_WINDOW {
+            return false;
+        }
+        let last = inp_len - SAMPLE_WINDOW;
+        let nwindows = usize::min(SAMPLE_WINDOWS, (last - first) / SAMPLE_WINDOW + 1);
+        let stride = if nwindows > 1 {
+            (last - first) / (nwindows - 1)
+        } else {
+            0
+        };
+
+        self.reset_htab(inp_len);
+        let mut far_bytes = 0;
+        let mut hist_start = 0;
+        for i in 0..nwindows {
+            let start = first + i * stride;
+            let end = start + SAMPLE_WINDOW;
+
+            // index the history before the window,
+            // densely within level 1's reach and sparsely beyond it
+            let near_start = usize::max(hist_start, start - L1_MAX_DISP);
+            let mut pos = usize::max(hist_start, start.saturating_sub(L2_MAX_DISP));
+            while pos < start {
+                if let Some(v) = inp.peek4(pos) {
+                    self.htab_set(self.hash(v & 0xffffff), pos);
+                }
+                pos += if pos < near_start { 4 } else { 1 };
+            }
+
+            pos = start;
+            while pos < end {
+                let Some(v) = inp.peek4(pos) else {
+                    break;
+                };
+                let ref_pos = self.htab_replace(self.hash(v & 0xffffff), pos);
+                if ref_pos < pos
+                    && pos - ref_pos - 1 <= L2_MAX_DISP
+                    && inp.match_len(pos, ref_pos, 3) == 3
+                {
+                    let len = 3 + inp.match_len(pos + 3, ref_pos + 3, end - pos - 3);
+                    if pos - ref_pos - 1 > L1_MAX_DISP {
+                        far_bytes += len;
+                    }
+                    pos += len;
+                } else {
+                    pos += 1;
+                }
+            }
+            hist_start = end;
+        }
+
+        far_bytes * 8 >= nwindows * SAMPLE_WINDOW
+    }
+
+    /// Compress the input into the given sink at the given level
+    ///
+    /// (Always inlined for the same reason as [Self::resolve_level].)
+    #[inline(always)]
+    fn compress_to_output<O: ByteSink>(
+        &mut self,
+        inp: &mut impl CompressInput,
+        outp: O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<(), O::Error> {
+        let level = self.resolve_level(inp, level, opts);
+        self.compress_to_output_at(inp, outp, level, opts)
+    }
+
+    /// Compress the input into the given sink at the given (resolved) level
+    fn compress_to_output_at<O: ByteSink>(
+        &mut self,
+        inp: &mut impl CompressInput,
+        mut outp: O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<(), O::Error> {
+        let outp = erase_sink(&mut outp);
+        match level {
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2 => {
+                let mut outp = RatioCheckOutput::new(L2Output(MarkL2::new(outp)), opts);
+                self.compress_impl(inp, &mut outp, opts)?;
+                outp.inner.0.flush()
+            }
+            _ => {
+                let mut outp = RatioCheckOutput::new(L1Output(outp), opts);
+                self.compress_impl(inp, &mut outp, opts)?;
+                outp.inner.0.flush()
+            }
+        }
+    }
+
+    /// Compress the input into the given sink, periodically reporting progress
+    fn compress_to_output_with_progress<O: ByteSink>(
+        &mut self,
+        inp: &mut impl CompressInput,
+        mut outp: O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+        mut progress: impl FnMut(Progress) -> ControlFlow<()>,
+    ) -> Result<(), O::Error> {
+        let outp = erase_sink(&mut outp);
+        let reporter = ProgressReporter::new(inp.len(), erase_progress(&mut progress));
+        match self.resolve_level(inp, level, opts) {
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2 => {
+                let outp = ProgressOutput {
+                    inner: L2Output(MarkL2::new(outp)),
+                    processed: 0,
+                    reporter,
+                };
+                let mut outp = RatioCheckOutput::new(outp, opts);
+                self.compress_impl(inp, &mut outp, opts)?;
+                outp.inner.inner.0.flush()
+            }
+            _ => {
+                let outp = ProgressOutput {
+                    inner: L1Output(outp),
+                    processed: 0,
+                    reporter,
+                };
+                let mut outp = RatioCheckOutput::new(outp, opts);
+                self.compress_impl(inp, &mut outp, opts)?;
+                outp.inner.inner.0.flush()
+            }
+        }
+    }
+
+    /// Estimate how much more output is needed, after running out of space
+    ///
+    /// `written` is the truncated output, which must have been produced from the same input.
+    /// The complete opcodes in it determine how much input was covered, and the rest is
+    /// bounded with [compress_bound] rather than by compressing everything again.
+    /// (Always inlined for the same reason as [Self::resolve_level].)
+    #[inline(always)]
+    fn output_too_small(inp: &impl CompressInput, written: &[u8]) -> CompressError {
+        let mut processed = CountOutput(0);
+        let _ = decompress_impl(written, &mut processed, &DecompressOptions::default());
+
+        // the output for the rest of the input can't exceed its bound, whatever precedes it,
+        // so this is always enough (if usually a little more than needed)
+        CompressError::OutputTooSmall {
+            written: written.len(),
+            input_processed: processed.0,
+            remaining: compress_bound(inp.len().saturating_sub(processed.0)),
+        }
+    }
+
+    /// Compress the input into a custom [ByteSink]
+    ///
+    /// Returns an error if the sink does
+    pub fn compress_to_sink<O: ByteSink>(
+        &mut self,
+        inp: &[u8],
+        outp: &mut O,
+        level: CompressionLevel,
+    ) -> Result<(), O::Error> {
+        self.compress_to_sink_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress the input into a custom [ByteSink], with additional options
+    ///
+    /// Returns an error if the sink does
+    pub fn compress_to_sink_with_options<O: ByteSink>(
+        &mut self,
+        mut inp: &[u8],
+        outp: &mut O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<(), O::Error> {
+        #[cfg(all(feature = "paranoid", feature = "alloc"))]
+        {
+            let mut outp = TeeSink::new(outp);
+            self.compress_to_output(&mut inp, &mut outp, level, opts)?;
+            verify_compressed(&mut inp, &outp.copy, opts);
+            Ok(())
+        }
+        #[cfg(not(all(feature = "paranoid", feature = "alloc")))]
+        self.compress_to_output(&mut inp, outp, level, opts)
+    }
+
+    /// Compress the input into a custom [ByteSink], with additional options,
+    /// periodically reporting progress
+    ///
+    /// `progress` is called roughly every [PROGRESS_INTERVAL] bytes of input.
+    /// If it returns [ControlFlow::Break], compression stops with [CompressError::Cancelled].
+    ///
+    /// Returns an error if the sink does
+    pub fn compress_to_sink_with_progress<O: ByteSink>(
+        &mut self,
+        mut inp: &[u8],
+        outp: &mut O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+        progress: impl FnMut(Progress) -> ControlFlow<()>,
+    ) -> Result<(), O::Error> {
+        #[cfg(all(feature = "paranoid", feature = "alloc"))]
+        {
+            let mut outp = TeeSink::new(outp);
+            self.compress_to_output_with_progress(&mut inp, &mut outp, level, opts, progress)?;
+            verify_compressed(&mut inp, &outp.copy, opts);
+            Ok(())
+        }
+        #[cfg(not(all(feature = "paranoid", feature = "alloc")))]
+        self.compress_to_output_with_progress(&mut inp, outp, level, opts, progress)
+    }
+
+    /// Compress the input from a custom [InputSource] into a custom [ByteSink]
+    ///
+    /// Returns an error if the sink does
+    pub fn compress_source_to_sink<S: InputSource + ?Sized, O: ByteSink>(
+        &mut self,
+        inp: &mut S,
+        outp: &mut O,
+        level: CompressionLevel,
+    ) -> Result<(), O::Error> {
+        self.compress_source_to_sink_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress the input from a custom [InputSource] into a custom [ByteSink], with additional options
+    ///
+    /// Returns an error if the sink does
+    pub fn compress_source_to_sink_with_options<S: InputSource + ?Sized, O: ByteSink>(
+        &mut self,
+        inp: &mut S,
+        outp: &mut O,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<(), O::Error> {
+        let mut inp = SourceInput::new(inp);
+        #[cfg(all(feature = "paranoid", feature = "alloc"))]
+        {
+            let mut outp = TeeSink::new(outp);
+            self.compress_to_output(&mut inp, &mut outp, level, opts)?;
+            verify_compressed(&mut inp, &outp.copy, opts);
+            Ok(())
+        }
+        #[cfg(not(all(feature = "paranoid", feature = "alloc")))]
+        self.compress_to_output(&mut inp, outp, level, opts)
+    }
+
+    /// Compute exactly how large the compressed output will be, without writing it anywhere
+    ///
+    /// This does all of the work of compressing the input, so it takes just as long.
+    pub fn compressed_len(&mut self, inp: &[u8], level: CompressionLevel) -> usize {
+        self.compressed_len_with_options(inp, level, &CompressOptions::default())
+    }
+
+    /// Compute exactly how large the compressed output will be, without writing it anywhere,
+    /// with additional options
+    ///
+    /// This does all of the work of compressing the input, so it takes just as long.
+    /// [CompressOptions::incompressible_fraction] is ignored.
+    pub fn compressed_len_with_options(
+        &mut self,
+        mut inp: &[u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> usize {
+        let mut count = CountOutput(0);
+        // counting can never fail
+        let _ = self.compress_to_output(&mut inp, &mut count, level, &opts.never_give_up());
+        count.0
+    }
+
+    /// Compress the input into a preallocated buffer
+    ///
+    /// Returns the compressed size on success, or an error otherwise
+    pub fn compress_to_buf(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+        level: CompressionLevel,
+    ) -> Result<usize, CompressError> {
+        self.compress_to_buf_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress the input into a preallocated buffer, with additional options
+    ///
+    /// Returns the compressed size on success, or an error otherwise
+    // (always inlined for the same reason as `resolve_level`)
+    #[inline(always)]
+    pub fn compress_to_buf_with_options(
+        &mut self,
+        mut inp: &[u8],
+        outp: &mut [u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<usize, CompressError> {
+        let mut outp: BufOutput = outp.into();
+        match self.compress_to_output(&mut inp, &mut outp, level, opts) {
+            Ok(()) => {
+                #[cfg(feature = "paranoid")]
+                verify_compressed(&mut inp, outp.split().0, opts);
+                Ok(outp.pos)
+            }
+            Err(CompressError::OutputTooSmall { .. }) => {
+                Err(Self::output_too_small(&inp, outp.split().0))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Compress the input into a preallocated buffer, returning additional details
+    ///
+    /// Returns a [CompressOutcome] on success, or an error otherwise
+    pub fn compress_to_buf_ex(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+        level: CompressionLevel,
+    ) -> Result<CompressOutcome, CompressError> {
+        self.compress_to_buf_ex_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress the input into a preallocated buffer, returning additional details,
+    /// with additional options
+    ///
+    /// Returns a [CompressOutcome] on success, or an error otherwise
+    pub fn compress_to_buf_ex_with_options(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<CompressOutcome, CompressError> {
+        // resolving here (rather than when compressing) lets us find out the result
+        let level_used = self.resolve_level(&mut &*inp, level, opts);
+        let written = self.compress_to_buf_with_options(inp, outp, level_used, opts)?;
+        let stats = if opts.collect_stats {
+            Some(self.collect_stats(&mut &*inp, level_used, opts))
+        } else {
+            None
+        };
+        Ok(CompressOutcome::new(inp.len(), written, level_used, stats))
+    }
+
+    /// Compress the input into a preallocated buffer, using whichever level fits
+    ///
+    /// Level 2 is tried first, then level 1 (or only level 1, without the `level2` feature).
+    /// This is intended for fixed-size destinations where fitting at all matters more than speed.
+    ///
+    /// Storing the input as literals is not tried separately, as level 1 output is never larger than that:
+    /// every backreference saves at least as much as the opcode of the literal run following it.
+    ///
+    /// Returns a [CompressOutcome] on success. If neither level fits, the error describes
+    /// whichever is estimated to need less space, and the contents of the buffer are unspecified.
+    pub fn compress_to_fit(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+    ) -> Result<CompressOutcome, CompressError> {
+        self.compress_to_fit_with_options(inp, outp, &CompressOptions::default())
+    }
+
+    /// Compress the input into a preallocated buffer, using whichever level fits,
+    /// with additional options
+    ///
+    /// See [compress_to_fit](Self::compress_to_fit) for details.
+    pub fn compress_to_fit_with_options(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+        opts: &CompressOptions,
+    ) -> Result<CompressOutcome, CompressError> {
+        #[cfg(feature = "level2")]
+        let l2_err =
+            match self.compress_to_buf_ex_with_options(inp, outp, CompressionLevel::Level2, opts) {
+                Err(e @ CompressError::OutputTooSmall { .. }) => e,
+                res => return res,
+            };
+        let l1_err =
+            match self.compress_to_buf_ex_with_options(inp, outp, CompressionLevel::Level1, opts) {
+                Err(e @ CompressError::OutputTooSmall { .. }) => e,
+                res => return res,
+            };
+
+        #[cfg(feature = "level2")]
+        {
+            let required = |e: &CompressError| match *e {
+                CompressError::OutputTooSmall {
+                    written, remaining, ..
+                } => written + remaining,
+                _ => unreachable!(),
+            };
+            if required(&l2_err) < required(&l1_err) {
+                return Err(l2_err);
+            }
+        }
+        Err(l1_err)
+    }
+
+    /// Compress the input again at the given (resolved) level, only recording statistics
+    fn collect_stats(
+        &mut self,
+        inp: &mut impl CompressInput,
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> CompressStats {
+        let mut stats = CompressStats::default();
+        // counting can never fail
+        match level {
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2 => {
+                let mut outp = StatsOutput {
+                    inner: L2Output(CountOutput(0)),
+                    stats: &mut stats,
+                };
+                let _ = self.compress_impl(inp, &mut outp, opts);
+            }
+            _ => {
+                let mut outp = StatsOutput {
+                    inner: L1Output(CountOutput(0)),
+                    stats: &mut stats,
+                };
+                let _ = self.compress_impl(inp, &mut outp, opts);
+            }
+        }
+        stats
+    }
+
+    /// Compress a list of slices, treated as one logical input, into a preallocated buffer
+    ///
+    /// This produces the same output as concatenating the slices and compressing the result.
+    ///
+    /// Returns the compressed size on success, or an error otherwise
+    pub fn compress_vectored(
+        &mut self,
+        inp: &[&[u8]],
+        outp: &mut [u8],
+        level: CompressionLevel,
+    ) -> Result<usize, CompressError> {
+        self.compress_vectored_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress a list of slices, treated as one logical input, into a preallocated buffer,
+    /// with additional options
+    ///
+    /// Returns the compressed size on success, or an error otherwise
+    pub fn compress_vectored_with_options(
+        &mut self,
+        mut inp: &[&[u8]],
+        outp: &mut [u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<usize, CompressError> {
+        let mut inp = SourceInput::new(&mut inp);
+        let mut outp: BufOutput = outp.into();
+        match self.compress_to_output(&mut inp, &mut outp, level, opts) {
+            Ok(()) => {
+                #[cfg(feature = "paranoid")]
+                verify_compressed(&mut inp, outp.split().0, opts);
+                Ok(outp.pos)
+            }
+            Err(CompressError::OutputTooSmall { .. }) => {
+                Err(Self::output_too_small(&inp, outp.split().0))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Compress the input into a preallocated buffer which doesn't need to be initialized
+    ///
+    /// Returns the initialized (i.e. compressed) portion of the buffer on success, or an error otherwise
+    #[cfg(feature = "unsafe-perf")]
+    pub fn compress_to_uninit_buf<'a>(
+        &mut self,
+        inp: &[u8],
+        outp: &'a mut [MaybeUninit<u8>],
+        level: CompressionLevel,
+    ) -> Result<&'a [u8], CompressError> {
+        self.compress_to_uninit_buf_with_options(inp, outp, level, &CompressOptions::default())
+    }
+
+    /// Compress the input into a preallocated buffer which doesn't need to be initialized,
+    /// with additional options
+    ///
+    /// Returns the initialized (i.e. compressed) portion of the buffer on success, or an error otherwise
+    #[cfg(feature = "unsafe-perf")]
+    // (always inlined for the same reason as `resolve_level`)
+    #[inline(always)]
+    pub fn compress_to_uninit_buf_with_options<'a>(
+        &mut self,
+        mut inp: &[u8],
+        outp: &'a mut [MaybeUninit<u8>],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<&'a [u8], CompressError> {
+        let mut outp: UninitBufOutput = outp.into();
+        match self.compress_to_output(&mut inp, &mut outp, level, opts) {
+            Ok(()) => {
+                let written = outp.into_init();
+                #[cfg(feature = "paranoid")]
+                verify_compressed(&mut inp, written, opts);
+                Ok(written)
+            }
+            Err(CompressError::OutputTooSmall { .. }) => {
+                Err(Self::output_too_small(&inp, outp.into_init()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    /// Decompress the input into a [Vec](alloc::vec::Vec)
+    ///
+    /// Returns the result on success, or an error otherwise
+    pub fn compress_to_vec(
+        &mut self,
+        inp: &[u8],
+        level: CompressionLevel,
+    ) -> Result<alloc::vec::Vec<u8>, CompressError> {
+        self.compress_to_vec_with_options(inp, level, &CompressOptions::default())
+    }
+
+    #[cfg(feature = "alloc")]
+    /// Compress the input into a [Vec](alloc::vec::Vec), with additional options
+    ///
+    /// Returns the result on success, or an error otherwise
+    pub fn compress_to_vec_with_options(
+        &mut self,
+        mut inp: &[u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+    ) -> Result<alloc::vec::Vec<u8>, CompressError> {
+        #[cfg(feature = "unsafe-perf")]
+        {
+            let mut ret = alloc::vec::Vec::with_capacity(compress_bound(inp.len()));
+            let mut outp: UninitBufOutput = ret.spare_capacity_mut().into();
+            self.compress_to_output(&mut inp, &mut outp, level, opts)?;
+            let len = outp.into_init().len();
+            // SAFETY: the compressor initialized exactly this many bytes
+            unsafe { ret.set_len(len) };
+            #[cfg(feature = "paranoid")]
+            verify_compressed(&mut inp, &ret, opts);
+            Ok(ret)
+        }
+        #[cfg(not(feature = "unsafe-perf"))]
+        {
+            // (zeroed first, as it can't be written to otherwise without unsafe code)
+            let mut ret = alloc::vec![0; compress_bound(inp.len())];
+            let mut outp: BufOutput = ret.as_mut_slice().into();
+            self.compress_to_output(&mut inp, &mut outp, level, opts)?;
+            let len = outp.pos;
+            ret.truncate(len);
+            #[cfg(feature = "paranoid")]
+            verify_compressed(&mut inp, &ret, opts);
+            Ok(ret)
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    /// Compress the input into a [Vec](alloc::vec::Vec), with additional options,
+    /// periodically reporting progress
+    ///
+    /// `progress` is called roughly every [PROGRESS_INTERVAL] bytes of input.
+    /// If it returns [ControlFlow::Break], compression stops with [CompressError::Cancelled].
+    ///
+    /// Returns the result on success, or an error otherwise
+    pub fn compress_to_vec_with_progress(
+        &mut self,
+        mut inp: &[u8],
+        level: CompressionLevel,
+        opts: &CompressOptions,
+        progress: impl FnMut(Progress) -> ControlFlow<()>,
+    ) -> Result<alloc::vec::Vec<u8>, CompressError> {
+        #[cfg(feature = "unsafe-perf")]
+        {
+            let mut ret = alloc::vec::Vec::with_capacity(compress_bound(inp.len()));
+            let mut outp: UninitBufOutput = ret.spare_capacity_mut().into();
+            self.compress_to_output_with_progress(&mut inp, &mut outp, level, opts, progress)?;
+            let len = outp.into_init().len();
+            // SAFETY: the compressor initialized exactly this many bytes
+            unsafe { ret.set_len(len) };
+            #[cfg(feature = "paranoid")]
+            verify_compressed(&mut inp, &ret, opts);
+            Ok(ret)
+        }
+        #[cfg(not(feature = "unsafe-perf"))]
+        {
+            let mut ret = alloc::vec![0; compress_bound(inp.len())];
+            let mut outp: BufOutput = ret.as_mut_slice().into();
+            self.compress_to_output_with_progress(&mut inp, &mut outp, level, opts, progress)?;
+            let len = outp.pos;
+            ret.truncate(len);
+            #[cfg(feature = "paranoid")]
+            verify_compressed(&mut inp, &ret, opts);
+            Ok(ret)
+        }
+    }
+}
+
+#[cfg(test)]
+// (some loops over levels only have one without level 2)
+#[cfg_attr(not(feature = "level2"), allow(clippy::single_element_loop))]
+mod tests {
+    use super::*;
+    use crate::decompress::DecompressError;
+    use crate::level::InvalidLevelError;
+
+    #[test]
+    fn test_lv1_encoding_lit() {
+        {
+            let mut out = [0u8; 3];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_lits(&[1, 2]).unwrap();
+            assert_eq!(outbuf.0.buf, [0x01, 1, 2]);
+        }
+
+        {
+            let mut out = [0u8; 2];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_lits(&[1, 2]).expect_err("");
+            assert_eq!(outbuf.0.buf, [0x01, 1]);
+        }
+
+        {
+            let mut out = [0u8; 0];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_lits(&[0]).expect_err("");
+        }
+    }
+
+    #[test]
+    fn test_lv1_encoding_short() {
+        {
+            let mut out = [0u8; 2];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 5).unwrap();
+            assert_eq!(outbuf.0.buf, [0x60, 0x01]);
+        }
+
+        {
+            let mut out = [0u8; 1];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 5).expect_err("");
+            assert_eq!(outbuf.0.buf, [0x60]);
+        }
+    }
+
+    #[test]
+    fn test_lv1_encoding_long() {
+        {
+            let mut out = [0u8; 3];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 9).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0x00, 0x01]);
+        }
+
+        {
+            let mut out = [0u8; 3];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 264).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0xff, 0x01]);
+        }
+
+        {
+            let mut out = [0u8; 1];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 9).expect_err("");
+            assert_eq!(outbuf.0.buf, [0xe0]);
+        }
+    }
+
+    #[test]
+    fn test_lv1_encoding_verylong() {
+        {
+            // exactly overflows len 3 into next
+            let mut out = [0u8; 5];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 265).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0xfd, 0x01, 0x20, 0x01]);
+        }
+
+        {
+            // exactly overflows len 264 (max) into next
+            let mut out = [0u8; 6];
+            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 526).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0xfd, 0x01, 0xe0, 0xff, 0x01]);
+        }
+
+        {
+            // overflows twice
+            let mut out = [0u8; 8];
             let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
+            outbuf.put_backref(1, 527).unwrap();
+            assert_eq!(
+                outbuf.0.buf,
+                [0xe0, 0xfd, 0x01, 0xe0, 0xfd, 0x01, 0x20, 0x01]
+            );
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_lv2_encoding_lit() {
+        {
+            let mut out = [0u8; 3];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_lits(&[1, 2]).unwrap();
+            assert_eq!(outbuf.0.buf, [0x01, 1, 2]);
+        }
+
+        {
+            let mut out = [0u8; 2];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
             outbuf.put_lits(&[1, 2]).expect_err("");
             assert_eq!(outbuf.0.buf, [0x01, 1]);
         }
 
         {
-            let mut out = [0u8; 0];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_lits(&[0]).expect_err("");
+            let mut out = [0u8; 0];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_lits(&[0]).expect_err("");
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_lv2_encoding_short() {
+        {
+            let mut out = [0u8; 2];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(1, 5).unwrap();
+            assert_eq!(outbuf.0.buf, [0x60, 0x01]);
+        }
+
+        {
+            let mut out = [0u8; 1];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(1, 5).expect_err("");
+            assert_eq!(outbuf.0.buf, [0x60]);
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_lv2_encoding_longlen() {
+        {
+            let mut out = [0u8; 3];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(1, 9).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0x00, 0x01]);
+        }
+
+        {
+            let mut out = [0u8; 4];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(2, 9 + 0xff + 1).unwrap();
+            assert_eq!(outbuf.0.buf, [0xe0, 0xff, 0x01, 0x02]);
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_lv2_encoding_longdisp() {
+        {
+            let mut out = [0u8; 4];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(8191, 3).unwrap();
+            assert_eq!(outbuf.0.buf, [0x3f, 0xff, 0x00, 0x00]);
+        }
+
+        {
+            let mut out = [0u8; 4];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(8192, 3).unwrap();
+            assert_eq!(outbuf.0.buf, [0x3f, 0xff, 0x00, 0x01]);
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_lv2_encoding_longboth() {
+        {
+            let mut out = [0u8; 6];
+            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
+            outbuf.put_backref(8192, 9 + 0xff + 1).unwrap();
+            assert_eq!(outbuf.0.buf, [0xff, 0xff, 0x01, 0xff, 0x00, 0x01]);
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_backref_cost() {
+        for len in 3..2000 {
+            for disp in [0, 8190] {
+                let mut l1 = L1Output(CountOutput(0));
+                l1.put_backref(disp, len).unwrap();
+                assert_eq!(backref_cost::<L1Output<CountOutput>>(disp, len), l1.0 .0);
+            }
+            for disp in [0, 8190, 8191, 8191 + 65535] {
+                let mut l2 = L2Output(CountOutput(0));
+                l2.put_backref(disp, len).unwrap();
+                assert_eq!(backref_cost::<L2Output<CountOutput>>(disp, len), l2.0 .0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ref_hashes() {
+        assert_eq!(fastlz_hash(13, 1), 5062);
+        assert_eq!(fastlz_hash(13, 2), 1933);
+        assert_eq!(fastlz_hash(13, 3), 6996);
+        assert_eq!(fastlz_hash(13, 4), 3867);
+        assert_eq!(fastlz_hash(13, 0xaa), 538);
+        assert_eq!(fastlz_hash(13, 0xbb), 4688);
+        assert_eq!(fastlz_hash(13, 0xff), 4904);
+    }
+
+    #[test]
+    fn test_short_and_uncompressible() {
+        {
+            let mut state = CompressState::new();
+            let mut out = [0u8; 3];
+            let len = state
+                .compress_to_buf(&[1, 2], &mut out, CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x01, 1, 2]);
+        }
+
+        {
+            let mut state = CompressState::new();
+            let mut out = [0u8; 6];
+            let len = state
+                .compress_to_buf(&[1, 2, 3, 4, 5], &mut out, CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x04, 1, 2, 3, 4, 5]);
         }
     }
 
     #[test]
-    fn test_lv1_encoding_short() {
+    fn test_simple_backref() {
         {
-            let mut out = [0u8; 2];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 5).unwrap();
-            assert_eq!(outbuf.0.buf, [0x60, 0x01]);
+            let mut state = CompressState::new();
+            let mut out = [0u8; 4];
+            let len = state
+                .compress_to_buf(&[1, 1, 1, 1, 1], &mut out, CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x00, 1, 0x40, 0x00]);
         }
-
         {
-            let mut out = [0u8; 1];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 5).expect_err("");
-            assert_eq!(outbuf.0.buf, [0x60]);
+            // test trailing nonmatch
+            let mut state = CompressState::new();
+            let mut out = [0u8; 6];
+            let len = state
+                .compress_to_buf(&[1, 1, 1, 1, 1, 2], &mut out, CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x00, 1, 0x40, 0x00, 0x00, 2]);
+        }
+        {
+            // test longer match, ending at end
+            let mut state = CompressState::new();
+            let mut out = [0u8; 6];
+            let len = state
+                .compress_to_buf(
+                    &[1, 2, 3, 1, 2, 3, 1, 2, 3],
+                    &mut out,
+                    CompressionLevel::Level1,
+                )
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02]);
+        }
+        {
+            // test longer match, not ending at end
+            let mut state = CompressState::new();
+            let mut out = [0u8; 8];
+            let len = state
+                .compress_to_buf(
+                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 4],
+                    &mut out,
+                    CompressionLevel::Level1,
+                )
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]);
         }
     }
 
     #[test]
-    fn test_lv1_encoding_long() {
+    fn test_rehash_at_boundary() {
         {
-            let mut out = [0u8; 3];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 9).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0x00, 0x01]);
+            let mut state = CompressState::new();
+            let mut out = [0u8; 8];
+            let len = state
+                .compress_to_buf(
+                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 2, 3, 2, 3],
+                    &mut out,
+                    CompressionLevel::Level1,
+                )
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x40, 0x01]);
+        }
+        {
+            let mut state = CompressState::new();
+            let mut out = [0u8; 8];
+            let len = state
+                .compress_to_buf(
+                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 3, 3, 3, 3],
+                    &mut out,
+                    CompressionLevel::Level1,
+                )
+                .unwrap();
+            assert_eq!(len, out.len());
+            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x40, 0x00]);
+        }
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_far_match_at_end() {
+        let mut inp = [0u8; 8200];
+        inp[..5].copy_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+        inp[8195..].copy_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+
+        let mut state = CompressState::new();
+        let mut out = [0u8; 64];
+        let len = state
+            .compress_to_buf(&inp, &mut out, CompressionLevel::Level2)
+            .unwrap();
+        // shortened match, followed by a literal
+        assert_eq!(out[len - 6..len], [0x5f, 0xff, 0x00, 0x03, 0x00, 0xee]);
+
+        let opts = CompressOptions {
+            allow_far_match_at_end: true,
+            ..Default::default()
+        };
+        let len = state
+            .compress_to_buf_with_options(&inp, &mut out, CompressionLevel::Level2, &opts)
+            .unwrap();
+        assert_eq!(out[len - 4..len], [0x7f, 0xff, 0x00, 0x03]);
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_level_conversions() {
+        assert_eq!(CompressionLevel::try_from(1), Ok(CompressionLevel::Level1));
+        assert_eq!(CompressionLevel::try_from(2), Ok(CompressionLevel::Level2));
+        assert_eq!(CompressionLevel::try_from(3), Err(InvalidLevelError));
+
+        assert_eq!(CompressionLevel::Level2.into_header_bits(), Some(0x20));
+        assert_eq!(CompressionLevel::Best.into_header_bits(), None);
+        assert_eq!(
+            CompressionLevel::from_header_bits(0x3f),
+            Ok(CompressionLevel::Level2)
+        );
+
+        let mut state = CompressState::new();
+        let mut out = [0u8; 8];
+        for level in [CompressionLevel::Level1, CompressionLevel::Level2] {
+            state.compress_to_buf(&[1, 2, 3], &mut out, level).unwrap();
+            assert_eq!(CompressionLevel::of_compressed(&out), Ok(level));
+        }
+        assert_eq!(
+            CompressionLevel::of_compressed(&[]),
+            Err(DecompressError::InputTruncated { input_offset: 0 })
+        );
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_best_level() {
+        let mut state = CompressState::new();
+        let mut out = [0u8; 16];
+
+        // level 1 and 2 are identical in size, so level 1 is chosen
+        let len = state
+            .compress_to_buf(&[1, 1, 1, 1, 1], &mut out, CompressionLevel::Best)
+            .unwrap();
+        assert_eq!(out[..len], [0x00, 1, 0x40, 0x00]);
+
+        // a long match is more compact with level 2
+        let mut inp = [0u8; 600];
+        inp[0] = 1;
+        let mut out = [0u8; 16];
+        let len_l1 = state
+            .compress_to_buf(&inp, &mut out, CompressionLevel::Level1)
+            .unwrap();
+        let len = state
+            .compress_to_buf(&inp, &mut out, CompressionLevel::Best)
+            .unwrap();
+        assert!(len < len_l1);
+        assert_eq!(out[0] >> 5, 1);
+    }
+
+    #[cfg(not(feature = "level2"))]
+    #[test]
+    fn test_level2_disabled() {
+        assert_eq!(CompressionLevel::try_from(2), Err(InvalidLevelError));
+        assert_eq!(
+            CompressionLevel::from_header_bits(0x3f),
+            Err(InvalidLevelError)
+        );
+        assert_eq!(
+            CompressionLevel::of_compressed(&[0x22]),
+            Err(DecompressError::InvalidCompressionLevel)
+        );
+
+        // everything else falls back to level 1
+        let mut inp = [0u8; 70000];
+        inp[0] = 1;
+        let mut state = CompressState::new();
+        let mut out = [0u8; 1024];
+        for level in [CompressionLevel::Default, CompressionLevel::Best] {
+            let len = state.compress_to_buf(&inp, &mut out, level).unwrap();
+            assert_eq!(
+                CompressionLevel::of_compressed(&out[..len]),
+                Ok(CompressionLevel::Level1)
+            );
         }
 
+        #[cfg(feature = "decompress")]
         {
-            let mut out = [0u8; 3];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 264).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0xff, 0x01]);
+            let mut out = [0u8; 8];
+            assert_eq!(
+                crate::decompress_to_buf(&[0x22, 1, 2, 3], &mut out),
+                Err(DecompressError::InvalidCompressionLevel)
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_state_traits() {
+        extern crate std;
+        use std::format;
+
+        let mut state = CompressState::default();
+        assert_eq!(
+            format!("{:?}", state),
+            "CompressState { htab_size: 8192, htab_used: 0 }"
+        );
+
+        let mut out = [0u8; 8];
+        state
+            .compress_to_buf(
+                &[1, 2, 3, 1, 2, 3, 1, 2, 3],
+                &mut out,
+                CompressionLevel::Level1,
+            )
+            .unwrap();
+        let snapshot = state.clone();
+        assert_eq!(format!("{:?}", state), format!("{:?}", snapshot));
+        assert_eq!(snapshot.htab, state.htab);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_state_reuse() {
+        let d = [1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+        let other = [8, 7, 6, 5, 4, 3, 2, 1, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+        for c_compatible in [false, true] {
+            let opts = CompressOptions {
+                c_compatible,
+                ..Default::default()
+            };
+            for level in [
+                CompressionLevel::Level1,
+                #[cfg(feature = "level2")]
+                CompressionLevel::Level2,
+            ] {
+                let fresh = CompressState::new()
+                    .compress_to_vec_with_options(&d, level, &opts)
+                    .unwrap();
+
+                // stale entries must not be mistaken for positions in the new input
+                let mut state = CompressState::new();
+                state
+                    .compress_to_vec_with_options(&other, level, &opts)
+                    .unwrap();
+                let reused = state
+                    .compress_to_vec_with_options(&d, level, &opts)
+                    .unwrap();
+                assert_eq!(reused, fresh);
+
+                // the table is cleared once the offset would overflow
+                state.htab_next_base = u32::MAX - 4;
+                let wrapped = state
+                    .compress_to_vec_with_options(&d, level, &opts)
+                    .unwrap();
+                assert_eq!(wrapped, fresh);
+                assert_eq!(state.htab_base, 0);
+            }
         }
+    }
 
+    #[test]
+    fn test_htab_wrapping() {
+        assert_eq!(mem::size_of::<CompressState>(), 4 * DEFAULT_HTAB_SZ + 8);
+
+        let mut state = CompressState::new();
+        state.htab_base = u32::MAX - 30;
+        state.htab_set(0, 10);
+        assert_eq!(state.htab_replace(0, 20), 10);
+        // stored before this call
+        state.htab[1] = u32::MAX - 31;
+        assert_eq!(state.htab_replace(1, 20), 0);
+
+        #[cfg(target_pointer_width = "64")]
         {
-            let mut out = [0u8; 1];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 9).expect_err("");
-            assert_eq!(outbuf.0.buf, [0xe0]);
+            state.htab_base = 0;
+            state.htab_set(2, (1 << 32) + 5);
+            assert_eq!(state.htab_replace(2, (1 << 32) + 9), (1 << 32) + 5);
+            state.htab_set(3, 100);
+            assert_eq!(state.htab_replace(3, (1 << 32) + 50), 100);
+            // 4 GiB or more back aliases with a later position, but never the current one
+            state.htab_set(5, 10);
+            assert_eq!(state.htab_replace(5, (1 << 32) + 20), (1 << 32) + 10);
+            state.htab_set(4, 10);
+            assert_eq!(state.htab_replace(4, (1 << 32) + 10), 0);
         }
     }
 
     #[test]
-    fn test_lv1_encoding_verylong() {
+    fn test_state_static() {
+        static STATE: CompressState = CompressState::new();
+
+        let mut state = STATE.clone();
+        let mut out = [0u8; 4];
+        let len = state
+            .compress_to_buf(&[1, 1, 1, 1, 1], &mut out, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(out[..len], [0x00, 1, 0x40, 0x00]);
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_htab_sizes() {
+        extern crate std;
+        use crate::decompress::decompress_to_vec;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let tiny = CompressState::<2>::new_sized()
+                .compress_to_vec(&src, level)
+                .unwrap();
+            let small = CompressState::<1024>::new_sized()
+                .compress_to_vec(&src, level)
+                .unwrap();
+            let default = CompressState::new().compress_to_vec(&src, level).unwrap();
+            let large = CompressState::<65536>::new_sized_boxed()
+                .compress_to_vec(&src, level)
+                .unwrap();
+            assert!(tiny.len() > small.len());
+            assert!(small.len() > default.len());
+            assert!(default.len() >= large.len());
+            for comp in [tiny, small, default, large] {
+                assert_eq!(decompress_to_vec(&comp, None).unwrap(), src);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_two_entry_buckets() {
+        extern crate std;
+        use crate::decompress::decompress_to_vec;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        let opts = CompressOptions {
+            two_entry_buckets: true,
+            ..Default::default()
+        };
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let one = CompressState::new().compress_to_vec(&src, level).unwrap();
+            let two = CompressState::new()
+                .compress_to_vec_with_options(&src, level, &opts)
+                .unwrap();
+            assert!(two.len() < one.len());
+            assert_eq!(decompress_to_vec(&two, None).unwrap(), src);
+
+            // a single bucket
+            let tiny = CompressState::<2>::new_sized()
+                .compress_to_vec_with_options(&src, level, &opts)
+                .unwrap();
+            assert_eq!(decompress_to_vec(&tiny, None).unwrap(), src);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_lazy_matching() {
+        extern crate std;
+        use crate::decompress::decompress_to_vec;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        // text-like input, where most matches are short and a greedy match is usually followed by another
+        const WORDS: &[&[u8]] = &[
+            b"the ", b"quick ", b"brown ", b"fox ", b"jumps ", b"over ", b"lazy ", b"dog", b". ",
+        ];
+        let mut text = std::vec::Vec::new();
+        for x in random_bytes().take(20_000) {
+            text.extend_from_slice(WORDS[x as usize % WORDS.len()]);
+        }
+        let opts = CompressOptions {
+            lazy_matching: true,
+            ..Default::default()
+        };
+
+        for inp in [&src, &text] {
+            for level in [
+                CompressionLevel::Level1,
+                #[cfg(feature = "level2")]
+                CompressionLevel::Level2,
+            ] {
+                let greedy = CompressState::new().compress_to_vec(inp, level).unwrap();
+                let lazy = CompressState::new()
+                    .compress_to_vec_with_options(inp, level, &opts)
+                    .unwrap();
+                assert!(lazy.len() < greedy.len());
+                assert_eq!(&decompress_to_vec(&lazy, None).unwrap(), inp);
+            }
+        }
+
+        // "abcd" could be matched, but "bcdefghijklmno" is much longer
+        let inp = b"abcd0123bcdefghijklmno4567abcdefghijklmno";
+        let mut comp = [0u8; 32];
+        let len = CompressState::new()
+            .compress_to_buf_with_options(inp, &mut comp, CompressionLevel::Level1, &opts)
+            .unwrap();
+        assert_eq!(
+            comp[..len],
+            [
+                7, b'a', b'b', b'c', b'd', b'0', b'1', b'2', b'3', 0x20, 6, 15, b'e', b'f', b'g',
+                b'h', b'i', b'j', b'k', b'l', b'm', b'n', b'o', b'4', b'5', b'6', b'7', b'a', 0xe0,
+                5, 18
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_hash_chains() {
+        extern crate std;
+        use crate::decompress::decompress_to_vec;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let mut prev = CompressState::new().compress_to_vec(&src, level).unwrap();
+            for max_chain in [4, 16, 64] {
+                let opts = CompressOptions {
+                    max_chain,
+                    ..Default::default()
+                };
+                let comp = CompressState::new()
+                    .compress_to_vec_with_options(&src, level, &opts)
+                    .unwrap();
+                assert!(comp.len() < prev.len());
+                assert_eq!(decompress_to_vec(&comp, None).unwrap(), src);
+                prev = comp;
+            }
+        }
+
+        // "abcde" is further away than "abcxy", but longer
+        let inp = b"abcde0abcxy1abcde";
+        let opts = CompressOptions {
+            max_chain: 2,
+            ..Default::default()
+        };
+        let mut comp = [0u8; 32];
+        let len = CompressState::new()
+            .compress_to_buf_with_options(inp, &mut comp, CompressionLevel::Level1, &opts)
+            .unwrap();
+        assert_eq!(
+            comp[..len],
+            [5, b'a', b'b', b'c', b'd', b'e', b'0', 0x20, 5, 2, b'x', b'y', b'1', 0x60, 11]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_optimal_parse() {
+        extern crate std;
+        use crate::decompress::decompress_to_vec;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        // long runs crossing blocks, then text beyond level 1's reach of its previous copy
+        let mut long = std::vec![b'a'; 3 * OPTIMAL_BLOCK + 1000];
+        long.extend((0..OPTIMAL_BLOCK).flat_map(|x| [x as u8, 0, b'b', b'b', b'b']));
+        long.extend_from_slice(&src[..10000]);
+        long.extend_from_slice(&src[..10000]);
+
+        let greedy_opts = CompressOptions {
+            max_chain: OPTIMAL_CHAIN,
+            lazy_matching: true,
+            ..Default::default()
+        };
+        let opts = CompressOptions {
+            optimal_parse: true,
+            ..Default::default()
+        };
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let greedy = CompressState::new()
+                .compress_to_vec_with_options(&src, level, &greedy_opts)
+                .unwrap();
+            let optimal = CompressState::new()
+                .compress_to_vec_with_options(&src, level, &opts)
+                .unwrap();
+            assert!(optimal.len() < greedy.len());
+            assert_eq!(decompress_to_vec(&optimal, None).unwrap(), src);
+
+            for inp in [&long[..], &long[..5], &src[..40]] {
+                let comp = CompressState::new()
+                    .compress_to_vec_with_options(inp, level, &opts)
+                    .unwrap();
+                assert!(comp.len() <= compress_bound(inp.len()));
+                assert_eq!(decompress_to_vec(&comp, None).unwrap(), inp);
+            }
+        }
+
+        // a literal and a long match beats two shorter matches
+        let inp = b"abcdef0bcdefghij1abcdefghij";
+        let mut comp = [0u8; 32];
+        let len = CompressState::new()
+            .compress_to_buf_with_options(inp, &mut comp, CompressionLevel::Level1, &opts)
+            .unwrap();
+        assert_eq!(
+            comp[..len],
+            [
+                6, b'a', b'b', b'c', b'd', b'e', b'f', b'0', 0x60, 5, 5, b'g', b'h', b'i', b'j',
+                b'1', b'a', 0xe0, 0, 10
+            ]
+        );
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_find_run() {
+        fn run_at(inp: &[u8], pos: usize) -> Option<(usize, usize)> {
+            let mut inp = inp;
+            let head = inp.peek4(pos).unwrap();
+            find_run(&mut inp, pos, head)
+        }
+
+        let mut inp = [0u8; 64];
+        inp[..4].copy_from_slice(b"wxyz");
+        inp[4..24].fill(b'a');
+        for (i, x) in inp[24..44].iter_mut().enumerate() {
+            *x = b"bc"[i % 2];
+        }
+        for (i, x) in inp[44..64].iter_mut().enumerate() {
+            *x = b"defg"[i % 4];
+        }
+        assert_eq!(run_at(&inp, 3), None);
+        assert_eq!(run_at(&inp, 4), None);
+        assert_eq!(run_at(&inp, 5), Some((0, 19)));
+        assert_eq!(run_at(&inp, 24), None);
+        assert_eq!(run_at(&inp, 26), Some((1, 18)));
+        assert_eq!(run_at(&inp, 47), None);
+        assert_eq!(run_at(&inp, 48), Some((3, 16)));
+        // too short
+        assert_eq!(run_at(&inp, 49), None);
+
+        let mut long = [0u8; 200];
+        long[..64].copy_from_slice(&inp);
+        long[64..].fill(b'h');
+        assert_eq!(run_at(&long, 48), Some((3, 16)));
+        assert_eq!(run_at(&long, 65), Some((0, 135)));
+
+        for (i, x) in long[64..].iter_mut().enumerate() {
+            *x = b"ijkl"[i % 4];
+        }
+        assert_eq!(run_at(&long, 68), Some((3, 132)));
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let mut comp = [0u8; 64];
+            let len = CompressState::new()
+                .compress_to_buf(&long, &mut comp, level)
+                .unwrap();
+            let mut out = [0u8; 200];
+            assert_eq!(
+                crate::decompress::decompress_to_buf(&comp[..len], &mut out),
+                Ok(200)
+            );
+            assert_eq!(out, long);
+        }
+    }
+
+    #[test]
+    fn test_runtime_htab_size() {
+        let inp = b"abcdefgh abcdefgh abcdefgh ijklmnop abcdefgh ijklmnop";
+        let mut expected = [0u8; 64];
+        let expected_len = CompressState::<16>::new_sized()
+            .compress_to_buf(inp, &mut expected, CompressionLevel::Level1)
+            .unwrap();
+
+        // previous contents of the buffer don't matter
+        let mut buf = [0xdeadbeef; 16];
+        let mut state = GenericCompressState::with_table(&mut buf[..]);
+        let mut out = [0u8; 64];
+        let len = state
+            .compress_to_buf(inp, &mut out, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(out[..len], expected[..expected_len]);
+
+        #[cfg(feature = "alloc")]
         {
-            // exactly overflows len 3 into next
-            let mut out = [0u8; 5];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 265).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0xfd, 0x01, 0x20, 0x01]);
+            let mut state = GenericCompressState::with_hash_bits(4);
+            let len = state
+                .compress_to_buf(inp, &mut out, CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(out[..len], expected[..expected_len]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "hash table size must be a power of two")]
+    fn test_runtime_htab_size_invalid() {
+        let mut buf = [0; 24];
+        GenericCompressState::with_table(&mut buf[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_new_boxed_stack_usage() {
+        extern crate std;
+
+        // much smaller than the state itself
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(|| {
+                let mut state = CompressState::new_boxed();
+                assert!(state.htab.iter().all(|&x| x == 0));
+
+                let mut out = [0u8; 4];
+                let len = state
+                    .compress_to_buf(&[1, 1, 1, 1, 1], &mut out, CompressionLevel::Level1)
+                    .unwrap();
+                assert_eq!(out[..len], [0x00, 1, 0x40, 0x00]);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_custom_sink() {
+        /// Only accepts one byte at a time, like a UART
+        struct Uart {
+            sent: [u8; 16],
+            n: usize,
+        }
+        impl ByteSink for Uart {
+            type Error = CompressError;
+
+            fn putc(&mut self, c: u8) -> Result<(), CompressError> {
+                if self.n == self.sent.len() {
+                    return Err(CompressError::OutputTooSmall {
+                        written: self.n,
+                        input_processed: 0,
+                        remaining: 0,
+                    });
+                }
+                self.sent[self.n] = c;
+                self.n += 1;
+                Ok(())
+            }
+            fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+                buf.iter().try_for_each(|&c| self.putc(c))
+            }
         }
 
-        {
-            // exactly overflows len 264 (max) into next
-            let mut out = [0u8; 6];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 526).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0xfd, 0x01, 0xe0, 0xff, 0x01]);
+        let mut state = CompressState::new();
+        let mut uart = Uart {
+            sent: [0; 16],
+            n: 0,
+        };
+        state
+            .compress_to_sink(&[1, 1, 1, 1, 1, 2], &mut uart, CompressionLevel::Level2)
+            .unwrap();
+        assert_eq!(uart.sent[..uart.n], [0x20, 1, 0x40, 0x00, 0x00, 2]);
+    }
+
+    #[test]
+    fn test_custom_sink_error() {
+        #[derive(Debug, PartialEq, Eq)]
+        enum SpiError {
+            Nack(usize),
+            Compress(CompressError),
+        }
+        impl From<CompressError> for SpiError {
+            fn from(e: CompressError) -> Self {
+                SpiError::Compress(e)
+            }
         }
 
-        {
-            // overflows twice
-            let mut out = [0u8; 8];
-            let mut outbuf: L1Output<BufOutput> = L1Output((&mut out[..]).into());
-            outbuf.put_backref(1, 527).unwrap();
-            assert_eq!(
-                outbuf.0.buf,
-                [0xe0, 0xfd, 0x01, 0xe0, 0xfd, 0x01, 0x20, 0x01]
-            );
+        /// Fails after a certain number of bytes
+        struct Flash(usize);
+        impl ByteSink for Flash {
+            type Error = SpiError;
+
+            fn put_buf(&mut self, buf: &[u8]) -> Result<(), SpiError> {
+                if self.0 + buf.len() > 4 {
+                    return Err(SpiError::Nack(self.0));
+                }
+                self.0 += buf.len();
+                Ok(())
+            }
         }
+
+        let mut state = CompressState::new();
+        assert_eq!(
+            state.compress_to_sink(&[1, 2, 3, 4, 5], &mut Flash(0), CompressionLevel::Level1),
+            Err(SpiError::Nack(1))
+        );
     }
 
     #[test]
-    fn test_lv2_encoding_lit() {
-        {
-            let mut out = [0u8; 3];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_lits(&[1, 2]).unwrap();
-            assert_eq!(outbuf.0.buf, [0x01, 1, 2]);
-        }
+    fn test_vectored() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut expected = [0u8; 32];
+        let expected_len = state
+            .compress_to_buf(&inp, &mut expected, CompressionLevel::Level1)
+            .unwrap();
 
-        {
-            let mut out = [0u8; 2];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_lits(&[1, 2]).expect_err("");
-            assert_eq!(outbuf.0.buf, [0x01, 1]);
+        let mut out = [0u8; 32];
+        for i in 0..=inp.len() {
+            for j in i..=inp.len() {
+                let len = state
+                    .compress_vectored(
+                        &[&inp[..i], &inp[i..j], &[], &inp[j..]],
+                        &mut out,
+                        CompressionLevel::Level1,
+                    )
+                    .unwrap();
+                assert_eq!(out[..len], expected[..expected_len]);
+            }
         }
+    }
 
-        {
-            let mut out = [0u8; 0];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_lits(&[0]).expect_err("");
-        }
+    #[cfg(all(feature = "unsafe-perf", feature = "level2"))]
+    #[test]
+    fn test_uninit_buf() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut expected = [0u8; 8];
+        state
+            .compress_to_buf(&inp, &mut expected, CompressionLevel::Level2)
+            .unwrap();
+
+        let mut out = [MaybeUninit::uninit(); 16];
+        let comp = state
+            .compress_to_uninit_buf(&inp, &mut out, CompressionLevel::Level2)
+            .unwrap();
+        assert_eq!(comp, expected);
+
+        let mut out = [MaybeUninit::uninit(); 4];
+        assert_eq!(
+            state.compress_to_uninit_buf(&inp, &mut out, CompressionLevel::Level2),
+            Err(CompressError::OutputTooSmall {
+                written: 4,
+                input_processed: 3,
+                remaining: compress_bound(7)
+            })
+        );
     }
 
+    #[cfg(feature = "level2")]
     #[test]
-    fn test_lv2_encoding_short() {
-        {
-            let mut out = [0u8; 2];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(1, 5).unwrap();
-            assert_eq!(outbuf.0.buf, [0x60, 0x01]);
-        }
+    fn test_buf_ex() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut out = [0u8; 16];
 
-        {
-            let mut out = [0u8; 1];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(1, 5).expect_err("");
-            assert_eq!(outbuf.0.buf, [0x60]);
-        }
+        let outcome = state
+            .compress_to_buf_ex(&inp, &mut out, CompressionLevel::Default)
+            .unwrap();
+        assert_eq!(outcome.written, 8);
+        assert_eq!(outcome.level_used, CompressionLevel::Level1);
+        assert_eq!(outcome.ratio, 1.25);
+        assert_eq!(out[..8], [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]);
+
+        let outcome = state
+            .compress_to_buf_ex(&inp, &mut out, CompressionLevel::Level2)
+            .unwrap();
+        assert_eq!(outcome.level_used, CompressionLevel::Level2);
+
+        let outcome = state
+            .compress_to_buf_ex(&[], &mut out, CompressionLevel::Best)
+            .unwrap();
+        assert_eq!(outcome.written, 0);
+        assert_eq!(outcome.level_used, CompressionLevel::Level1);
+        assert_eq!(outcome.ratio, 1.0);
+        assert_eq!(outcome.stats, None);
     }
 
     #[test]
-    fn test_lv2_encoding_longlen() {
-        {
-            let mut out = [0u8; 3];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(1, 9).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0x00, 0x01]);
-        }
+    fn test_stats() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut out = [0u8; 16];
+
+        let opts = CompressOptions {
+            collect_stats: true,
+            ..Default::default()
+        };
+        let outcome = state
+            .compress_to_buf_ex_with_options(&inp, &mut out, CompressionLevel::Level1, &opts)
+            .unwrap();
+        assert_eq!(outcome.written, 8);
+        let stats = outcome.stats.unwrap();
+        assert_eq!(stats.literal_runs, 2);
+        assert_eq!(stats.literal_bytes, 4);
+        assert_eq!(stats.matches, 1);
+        assert_eq!(stats.match_bytes, 6);
+        let mut expected_len_hist = [0; STATS_BUCKETS];
+        expected_len_hist[2] = 1;
+        assert_eq!(stats.match_len_hist, expected_len_hist);
+        let mut expected_dist_hist = [0; STATS_BUCKETS];
+        expected_dist_hist[1] = 1;
+        assert_eq!(stats.match_dist_hist, expected_dist_hist);
+    }
 
-        {
-            let mut out = [0u8; 4];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(2, 9 + 0xff + 1).unwrap();
-            assert_eq!(outbuf.0.buf, [0xe0, 0xff, 0x01, 0x02]);
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_progress() {
+        // poorly-compressible data
+        let inp: std::vec::Vec<u8> = random_bytes().take(300_000).map(|x| x & 0x0f).collect();
+        let mut state = CompressState::new();
+        let opts = CompressOptions::default();
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let expected = state.compress_to_vec(&inp, level).unwrap();
+
+            let mut reports = std::vec::Vec::new();
+            let comp = state
+                .compress_to_vec_with_progress(&inp, level, &opts, |p| {
+                    assert_eq!(p.input_len, inp.len());
+                    reports.push(p.input_processed);
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            assert_eq!(comp, expected);
+            assert_eq!(reports.len(), 4);
+            assert!(reports.is_sorted());
+            assert!(reports[0] >= PROGRESS_INTERVAL);
+
+            let mut calls = 0;
+            assert_eq!(
+                state.compress_to_vec_with_progress(&inp, level, &opts, |_| {
+                    calls += 1;
+                    ControlFlow::Break(())
+                }),
+                Err(CompressError::Cancelled)
+            );
+            assert_eq!(calls, 1);
         }
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_lv2_encoding_longdisp() {
-        {
-            let mut out = [0u8; 4];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(8191, 3).unwrap();
-            assert_eq!(outbuf.0.buf, [0x3f, 0xff, 0x00, 0x00]);
+    fn test_progress_incompressible() {
+        // random data, which is all one literal run
+        let inp: std::vec::Vec<u8> = random_bytes().take(1_000_000).collect();
+        let mut state = CompressState::new();
+
+        for opts in [
+            CompressOptions::default(),
+            CompressOptions {
+                c_compatible: true,
+                ..Default::default()
+            },
+        ] {
+            for level in [
+                CompressionLevel::Level1,
+                #[cfg(feature = "level2")]
+                CompressionLevel::Level2,
+            ] {
+                let expected = state
+                    .compress_to_vec_with_options(&inp, level, &opts)
+                    .unwrap();
+
+                let mut reports = std::vec::Vec::new();
+                let comp = state
+                    .compress_to_vec_with_progress(&inp, level, &opts, |p| {
+                        reports.push(p.input_processed);
+                        ControlFlow::Continue(())
+                    })
+                    .unwrap();
+                assert_eq!(comp, expected);
+                assert!(reports.len() > 1);
+                assert!(reports[0] < inp.len() / 2);
+
+                // cancelling stops part way through
+                let mut processed = 0;
+                assert_eq!(
+                    state.compress_to_vec_with_progress(&inp, level, &opts, |p| {
+                        processed = p.input_processed;
+                        ControlFlow::Break(())
+                    }),
+                    Err(CompressError::Cancelled)
+                );
+                assert!(processed < inp.len() / 2);
+            }
         }
+    }
 
-        {
-            let mut out = [0u8; 4];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(8192, 3).unwrap();
-            assert_eq!(outbuf.0.buf, [0x3f, 0xff, 0x00, 0x01]);
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_compress_bound() {
+        let mut state = CompressState::new();
+        let random: std::vec::Vec<u8> = random_bytes().take(100_000).collect();
+
+        for c_compatible in [false, true] {
+            let opts = CompressOptions {
+                c_compatible,
+                ..Default::default()
+            };
+            for level in [
+                CompressionLevel::Level1,
+                #[cfg(feature = "level2")]
+                CompressionLevel::Level2,
+            ] {
+                for len in (0..100).chain([1000, random.len()]) {
+                    let inp = &random[..len];
+                    let comp = state
+                        .compress_to_vec_with_options(inp, level, &opts)
+                        .unwrap();
+                    assert!(comp.len() <= compress_bound(len));
+
+                    let mut expected = std::vec![0u8; compress_bound(len)];
+                    let expected_len = state
+                        .compress_to_buf_with_options(inp, &mut expected, level, &opts)
+                        .unwrap();
+                    assert_eq!(comp, expected[..expected_len]);
+                }
+            }
         }
     }
 
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
     #[test]
-    fn test_lv2_encoding_longboth() {
-        {
-            let mut out = [0u8; 6];
-            let mut outbuf: L2Output<BufOutput> = L2Output((&mut out[..]).into());
-            outbuf.put_backref(8192, 9 + 0xff + 1).unwrap();
-            assert_eq!(outbuf.0.buf, [0xff, 0xff, 0x01, 0xff, 0x00, 0x01]);
+    fn test_output_too_small_retry() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let inp = std::fs::read(d.join("src/compress.rs")).unwrap();
+        let mut state = CompressState::new();
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let expected = state.compress_to_vec(&inp, level).unwrap();
+
+            for short_len in [0, 1, 100, expected.len() / 2, expected.len() - 1] {
+                let mut out = std::vec![0u8; short_len];
+                let Err(CompressError::OutputTooSmall {
+                    written,
+                    input_processed,
+                    remaining,
+                }) = state.compress_to_buf(&inp, &mut out, level)
+                else {
+                    panic!("compression should have failed");
+                };
+                assert_eq!(written, short_len);
+                assert!(written + remaining >= expected.len());
+
+                // the input which was processed can be recovered from the truncated output
+                let mut decomp = std::vec![0u8; input_processed];
+                let _ = crate::decompress_to_buf(&out, &mut decomp);
+                assert_eq!(decomp, inp[..input_processed]);
+
+                // and a single retry is sufficient
+                let mut out = std::vec![0u8; written + remaining];
+                let len = state.compress_to_buf(&inp, &mut out, level).unwrap();
+                assert_eq!(out[..len], expected);
+            }
         }
     }
 
+    #[cfg(feature = "zeroize")]
     #[test]
-    fn test_ref_hashes() {
-        assert_eq!(fastlz_hash(1), 5062);
-        assert_eq!(fastlz_hash(2), 1933);
-        assert_eq!(fastlz_hash(3), 6996);
-        assert_eq!(fastlz_hash(4), 3867);
-        assert_eq!(fastlz_hash(0xaa), 538);
-        assert_eq!(fastlz_hash(0xbb), 4688);
-        assert_eq!(fastlz_hash(0xff), 4904);
+    fn test_zeroize() {
+        use zeroize::Zeroize;
+
+        let mut state = CompressState::new();
+        state
+            .compress_to_vec(b"secret secret secret", CompressionLevel::Level1)
+            .unwrap();
+        assert!(state.htab.iter().any(|&x| x != 0));
+        state.zeroize();
+        assert!(state.htab.iter().all(|&x| x == 0));
     }
 
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
     #[test]
-    fn test_short_and_uncompressible() {
-        {
-            let mut state = CompressState::new();
-            let mut out = [0u8; 3];
-            let len = state
-                .compress_to_buf(&[1, 2], &mut out, CompressionLevel::Level1)
+    fn test_acceleration() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        // text, then incompressible data, then the same text again
+        let mut inp = src.clone();
+        inp.extend(random_bytes().take(100_000));
+        inp.extend_from_slice(&src);
+
+        let mut state = CompressState::new();
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let every_byte = CompressOptions {
+                acceleration: 0,
+                ..Default::default()
+            };
+            let normal = state
+                .compress_to_vec_with_options(&inp, level, &every_byte)
                 .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x01, 1, 2]);
+            // the default skips ahead
+            assert_eq!(
+                state.compress_to_vec(&inp, level).unwrap(),
+                state
+                    .compress_to_vec_with_options(
+                        &inp,
+                        level,
+                        &CompressOptions {
+                            acceleration: 1,
+                            ..Default::default()
+                        }
+                    )
+                    .unwrap()
+            );
+            let mut stats = std::vec::Vec::new();
+            for acceleration in [1, 4, 64] {
+                let opts = CompressOptions {
+                    acceleration,
+                    collect_stats: true,
+                    ..Default::default()
+                };
+                let comp = state
+                    .compress_to_vec_with_options(&inp, level, &opts)
+                    .unwrap();
+                assert!(comp.len() >= normal.len());
+                assert!(comp.len() <= compress_bound(inp.len()));
+                let decomp = crate::decompress_to_vec(&comp, None).unwrap();
+                assert_eq!(decomp, inp);
+
+                let mut out = std::vec![0; comp.len()];
+                let outcome = state
+                    .compress_to_buf_ex_with_options(&inp, &mut out, level, &opts)
+                    .unwrap();
+                stats.push(outcome.stats.unwrap());
+            }
+            // fewer positions are tried, so fewer (longer) literal runs are found
+            // (though not necessarily monotonically, as where the skips land depends on the input)
+            assert!(stats[1..]
+                .iter()
+                .all(|s| s.literal_bytes >= stats[0].literal_bytes));
         }
+    }
 
-        {
-            let mut state = CompressState::new();
-            let mut out = [0u8; 6];
-            let len = state
-                .compress_to_buf(&[1, 2, 3, 4, 5], &mut out, CompressionLevel::Level1)
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_acceleration_max() {
+        // text, then enough incompressible data for the skips to become huge, then text again
+        let mut inp = std::vec::Vec::from(&b"the same text, and the same text"[..]);
+        inp.extend(random_bytes().take(10_000));
+        inp.extend_from_slice(b"the same text, and the same text");
+
+        let opts = CompressOptions {
+            acceleration: usize::MAX,
+            ..Default::default()
+        };
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let comp = CompressState::new()
+                .compress_to_vec_with_options(&inp, level, &opts)
                 .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x04, 1, 2, 3, 4, 5]);
+            assert_eq!(crate::decompress_to_vec(&comp, None).unwrap(), inp);
         }
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn test_simple_backref() {
-        {
-            let mut state = CompressState::new();
-            let mut out = [0u8; 4];
-            let len = state
-                .compress_to_buf(&[1, 1, 1, 1, 1], &mut out, CompressionLevel::Level1)
-                .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x00, 1, 0x40, 0x00]);
-        }
-        {
-            // test trailing nonmatch
-            let mut state = CompressState::new();
-            let mut out = [0u8; 6];
-            let len = state
-                .compress_to_buf(&[1, 1, 1, 1, 1, 2], &mut out, CompressionLevel::Level1)
-                .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x00, 1, 0x40, 0x00, 0x00, 2]);
+    fn test_incompressible() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        let random: std::vec::Vec<u8> = random_bytes().take(100_000).collect();
+        let opts = CompressOptions {
+            incompressible_fraction: Some(0.9),
+            ..Default::default()
+        };
+
+        let mut state = CompressState::new();
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            assert_eq!(
+                state.compress_to_vec_with_options(&random, level, &opts),
+                Err(CompressError::Incompressible)
+            );
+            // gives up long before running out of space
+            let mut out = std::vec![0; 10_000];
+            assert_eq!(
+                state.compress_to_buf_with_options(&random, &mut out, level, &opts),
+                Err(CompressError::Incompressible)
+            );
+            // small inputs are never checked
+            assert!(state
+                .compress_to_vec_with_options(&random[..1000], level, &opts)
+                .is_ok());
+            // sizes are still counted in full
+            assert_eq!(
+                state.compressed_len_with_options(&random, level, &opts),
+                state.compressed_len(&random, level)
+            );
+
+            assert_eq!(
+                state.compress_to_vec_with_options(&src, level, &opts),
+                state.compress_to_vec(&src, level)
+            );
         }
-        {
-            // test longer match, ending at end
-            let mut state = CompressState::new();
-            let mut out = [0u8; 6];
-            let len = state
-                .compress_to_buf(
-                    &[1, 2, 3, 1, 2, 3, 1, 2, 3],
+
+        // the other compression loops also check part way through literal runs
+        for opts in [
+            CompressOptions {
+                c_compatible: true,
+                ..opts.clone()
+            },
+            CompressOptions {
+                optimal_parse: true,
+                ..opts.clone()
+            },
+        ] {
+            let mut out = std::vec![0; 10_000];
+            assert_eq!(
+                state.compress_to_buf_with_options(
+                    &random,
                     &mut out,
                     CompressionLevel::Level1,
-                )
-                .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02]);
+                    &opts
+                ),
+                Err(CompressError::Incompressible)
+            );
         }
-        {
-            // test longer match, not ending at end
-            let mut state = CompressState::new();
-            let mut out = [0u8; 8];
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_compressed_len() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        assert_eq!(state.compressed_len(&inp, CompressionLevel::Level1), 8);
+        assert_eq!(state.compressed_len(&[], CompressionLevel::Level1), 0);
+
+        let opts = CompressOptions {
+            c_compatible: true,
+            ..Default::default()
+        };
+        for level in [
+            CompressionLevel::Level1,
+            CompressionLevel::Level2,
+            CompressionLevel::Best,
+        ] {
+            let mut out = [0u8; 16];
             let len = state
-                .compress_to_buf(
-                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 4],
-                    &mut out,
-                    CompressionLevel::Level1,
-                )
+                .compress_to_buf_with_options(&inp, &mut out, level, &opts)
                 .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]);
+            assert_eq!(state.compressed_len_with_options(&inp, level, &opts), len);
         }
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
-    fn test_rehash_at_boundary() {
-        {
-            let mut state = CompressState::new();
-            let mut out = [0u8; 8];
-            let len = state
-                .compress_to_buf(
-                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 2, 3, 2, 3],
+    fn test_compress_to_fit() {
+        let mut state = CompressState::new();
+
+        // level 2 fits
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut out = [0u8; 8];
+        let outcome = state.compress_to_fit(&inp, &mut out).unwrap();
+        assert_eq!(outcome.written, 8);
+        assert_eq!(outcome.level_used, CompressionLevel::Level2);
+        assert_eq!(crate::decompress_to_buf(&out, &mut [0; 10]), Ok(10));
+
+        // a match of length 264 needs an extra length byte at level 2
+        let inp = [0u8; 265];
+        assert_eq!(state.compressed_len(&inp, CompressionLevel::Level1), 5);
+        assert_eq!(state.compressed_len(&inp, CompressionLevel::Level2), 6);
+        let mut out = [0u8; 5];
+        let outcome = state.compress_to_fit(&inp, &mut out).unwrap();
+        assert_eq!(outcome.written, 5);
+        assert_eq!(outcome.level_used, CompressionLevel::Level1);
+        let mut decomp = [1u8; 265];
+        assert_eq!(crate::decompress_to_buf(&out, &mut decomp), Ok(265));
+        assert_eq!(decomp, inp);
+
+        // neither fits, so the error describes level 1
+        let mut out = [0u8; 4];
+        assert_eq!(
+            state.compress_to_fit(&inp, &mut out),
+            Err(CompressError::OutputTooSmall {
+                written: 4,
+                input_processed: 1,
+                remaining: compress_bound(264)
+            })
+        );
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_default_level_policy() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut out = [0u8; 16];
+
+        let opts = CompressOptions {
+            default_level_policy: Some(|_| CompressionLevel::Level2),
+            ..Default::default()
+        };
+        let outcome = state
+            .compress_to_buf_ex_with_options(&inp, &mut out, CompressionLevel::Default, &opts)
+            .unwrap();
+        assert_eq!(outcome.level_used, CompressionLevel::Level2);
+        assert_eq!(
+            CompressionLevel::of_compressed(&out),
+            Ok(CompressionLevel::Level2)
+        );
+
+        // explicit levels are unaffected
+        let outcome = state
+            .compress_to_buf_ex_with_options(&inp, &mut out, CompressionLevel::Level1, &opts)
+            .unwrap();
+        assert_eq!(outcome.level_used, CompressionLevel::Level1);
+
+        let opts = CompressOptions {
+            default_level_policy: Some(|len| {
+                if len < 8 {
+                    CompressionLevel::Level2
+                } else {
+                    CompressionLevel::Default
+                }
+            }),
+            ..Default::default()
+        };
+        for (len, expected) in [
+            (5, CompressionLevel::Level2),
+            (10, CompressionLevel::Level1),
+        ] {
+            let outcome = state
+                .compress_to_buf_ex_with_options(
+                    &inp[..len],
                     &mut out,
-                    CompressionLevel::Level1,
+                    CompressionLevel::Default,
+                    &opts,
                 )
                 .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x40, 0x01]);
+            assert_eq!(outcome.level_used, expected);
         }
-        {
-            let mut state = CompressState::new();
-            let mut out = [0u8; 8];
-            let len = state
-                .compress_to_buf(
-                    &[1, 2, 3, 1, 2, 3, 1, 2, 3, 3, 3, 3, 3],
-                    &mut out,
-                    CompressionLevel::Level1,
-                )
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_sample_default_level() {
+        extern crate alloc;
+        use alloc::vec::Vec;
+
+        let mut rng = random_bytes();
+        let mut random = |len| rng.by_ref().take(len).collect::<Vec<u8>>();
+        let local = random(4096).repeat(32);
+        let far = random(16384).repeat(8);
+
+        let mut state = CompressState::new();
+        let opts = CompressOptions {
+            sample_default_level: true,
+            ..Default::default()
+        };
+        for (inp, sampled) in [
+            (&local[..], CompressionLevel::Level1),
+            (&far[..], CompressionLevel::Level2),
+            (&far[..10000], CompressionLevel::Level1),
+        ] {
+            let mut out = alloc::vec![0; compress_bound(inp.len())];
+            let outcome = state
+                .compress_to_buf_ex_with_options(inp, &mut out, CompressionLevel::Default, &opts)
                 .unwrap();
-            assert_eq!(len, out.len());
-            assert_eq!(out, [0x02, 1, 2, 3, 0x80, 0x02, 0x40, 0x00]);
+            assert_eq!(outcome.level_used, sampled);
         }
+
+        // data only repeating locally compresses nearly as well with level 1
+        let l1 = state.compressed_len(&local, CompressionLevel::Level1);
+        let l2 = state.compressed_len(&local, CompressionLevel::Level2);
+        assert!(l1.abs_diff(l2) < local.len() / 100);
+        let l1 = state.compressed_len(&far, CompressionLevel::Level1);
+        let l2 = state.compressed_len(&far, CompressionLevel::Level2);
+        assert!(l2 * 2 < l1);
+    }
+
+    #[test]
+    fn test_core_error() {
+        // usable as an error without needing std
+        let e: &dyn core::error::Error = &CompressError::InputTooLarge;
+        assert!(e.source().is_none());
+        let e: &dyn core::error::Error = &DecompressError::InvalidCompressionLevel;
+        assert!(e.source().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use serde::de::{value, IntoDeserializer};
+        use serde::Deserialize;
+
+        let de: value::StrDeserializer<value::Error> = "Level2".into_deserializer();
+        assert_eq!(
+            CompressionLevel::deserialize(de),
+            Ok(CompressionLevel::Level2)
+        );
+        let de: value::StrDeserializer<value::Error> = "InputTooLarge".into_deserializer();
+        assert_eq!(
+            CompressError::deserialize(de),
+            Ok(CompressError::InputTooLarge)
+        );
+    }
+
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_l2_marker_when_truncated() {
+        let mut state = CompressState::new();
+        let mut out = [0u8; 2];
+        state
+            .compress_to_buf(&[1, 2, 3], &mut out, CompressionLevel::Level2)
+            .expect_err("");
+        assert_eq!(out, [0x22, 1]);
     }
 
     #[cfg(feature = "std")]
@@ -746,13 +3955,54 @@ mod tests {
             .compress_to_vec(&inp, CompressionLevel::Level1)
             .unwrap();
 
-        let mut reference = crate::wasmtester::FastLZWasm::new();
+        let mut reference = crate::TestReference::new();
         let check = reference.fastlz_decompress(&out);
 
         assert_eq!(inp, check);
     }
 
     #[cfg(feature = "std")]
+    #[cfg(feature = "level2")]
+    #[test]
+    fn test_c_compatible_against_ref() {
+        extern crate std;
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        // make sure far matches get exercised too
+        let mut far = std::vec::Vec::new();
+        for i in 0..64 {
+            far.extend_from_slice(&src[i * 64..i * 64 + 1000]);
+            far.extend((0..9000u32).map(|x| (x * 7 + i as u32) as u8));
+        }
+
+        let opts = CompressOptions {
+            c_compatible: true,
+            ..Default::default()
+        };
+        let mut comp_state = CompressState::new();
+        let mut reference = crate::TestReference::new();
+        for inp in [
+            &src[..],
+            &far[..],
+            &src[..5],
+            &src[..13],
+            &src[..14],
+            &src[..20],
+        ] {
+            for (level, level_num) in [(CompressionLevel::Level1, 1), (CompressionLevel::Level2, 2)]
+            {
+                let out = comp_state
+                    .compress_to_vec_with_options(inp, level, &opts)
+                    .unwrap();
+                let check = reference.fastlz_compress_level(level_num, inp);
+                assert_eq!(out, check);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "level2")]
     #[test]
     fn test_lv2_against_ref() {
         extern crate std;
@@ -766,7 +4016,7 @@ mod tests {
             .compress_to_vec(&inp, CompressionLevel::Level2)
             .unwrap();
 
-        let mut reference = crate::wasmtester::FastLZWasm::new();
+        let mut reference = crate::TestReference::new();
         let check = reference.fastlz_decompress(&out);
 
         assert_eq!(inp, check);
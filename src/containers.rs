@@ -0,0 +1,499 @@
+//! Support for using other container types as output
+
+#[cfg(feature = "compress")]
+use crate::compress::*;
+#[cfg(feature = "decompress")]
+use crate::decompress::*;
+#[cfg(feature = "decompress")]
+use crate::util::*;
+
+/// [ByteSink] which appends to any [`Extend<u8>`](Extend) container
+///
+/// This allows compressing into custom growable buffers (e.g. `SmallVec` or bump-allocated vectors).
+#[cfg(feature = "compress")]
+pub struct ExtendSink<'a, T: ?Sized>(pub &'a mut T);
+#[cfg(feature = "compress")]
+impl<T: Extend<u8> + ?Sized> ByteSink for ExtendSink<'_, T> {
+    type Error = CompressError;
+
+    fn putc(&mut self, c: u8) -> Result<(), CompressError> {
+        self.0.extend(Some(c));
+        Ok(())
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+        self.0.extend(buf.iter().copied());
+        Ok(())
+    }
+}
+
+/// Appends to an [`Extend<u8>`](Extend) container, reading back previous output using [`AsRef<[u8]>`](AsRef)
+#[cfg(feature = "decompress")]
+struct ExtendOutput<'a, T: ?Sized> {
+    inner: &'a mut T,
+    start: usize,
+}
+#[cfg(feature = "decompress")]
+impl<T: Extend<u8> + AsRef<[u8]> + ?Sized> OutputSink<DecompressError> for ExtendOutput<'_, T> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        self.inner.extend(lits.iter().copied());
+        Ok(())
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.inner.as_ref().len();
+        // previous contents of the container are not part of the output
+        if disp >= pos - self.start {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos - self.start,
+                disp,
+            });
+        }
+
+        for i in 0..len {
+            let c = self.inner.as_ref()[pos - disp - 1 + i];
+            self.inner.extend(Some(c));
+        }
+
+        Ok(())
+    }
+}
+
+/// Decompress the input, appending it to any [`Extend<u8>`](Extend) container
+///
+/// The container must also be able to provide the data already written, via [`AsRef<[u8]>`](AsRef).
+///
+/// Returns the decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_to_extend<T: Extend<u8> + AsRef<[u8]> + ?Sized>(
+    inp: &[u8],
+    outp: &mut T,
+) -> Result<usize, DecompressError> {
+    decompress_to_extend_with_options(inp, outp, &DecompressOptions::default())
+}
+
+/// Decompress the input, appending it to any [`Extend<u8>`](Extend) container, with additional options
+///
+/// The container must also be able to provide the data already written, via [`AsRef<[u8]>`](AsRef).
+///
+/// Returns the decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_to_extend_with_options<T: Extend<u8> + AsRef<[u8]> + ?Sized>(
+    inp: &[u8],
+    outp: &mut T,
+    opts: &DecompressOptions,
+) -> Result<usize, DecompressError> {
+    let start = outp.as_ref().len();
+    let mut outp = ExtendOutput { inner: outp, start };
+    decompress_impl(inp, &mut LimitOutput::new(&mut outp, inp.len(), opts), opts)?;
+    Ok(outp.inner.as_ref().len() - start)
+}
+
+#[cfg(all(feature = "heapless", feature = "compress"))]
+impl<const N: usize> ByteSink for heapless::Vec<u8, N> {
+    type Error = CompressError;
+
+    fn putc(&mut self, c: u8) -> Result<(), CompressError> {
+        self.push(c).map_err(|_| CompressError::OutputTooSmall {
+            written: N,
+            input_processed: 0,
+            remaining: 0,
+        })
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+        let len = usize::min(buf.len(), N - self.len());
+        // cannot fail, as the length was just checked
+        let _ = self.extend_from_slice(&buf[..len]);
+        if len < buf.len() {
+            Err(CompressError::OutputTooSmall {
+                written: self.len(),
+                input_processed: 0,
+                remaining: 0,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature = "heapless", feature = "decompress"))]
+impl<const N: usize> OutputSink<DecompressError> for heapless::Vec<u8, N> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        let len = usize::min(lits.len(), N - self.len());
+        let _ = self.extend_from_slice(&lits[..len]);
+        if len < lits.len() {
+            Err(DecompressError::OutputTooSmall {
+                written: self.len(),
+                required: 0,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.len();
+        if disp >= pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
+        }
+
+        for i in 0..len {
+            self.push(self[pos - disp - 1 + i])
+                .map_err(|_| DecompressError::OutputTooSmall {
+                    written: N,
+                    required: 0,
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "heapless", feature = "decompress"))]
+/// Decompress the input into a [heapless::Vec]
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_to_heapless_vec<const N: usize>(
+    inp: &[u8],
+) -> Result<heapless::Vec<u8, N>, DecompressError> {
+    decompress_to_heapless_vec_with_options(inp, &DecompressOptions::default())
+}
+
+#[cfg(all(feature = "heapless", feature = "decompress"))]
+/// Decompress the input into a [heapless::Vec], with additional options
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_to_heapless_vec_with_options<const N: usize>(
+    inp: &[u8],
+    opts: &DecompressOptions,
+) -> Result<heapless::Vec<u8, N>, DecompressError> {
+    let mut ret = heapless::Vec::new();
+    decompress_impl(inp, &mut ret, opts)?;
+    Ok(ret)
+}
+
+#[cfg(all(feature = "arrayvec", feature = "compress"))]
+impl<const N: usize> ByteSink for arrayvec::ArrayVec<u8, N> {
+    type Error = CompressError;
+
+    fn putc(&mut self, c: u8) -> Result<(), CompressError> {
+        self.try_push(c).map_err(|_| CompressError::OutputTooSmall {
+            written: N,
+            input_processed: 0,
+            remaining: 0,
+        })
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+        let len = usize::min(buf.len(), self.remaining_capacity());
+        // cannot fail, as the length was just checked
+        let _ = self.try_extend_from_slice(&buf[..len]);
+        if len < buf.len() {
+            Err(CompressError::OutputTooSmall {
+                written: self.len(),
+                input_processed: 0,
+                remaining: 0,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature = "arrayvec", feature = "decompress"))]
+impl<const N: usize> OutputSink<DecompressError> for arrayvec::ArrayVec<u8, N> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        let len = usize::min(lits.len(), self.remaining_capacity());
+        let _ = self.try_extend_from_slice(&lits[..len]);
+        if len < lits.len() {
+            Err(DecompressError::OutputTooSmall {
+                written: self.len(),
+                required: 0,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.len();
+        if disp >= pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
+        }
+
+        for i in 0..len {
+            self.try_push(self[pos - disp - 1 + i]).map_err(|_| {
+                DecompressError::OutputTooSmall {
+                    written: N,
+                    required: 0,
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "arrayvec", feature = "decompress"))]
+/// Decompress the input into an [ArrayVec](arrayvec::ArrayVec)
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_to_arrayvec<const N: usize>(
+    inp: &[u8],
+) -> Result<arrayvec::ArrayVec<u8, N>, DecompressError> {
+    decompress_to_arrayvec_with_options(inp, &DecompressOptions::default())
+}
+
+#[cfg(all(feature = "arrayvec", feature = "decompress"))]
+/// Decompress the input into an [ArrayVec](arrayvec::ArrayVec), with additional options
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_to_arrayvec_with_options<const N: usize>(
+    inp: &[u8],
+    opts: &DecompressOptions,
+) -> Result<arrayvec::ArrayVec<u8, N>, DecompressError> {
+    let mut ret = arrayvec::ArrayVec::new();
+    decompress_impl(inp, &mut ret, opts)?;
+    Ok(ret)
+}
+
+#[cfg(all(feature = "bytes", feature = "compress"))]
+impl ByteSink for bytes::BytesMut {
+    type Error = CompressError;
+
+    fn putc(&mut self, c: u8) -> Result<(), CompressError> {
+        self.extend_from_slice(&[c]);
+        Ok(())
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "bytes", feature = "decompress"))]
+impl OutputSink<DecompressError> for bytes::BytesMut {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        self.extend_from_slice(lits);
+        Ok(())
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.len();
+        if disp >= pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
+        }
+
+        self.resize(pos + len, 0);
+        let (done, out) = self.split_at_mut(pos);
+        crate::accel::copy_backref(&done[pos - disp - 1..], out);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "bytes", feature = "decompress"))]
+/// Decompress the input into a [BytesMut](bytes::BytesMut)
+///
+/// Returns the result on success, or an error otherwise
+///
+/// If `capacity_hint` is provided, it will be passed to [BytesMut::with_capacity](bytes::BytesMut::with_capacity)
+pub fn decompress_to_bytes_mut(
+    inp: &[u8],
+    capacity_hint: Option<usize>,
+) -> Result<bytes::BytesMut, DecompressError> {
+    decompress_to_bytes_mut_with_options(inp, capacity_hint, &DecompressOptions::default())
+}
+
+#[cfg(all(feature = "bytes", feature = "decompress"))]
+/// Decompress the input into a [BytesMut](bytes::BytesMut), with additional options
+///
+/// Returns the result on success, or an error otherwise
+///
+/// If `capacity_hint` is provided, it will be passed to [BytesMut::with_capacity](bytes::BytesMut::with_capacity)
+pub fn decompress_to_bytes_mut_with_options(
+    inp: &[u8],
+    capacity_hint: Option<usize>,
+    opts: &DecompressOptions,
+) -> Result<bytes::BytesMut, DecompressError> {
+    let mut ret = bytes::BytesMut::with_capacity(capacity_hint.unwrap_or(0));
+    decompress_impl(inp, &mut LimitOutput::new(&mut ret, inp.len(), opts), opts)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "compress")]
+    use crate::level::CompressionLevel;
+
+    /// Fixed-size stand-in for a custom growable buffer
+    struct SmallBuf {
+        buf: [u8; 32],
+        len: usize,
+    }
+    impl Extend<u8> for SmallBuf {
+        fn extend<I: IntoIterator<Item = u8>>(&mut self, iter: I) {
+            for c in iter {
+                self.buf[self.len] = c;
+                self.len += 1;
+            }
+        }
+    }
+    impl AsRef<[u8]> for SmallBuf {
+        fn as_ref(&self) -> &[u8] {
+            &self.buf[..self.len]
+        }
+    }
+
+    #[cfg(any(feature = "compress", feature = "decompress"))]
+    #[test]
+    fn test_extend() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let comp = [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4];
+
+        #[cfg(feature = "compress")]
+        {
+            let mut state = CompressState::new();
+            let mut buf = SmallBuf {
+                buf: [0; 32],
+                len: 0,
+            };
+            state
+                .compress_to_sink(&inp, &mut ExtendSink(&mut buf), CompressionLevel::Level1)
+                .unwrap();
+            assert_eq!(buf.as_ref(), comp);
+        }
+
+        #[cfg(feature = "decompress")]
+        {
+            let mut out = SmallBuf {
+                buf: [0; 32],
+                len: 1,
+            };
+            let len = decompress_to_extend(&comp, &mut out).unwrap();
+            assert_eq!(len, inp.len());
+            assert_eq!(out.as_ref()[1..], inp);
+
+            // cannot refer to what was already in the container
+            assert_eq!(
+                decompress_to_extend(&[0x00, 1, 0x20, 0x01], &mut out),
+                Err(DecompressError::InvalidBackreference {
+                    input_offset: 2,
+                    output_pos: 1,
+                    disp: 1
+                })
+            );
+        }
+    }
+
+    #[cfg(all(feature = "heapless", feature = "compress"))]
+    #[test]
+    fn test_heapless() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+
+        let mut comp = heapless::Vec::<u8, 8>::new();
+        state
+            .compress_to_sink(&inp, &mut comp, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(comp, [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]);
+
+        let mut short = heapless::Vec::<u8, 6>::new();
+        assert_eq!(
+            state.compress_to_sink(&inp, &mut short, CompressionLevel::Level1),
+            Err(CompressError::OutputTooSmall {
+                written: 6,
+                input_processed: 0,
+                remaining: 0
+            })
+        );
+        assert_eq!(short, comp[..6]);
+
+        #[cfg(feature = "decompress")]
+        {
+            let out = decompress_to_heapless_vec::<10>(&comp).unwrap();
+            assert_eq!(out, inp);
+            assert_eq!(
+                decompress_to_heapless_vec::<9>(&comp),
+                Err(DecompressError::OutputTooSmall {
+                    written: 9,
+                    required: 10
+                })
+            );
+        }
+    }
+
+    #[cfg(all(feature = "arrayvec", feature = "compress"))]
+    #[test]
+    fn test_arrayvec() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+
+        let mut comp = arrayvec::ArrayVec::<u8, 8>::new();
+        state
+            .compress_to_sink(&inp, &mut comp, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(comp[..], [0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]);
+
+        // fills up to capacity before failing
+        let mut short = arrayvec::ArrayVec::<u8, 6>::new();
+        assert_eq!(
+            state.compress_to_sink(&inp, &mut short, CompressionLevel::Level1),
+            Err(CompressError::OutputTooSmall {
+                written: 6,
+                input_processed: 0,
+                remaining: 0
+            })
+        );
+        assert!(short.is_full());
+        assert_eq!(short[..], comp[..6]);
+
+        #[cfg(feature = "decompress")]
+        {
+            let out = decompress_to_arrayvec::<10>(&comp).unwrap();
+            assert_eq!(out[..], inp);
+            assert_eq!(
+                decompress_to_arrayvec::<9>(&comp),
+                Err(DecompressError::OutputTooSmall {
+                    written: 9,
+                    required: 10
+                })
+            );
+        }
+    }
+
+    #[cfg(all(feature = "bytes", feature = "compress"))]
+    #[test]
+    fn test_bytes_mut() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+
+        // appends to what is already there
+        let mut comp = bytes::BytesMut::from(&b"hdr"[..]);
+        state
+            .compress_to_sink(&inp, &mut comp, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(
+            comp[..],
+            [b'h', b'd', b'r', 0x02, 1, 2, 3, 0x80, 0x02, 0x00, 4]
+        );
+
+        #[cfg(feature = "decompress")]
+        {
+            let out = decompress_to_bytes_mut(&comp[3..], None).unwrap();
+            assert_eq!(out[..], inp);
+        }
+    }
+}
@@ -1,5 +1,18 @@
+#[cfg(feature = "decompress")]
+use core::cell::Cell;
 use core::fmt;
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+use core::mem::MaybeUninit;
+#[cfg(feature = "decompress")]
+use core::ops::ControlFlow;
 
+#[cfg(feature = "decompress")]
+use crate::accel::copy_backref;
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+use crate::accel::copy_backref_uninit;
+use crate::level::CompressionLevel;
+#[cfg(feature = "decompress")]
+use crate::progress::*;
 use crate::util::*;
 
 #[cfg(feature = "alloc")]
@@ -10,76 +23,515 @@ extern crate std;
 
 /// Compression errors
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
 pub enum DecompressError {
     /// The input was truncated
-    InputTruncated,
+    InputTruncated {
+        /// Offset in the input of the start of the instruction which was cut off
+        input_offset: usize,
+    },
     /// The input contains an invalid backreference
-    InvalidBackreference,
+    InvalidBackreference {
+        /// Offset in the input of the start of the backreference instruction
+        input_offset: usize,
+        /// Position in the output at which the backreference occurred
+        output_pos: usize,
+        /// Displacement of the backreference as encoded (i.e. 0 refers to the previous byte)
+        ///
+        /// (On 16-bit targets, level 2 displacements which don't fit are reported as `usize::MAX`.)
+        disp: usize,
+    },
     /// The input contains an compression level indicator
+    ///
+    /// Without the `level2` feature, this includes level 2.
     InvalidCompressionLevel,
     /// The output buffer was too small to hold all the output.
     ///
     /// The output that has been written *is* valid, but has been truncated.
-    OutputTooSmall,
+    OutputTooSmall {
+        /// Number of bytes which were written before running out of space
+        written: usize,
+        /// Number of bytes needed to hold the entire output
+        ///
+        /// This is determined by scanning the rest of the input,
+        /// so it may be inaccurate if the input is corrupted.
+        required: usize,
+    },
+    /// The decompressed size did not match the size stored in the frame header
+    FrameSizeMismatch,
+    /// The level 2 input ends on a backreference requiring extended displacement bytes
+    ///
+    /// This is only reported if [DecompressOptions::strict_l2_end] is set.
+    FarBackreferenceAtEnd,
+    /// The progress callback requested that decompression stop
+    Cancelled,
+    /// The output would have grown beyond [DecompressOptions::max_output_len]
+    OutputLimitExceeded {
+        /// The limit which was exceeded
+        limit: usize,
+    },
+    /// The output would have grown beyond [DecompressOptions::max_expansion_ratio] times the size of the input
+    ExpansionRatioExceeded {
+        /// The ratio which was exceeded
+        ratio: usize,
+    },
+    /// The input contains more instructions than [DecompressLimits::max_opcodes]
+    OpcodeLimitExceeded {
+        /// Offset in the input of the first instruction beyond the limit
+        input_offset: usize,
+        /// The limit which was exceeded
+        limit: usize,
+    },
+    /// The input contains a backreference further back than [DecompressLimits::max_backref_distance]
+    BackreferenceTooFar {
+        /// Offset in the input of the start of the backreference instruction
+        input_offset: usize,
+        /// The limit which was exceeded
+        limit: usize,
+    },
+    /// The input contains more literal bytes in total than [DecompressLimits::max_literal_bytes]
+    LiteralLimitExceeded {
+        /// Offset in the input of the literal run which went beyond the limit
+        input_offset: usize,
+        /// The limit which was exceeded
+        limit: usize,
+    },
 }
 impl fmt::Display for DecompressError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            DecompressError::InputTruncated => write!(f, "input was truncated"),
-            DecompressError::InvalidBackreference => write!(f, "invalid backreference"),
+            DecompressError::InputTruncated { input_offset } => write!(
+                f,
+                "input was truncated (in the instruction at offset {})",
+                input_offset
+            ),
+            DecompressError::InvalidBackreference {
+                input_offset,
+                output_pos,
+                disp,
+            } => write!(
+                f,
+                "invalid backreference with displacement {} at output position {} \
+                (in the instruction at offset {})",
+                disp, output_pos, input_offset
+            ),
             DecompressError::InvalidCompressionLevel => write!(f, "invalid compression level"),
-            DecompressError::OutputTooSmall => write!(f, "output buffer was insufficient"),
+            DecompressError::OutputTooSmall { written, required } => write!(
+                f,
+                "output buffer was insufficient ({} bytes written, {} required)",
+                written, required
+            ),
+            DecompressError::FrameSizeMismatch => write!(f, "frame size did not match contents"),
+            DecompressError::FarBackreferenceAtEnd => {
+                write!(f, "input ended on a far backreference")
+            }
+            DecompressError::Cancelled => write!(f, "decompression was cancelled"),
+            DecompressError::OutputLimitExceeded { limit } => {
+                write!(f, "output would exceed the limit of {} bytes", limit)
+            }
+            DecompressError::ExpansionRatioExceeded { ratio } => {
+                write!(
+                    f,
+                    "output would exceed {} times the size of the input",
+                    ratio
+                )
+            }
+            DecompressError::OpcodeLimitExceeded {
+                input_offset,
+                limit,
+            } => write!(
+                f,
+                "input has more than {} instructions (at offset {})",
+                limit, input_offset
+            ),
+            DecompressError::BackreferenceTooFar {
+                input_offset,
+                limit,
+            } => write!(
+                f,
+                "backreference is further back than the limit of {} bytes \
+                (in the instruction at offset {})",
+                limit, input_offset
+            ),
+            DecompressError::LiteralLimitExceeded {
+                input_offset,
+                limit,
+            } => write!(
+                f,
+                "input has more than {} literal bytes (in the instruction at offset {})",
+                limit, input_offset
+            ),
+        }
+    }
+}
+impl core::error::Error for DecompressError {}
+impl DecompressError {
+    /// Fill in the input offset for errors which carry one
+    fn with_input_offset(self, offset: usize) -> Self {
+        match self {
+            DecompressError::InputTruncated { .. } => DecompressError::InputTruncated {
+                input_offset: offset,
+            },
+            DecompressError::InvalidBackreference {
+                output_pos, disp, ..
+            } => DecompressError::InvalidBackreference {
+                input_offset: offset,
+                output_pos,
+                disp,
+            },
+            DecompressError::BackreferenceTooFar { limit, .. } => {
+                DecompressError::BackreferenceTooFar {
+                    input_offset: offset,
+                    limit,
+                }
+            }
+            DecompressError::LiteralLimitExceeded { limit, .. } => {
+                DecompressError::LiteralLimitExceeded {
+                    input_offset: offset,
+                    limit,
+                }
+            }
+            e => e,
         }
     }
 }
-#[cfg(feature = "std")]
-impl std::error::Error for DecompressError {}
 
-impl<'a> OutputSink<DecompressError> for BufOutput<'a> {
+/// Additional options controlling decompression
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct DecompressOptions {
+    /// Reject level 2 inputs which end on a backreference requiring extended displacement bytes
+    ///
+    /// The format does not permit this, but not all encoders respect the rule,
+    /// so such inputs are accepted by default.
+    pub strict_l2_end: bool,
+    /// Maximum number of bytes to decompress into a growable output (e.g. a `Vec`)
+    ///
+    /// Decompression fails with [DecompressError::OutputLimitExceeded] rather than exceeding this,
+    /// which protects against small inputs expanding to enormous outputs.
+    /// Fixed-size outputs are already limited by their size, so this does not apply to them.
+    pub max_output_len: Option<usize>,
+    /// Maximum size of the output into a growable output, as a multiple of the size of the input
+    ///
+    /// Decompression fails with [DecompressError::ExpansionRatioExceeded] rather than exceeding this.
+    /// This applies as well as [max_output_len](Self::max_output_len) (whichever is reached first),
+    /// as mitigation against "decompression bombs" which doesn't need a fixed cap on sizes.
+    /// As for `max_output_len`, this does not apply to fixed-size outputs.
+    ///
+    /// The format itself can only expand by about 90 times at level 1, or 255 times at level 2
+    /// (for a run of a single byte), so smaller ratios than that are needed to make a difference.
+    pub max_expansion_ratio: Option<usize>,
+}
+
+/// Bounds on the work done decoding untrusted input, for [decompress_with_limits]
+///
+/// The time taken to decode is proportional to the number of instructions plus the size of the output,
+/// so limiting these bounds the worst case for each input (e.g. in network-facing services).
+/// Each limit is unbounded if it is `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+#[cfg(feature = "decompress")]
+pub struct DecompressLimits {
+    /// Maximum number of instructions (literal runs and backreferences) to decode
+    ///
+    /// This bounds the time taken by streams of many tiny instructions (e.g. millions of
+    /// 2-byte backreferences), which the size of the output alone doesn't account for.
+    /// Every instruction takes at least 2 bytes of input, so this only makes a difference
+    /// for inputs longer than twice the limit.
+    pub max_opcodes: Option<usize>,
+    /// Maximum distance back that a backreference can copy from
+    ///
+    /// A distance of 1 repeats the previous byte.
+    pub max_backref_distance: Option<usize>,
+    /// Maximum total number of bytes in literal runs
+    pub max_literal_bytes: Option<usize>,
+}
+
+/// Wraps another output, enforcing the [DecompressLimits] which depend on the instructions' contents
+#[cfg(feature = "decompress")]
+struct BoundedOutput<'a, O: ?Sized> {
+    inner: &'a mut O,
+    literal_bytes: usize,
+    max_backref_distance: usize,
+    max_literal_bytes: usize,
+}
+#[cfg(feature = "decompress")]
+impl<'a, O: ?Sized> BoundedOutput<'a, O> {
+    fn new(inner: &'a mut O, limits: &DecompressLimits) -> Self {
+        Self {
+            inner,
+            literal_bytes: 0,
+            max_backref_distance: limits.max_backref_distance.unwrap_or(usize::MAX),
+            max_literal_bytes: limits.max_literal_bytes.unwrap_or(usize::MAX),
+        }
+    }
+}
+#[cfg(feature = "decompress")]
+impl<O: OutputSink<DecompressError> + ?Sized> OutputSink<DecompressError> for BoundedOutput<'_, O> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        if lits.len() > self.max_literal_bytes - self.literal_bytes {
+            return Err(DecompressError::LiteralLimitExceeded {
+                input_offset: 0,
+                limit: self.max_literal_bytes,
+            });
+        }
+        self.literal_bytes += lits.len();
+        self.inner.put_lits(lits)
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        if disp >= self.max_backref_distance {
+            return Err(DecompressError::BackreferenceTooFar {
+                input_offset: 0,
+                limit: self.max_backref_distance,
+            });
+        }
+        self.inner.put_backref(disp, len)
+    }
+}
+
+/// A single instruction of a compressed stream, as listed by [decode_tokens]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg(feature = "decompress")]
+pub enum Token {
+    /// A run of literal bytes, which follow the opcode byte in the input
+    Literals {
+        /// Offset in the input of the start of the instruction
+        input_offset: usize,
+        /// Position in the output of the first literal
+        output_pos: usize,
+        /// Number of literals
+        len: usize,
+    },
+    /// A copy of earlier output
+    Backreference {
+        /// Offset in the input of the start of the instruction
+        input_offset: usize,
+        /// Position in the output of the first byte copied to
+        output_pos: usize,
+        /// Displacement as encoded (i.e. 0 refers to the previous byte)
+        disp: usize,
+        /// Number of bytes copied
+        len: usize,
+    },
+}
+
+/// Passes each instruction to a callback instead of producing output
+#[cfg(feature = "decompress")]
+struct TokenOutput<'a, F> {
+    /// Offset of the current instruction, updated by the decoder's per-instruction hook
+    input_offset: &'a Cell<usize>,
+    pos: usize,
+    f: F,
+}
+#[cfg(feature = "decompress")]
+impl<F: FnMut(Token)> OutputSink<DecompressError> for TokenOutput<'_, F> {
     fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
-        let mut len = lits.len();
-        let mut did_overflow = false;
-        if self.pos + len > self.buf.len() {
-            did_overflow = true;
-            len = self.buf.len() - self.pos;
+        (self.f)(Token::Literals {
+            input_offset: self.input_offset.get(),
+            output_pos: self.pos,
+            len: lits.len(),
+        });
+        self.pos = self.pos.saturating_add(lits.len());
+        Ok(())
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        if disp >= self.pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: self.pos,
+                disp,
+            });
         }
+        (self.f)(Token::Backreference {
+            input_offset: self.input_offset.get(),
+            output_pos: self.pos,
+            disp,
+            len,
+        });
+        self.pos = self.pos.saturating_add(len);
+        Ok(())
+    }
+}
 
-        self.buf[self.pos..self.pos + len].copy_from_slice(&lits[..len]);
+/// Output of lenient decompression, along with the error which stopped it (if any)
+///
+/// The output contains everything which was decoded before the error occurred.
+/// The error indicates where in the input decoding stopped (e.g. [DecompressError::InvalidBackreference::input_offset]).
+#[derive(Debug, PartialEq, Eq)]
+#[cfg(feature = "decompress")]
+pub struct LenientOutput<T> {
+    /// The output which was successfully decoded
+    pub output: T,
+    /// The error which stopped decompression, or `None` if the entire input was decoded
+    pub error: Option<DecompressError>,
+}
+
+/// Wraps a growable output, enforcing [DecompressOptions::max_output_len]
+/// and [DecompressOptions::max_expansion_ratio]
+#[cfg(feature = "decompress")]
+pub(crate) struct LimitOutput<'a, O: ?Sized> {
+    inner: &'a mut O,
+    written: usize,
+    limit: usize,
+    /// The size allowed by `max_expansion_ratio`
+    ratio_limit: usize,
+    ratio: usize,
+}
+#[cfg(feature = "decompress")]
+impl<'a, O: ?Sized> LimitOutput<'a, O> {
+    /// `inp_len` is the size of the compressed input
+    pub fn new(inner: &'a mut O, inp_len: usize, opts: &DecompressOptions) -> Self {
+        let ratio = opts.max_expansion_ratio.unwrap_or(usize::MAX);
+        Self {
+            inner,
+            written: 0,
+            limit: opts.max_output_len.unwrap_or(usize::MAX),
+            ratio_limit: inp_len.saturating_mul(ratio),
+            ratio,
+        }
+    }
+
+    fn advance(&mut self, len: usize) -> Result<(), DecompressError> {
+        if len > self.limit - self.written {
+            return Err(DecompressError::OutputLimitExceeded { limit: self.limit });
+        }
+        if len > self.ratio_limit - self.written {
+            return Err(DecompressError::ExpansionRatioExceeded { ratio: self.ratio });
+        }
+        self.written += len;
+        Ok(())
+    }
+}
+#[cfg(feature = "decompress")]
+impl<O: OutputSink<DecompressError> + ?Sized> OutputSink<DecompressError> for LimitOutput<'_, O> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        self.advance(lits.len())?;
+        self.inner.put_lits(lits)
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        self.advance(len)?;
+        self.inner.put_backref(disp, len)
+    }
+}
+
+#[cfg(feature = "decompress")]
+impl<'a> OutputSink<DecompressError> for BufOutput<'a> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        let (_, spare) = self.split();
+        let len = usize::min(lits.len(), spare.len());
+        spare[..len].copy_from_slice(&lits[..len]);
         self.pos += len;
 
-        if did_overflow {
-            Err(DecompressError::OutputTooSmall)
+        if len < lits.len() {
+            Err(DecompressError::OutputTooSmall {
+                written: self.pos,
+                required: 0,
+            })
         } else {
             Ok(())
         }
     }
 
-    fn put_backref(&mut self, disp: usize, mut len: usize) -> Result<(), DecompressError> {
-        if disp + 1 > self.pos {
-            return Err(DecompressError::InvalidBackreference);
-        }
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.pos;
+        let (done, spare) = self.split();
+        let Some(src) = done.len().checked_sub(disp.saturating_add(1)) else {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
+        };
 
-        let mut did_overflow = false;
-        if self.pos + len > self.buf.len() {
-            did_overflow = true;
-            len = self.buf.len() - self.pos;
+        let n = usize::min(len, spare.len());
+        copy_backref(&done[src..], &mut spare[..n]);
+        self.pos += n;
+
+        if n < len {
+            Err(DecompressError::OutputTooSmall {
+                written: self.pos,
+                required: 0,
+            })
+        } else {
+            Ok(())
         }
+    }
+}
 
-        for i in 0..len {
-            self.buf[self.pos + i] = self.buf[self.pos - disp - 1 + i];
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+impl<'a> OutputSink<DecompressError> for UninitBufOutput<'a> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        let (_, spare) = self.split();
+        let len = usize::min(lits.len(), spare.len());
+        for (dst, &src) in spare[..len].iter_mut().zip(lits) {
+            dst.write(src);
         }
         self.pos += len;
 
-        if did_overflow {
-            Err(DecompressError::OutputTooSmall)
+        if len < lits.len() {
+            Err(DecompressError::OutputTooSmall {
+                written: self.pos,
+                required: 0,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        let pos = self.pos;
+        let (done, spare) = self.split();
+        let Some(src) = done.len().checked_sub(disp.saturating_add(1)) else {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
+        };
+
+        // (copying `MaybeUninit`s, so nothing needs to be assumed initialized)
+        let n = usize::min(len, spare.len());
+        copy_backref_uninit(&done[src..], &mut spare[..n]);
+        self.pos += n;
+
+        if n < len {
+            Err(DecompressError::OutputTooSmall {
+                written: self.pos,
+                required: 0,
+            })
         } else {
             Ok(())
         }
     }
 }
 
-#[cfg(feature = "alloc")]
+impl OutputSink<DecompressError> for CountOutput {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        self.0 = self.0.saturating_add(lits.len());
+        Ok(())
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        if disp >= self.0 {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: self.0,
+                disp,
+            });
+        }
+        self.0 = self.0.saturating_add(len);
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
 impl OutputSink<DecompressError> for VecOutput {
     fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
         self.vec.extend_from_slice(lits);
@@ -88,67 +540,201 @@ impl OutputSink<DecompressError> for VecOutput {
 
     fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
         let pos = self.vec.len();
-        if disp + 1 > pos {
-            return Err(DecompressError::InvalidBackreference);
+        if disp >= pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: pos,
+                disp,
+            });
         }
 
-        self.vec.resize(pos + len, 0);
-        for i in 0..len {
-            self.vec[pos + i] = self.vec[pos - disp - 1 + i];
+        // append directly rather than zero-filling and then overwriting
+        let src = pos - disp - 1;
+        self.vec.reserve(len);
+        if disp == 0 {
+            // a run of a single byte
+            let c = self.vec[src];
+            self.vec.resize(pos + len, c);
+        } else {
+            // each copy doubles the repeating pattern available behind the end (see copy_backref)
+            let mut copied = 0;
+            while copied < len {
+                let n = usize::min(disp + 1 + copied, len - copied);
+                self.vec.extend_from_within(src..src + n);
+                copied += n;
+            }
         }
 
         Ok(())
     }
 }
 
-trait InputHelper {
+pub(crate) trait InputHelper {
     fn getc(&mut self) -> Result<u8, DecompressError>;
-    fn check_len(&mut self, min: usize) -> Result<(), DecompressError>;
+    fn peek(&self) -> Option<u8>;
+    #[cfg(feature = "level2")]
+    fn at_end(&self) -> bool;
+    /// Number of bytes left in the input
+    fn remaining(&self) -> usize;
+    fn copy_lits(
+        &mut self,
+        len: usize,
+        outp: &mut impl OutputSink<DecompressError>,
+    ) -> Result<(), DecompressError>;
 }
 impl InputHelper for &[u8] {
     fn getc(&mut self) -> Result<u8, DecompressError> {
-        if self.len() == 0 {
-            return Err(DecompressError::InputTruncated);
+        if self.is_empty() {
+            return Err(DecompressError::InputTruncated { input_offset: 0 });
         }
         let c = self[0];
         *self = &self[1..];
         Ok(c)
     }
 
-    fn check_len(&mut self, min: usize) -> Result<(), DecompressError> {
-        if self.len() < min {
-            Err(DecompressError::InputTruncated)
-        } else {
-            Ok(())
+    fn peek(&self) -> Option<u8> {
+        self.first().copied()
+    }
+
+    #[cfg(feature = "level2")]
+    fn at_end(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn remaining(&self) -> usize {
+        self.len()
+    }
+
+    fn copy_lits(
+        &mut self,
+        len: usize,
+        outp: &mut impl OutputSink<DecompressError>,
+    ) -> Result<(), DecompressError> {
+        if self.len() < len {
+            return Err(DecompressError::InputTruncated { input_offset: 0 });
+        }
+        outp.put_lits(&self[..len])?;
+        *self = &self[len..];
+        Ok(())
+    }
+}
+
+/// Input split across multiple segments (e.g. a wrapped ring buffer)
+///
+/// `cur` is kept non-empty unless the entire input has been consumed
+#[derive(Clone)]
+#[cfg(feature = "decompress")]
+struct SegmentedInput<'a, 'b> {
+    cur: &'a [u8],
+    rest: &'b [&'a [u8]],
+    /// Total length of `rest`
+    rest_len: usize,
+}
+#[cfg(feature = "decompress")]
+impl<'a, 'b> SegmentedInput<'a, 'b> {
+    fn new(segments: &'b [&'a [u8]]) -> Self {
+        let mut ret = Self {
+            cur: &[],
+            rest: segments,
+            rest_len: segments.iter().map(|s| s.len()).sum(),
+        };
+        ret.skip_empty();
+        ret
+    }
+
+    fn skip_empty(&mut self) {
+        while self.cur.is_empty() {
+            if let Some((&next, rest)) = self.rest.split_first() {
+                self.cur = next;
+                self.rest = rest;
+                self.rest_len -= next.len();
+            } else {
+                break;
+            }
         }
     }
 }
+#[cfg(feature = "decompress")]
+impl InputHelper for SegmentedInput<'_, '_> {
+    fn getc(&mut self) -> Result<u8, DecompressError> {
+        let c = self.cur.getc()?;
+        self.skip_empty();
+        Ok(c)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.cur.peek()
+    }
+
+    #[cfg(feature = "level2")]
+    fn at_end(&self) -> bool {
+        self.cur.is_empty()
+    }
+
+    fn remaining(&self) -> usize {
+        self.cur.len() + self.rest_len
+    }
+
+    fn copy_lits(
+        &mut self,
+        mut len: usize,
+        outp: &mut impl OutputSink<DecompressError>,
+    ) -> Result<(), DecompressError> {
+        // check everything is there first, to match the behavior of contiguous input
+        if self.remaining() < len {
+            return Err(DecompressError::InputTruncated { input_offset: 0 });
+        }
+
+        while len > 0 {
+            let n = usize::min(len, self.cur.len());
+            self.cur.copy_lits(n, outp)?;
+            self.skip_empty();
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a single level 1 instruction (other than the opcode byte)
+fn decompress_lv1_op(
+    ctrl: u8,
+    inp: &mut impl InputHelper,
+    outp: &mut impl OutputSink<DecompressError>,
+) -> Result<(), DecompressError> {
+    if ctrl >> 5 == 0b000 {
+        // literal run
+        let len = (ctrl & 0b000_11111) as usize + 1;
+        inp.copy_lits(len, outp)?;
+    } else {
+        // backreference
+        let mut disp = ((ctrl & 0b000_11111) as usize) << 8;
+        let len = if ctrl >> 5 == 0b111 {
+            // long match
+            inp.getc()? as usize + 9
+        } else {
+            (ctrl >> 5) as usize + 2
+        };
+        disp |= inp.getc()? as usize;
+        outp.put_backref(disp, len)?;
+    }
+    Ok(())
+}
 
 fn decompress_lv1(
-    mut inp: &[u8],
+    mut inp: impl InputHelper,
     outp: &mut impl OutputSink<DecompressError>,
+    progress: &mut impl FnMut(usize) -> Result<(), DecompressError>,
 ) -> Result<(), DecompressError> {
-    // special for first control byte
-    let mut ctrl = inp.getc().unwrap() & 0b000_11111;
+    let total_len = inp.remaining();
+    // special for first control byte (which has the level in its top bits)
+    let Ok(first) = inp.getc() else {
+        return Ok(());
+    };
+    let mut ctrl = first & 0b000_11111;
     loop {
-        if ctrl >> 5 == 0b000 {
-            // literal run
-            let len = (ctrl & 0b000_11111) as usize + 1;
-            inp.check_len(len)?;
-            outp.put_lits(&inp[..len])?;
-            inp = &inp[len..];
-        } else {
-            // backreference
-            let mut disp = ((ctrl & 0b000_11111) as usize) << 8;
-            let len = if ctrl >> 5 == 0b111 {
-                // long match
-                inp.getc()? as usize + 9
-            } else {
-                (ctrl >> 5) as usize + 2
-            };
-            disp |= inp.getc()? as usize;
-            outp.put_backref(disp, len)?;
-        }
+        let op_offset = total_len - inp.remaining() - 1;
+        progress(op_offset)?;
+        decompress_lv1_op(ctrl, &mut inp, outp).map_err(|e| e.with_input_offset(op_offset))?;
 
         if let Ok(c) = inp.getc() {
             ctrl = c;
@@ -158,44 +744,70 @@ fn decompress_lv1(
     }
 }
 
-fn decompress_lv2(
-    mut inp: &[u8],
+/// Decode a single level 2 instruction (other than the opcode byte)
+#[cfg(feature = "level2")]
+fn decompress_lv2_op(
+    ctrl: u8,
+    inp: &mut impl InputHelper,
     outp: &mut impl OutputSink<DecompressError>,
+    opts: &DecompressOptions,
 ) -> Result<(), DecompressError> {
-    // special for first control byte
-    let mut ctrl = inp.getc().unwrap() & 0b000_11111;
-    loop {
-        if ctrl >> 5 == 0b000 {
-            // literal run
-            let len = (ctrl & 0b000_11111) as usize + 1;
-            inp.check_len(len)?;
-            outp.put_lits(&inp[..len])?;
-            inp = &inp[len..];
-        } else {
-            // backreference
-            let mut disp = ((ctrl & 0b000_11111) as usize) << 8;
-
-            let mut len = (ctrl >> 5) as usize + 2;
-            if ctrl >> 5 == 0b111 {
-                // long match
-                loop {
-                    let morelen = inp.getc()?;
-                    len += morelen as usize;
-                    if morelen != 0xff {
-                        break;
-                    }
+    if ctrl >> 5 == 0b000 {
+        // literal run
+        let len = (ctrl & 0b000_11111) as usize + 1;
+        inp.copy_lits(len, outp)?;
+    } else {
+        // backreference
+        let mut disp = ((ctrl & 0b000_11111) as usize) << 8;
+
+        let mut len = (ctrl >> 5) as usize + 2;
+        if ctrl >> 5 == 0b111 {
+            // long match
+            loop {
+                let morelen = inp.getc()?;
+                // (saturating, as a long enough run of 0xff could otherwise overflow on small targets)
+                len = usize::saturating_add(len, morelen as usize);
+                if morelen != 0xff {
+                    break;
                 }
             }
+        }
 
-            disp |= inp.getc()? as usize;
-            if disp == 0b11111_11111111 {
-                let moredisp = ((inp.getc()? as usize) << 8) | (inp.getc()? as usize);
-                disp += moredisp;
-            }
+        disp |= inp.getc()? as usize;
+        if disp == 0b11111_11111111 {
+            let moredisp = ((inp.getc()? as usize) << 8) | (inp.getc()? as usize);
+            // (saturating, as this can overflow a 16-bit usize, but then it's too far back anyway)
+            disp = usize::saturating_add(disp, moredisp);
 
-            outp.put_backref(disp, len)?;
+            if opts.strict_l2_end && inp.at_end() {
+                return Err(DecompressError::FarBackreferenceAtEnd);
+            }
         }
 
+        outp.put_backref(disp, len)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "level2")]
+fn decompress_lv2(
+    mut inp: impl InputHelper,
+    outp: &mut impl OutputSink<DecompressError>,
+    opts: &DecompressOptions,
+    progress: &mut impl FnMut(usize) -> Result<(), DecompressError>,
+) -> Result<(), DecompressError> {
+    let total_len = inp.remaining();
+    // special for first control byte (which has the level in its top bits)
+    let Ok(first) = inp.getc() else {
+        return Ok(());
+    };
+    let mut ctrl = first & 0b000_11111;
+    loop {
+        let op_offset = total_len - inp.remaining() - 1;
+        progress(op_offset)?;
+        decompress_lv2_op(ctrl, &mut inp, outp, opts)
+            .map_err(|e| e.with_input_offset(op_offset))?;
+
         if let Ok(c) = inp.getc() {
             ctrl = c;
         } else {
@@ -204,31 +816,262 @@ fn decompress_lv2(
     }
 }
 
-fn decompress_impl(
-    inp: &[u8],
+/// `progress` is called with the input offset of each instruction, and can stop decoding by returning an error
+#[cfg_attr(not(feature = "level2"), allow(unused_variables))]
+fn decompress_any_level(
+    inp: impl InputHelper,
     outp: &mut impl OutputSink<DecompressError>,
+    opts: &DecompressOptions,
+    progress: &mut impl FnMut(usize) -> Result<(), DecompressError>,
 ) -> Result<(), DecompressError> {
-    if inp.len() == 0 {
+    let Some(first) = inp.peek() else {
         return Ok(());
+    };
+
+    match CompressionLevel::from_header_bits(first)? {
+        #[cfg(feature = "level2")]
+        CompressionLevel::Level2 => decompress_lv2(inp, outp, opts, progress),
+        _ => decompress_lv1(inp, outp, progress),
     }
+}
 
-    match inp[0] >> 5 {
-        0 => decompress_lv1(inp, outp),
-        1 => decompress_lv2(inp, outp),
-        _ => Err(DecompressError::InvalidCompressionLevel),
+pub(crate) fn decompress_impl(
+    inp: impl InputHelper + Clone,
+    outp: &mut impl OutputSink<DecompressError>,
+    opts: &DecompressOptions,
+) -> Result<(), DecompressError> {
+    decompress_impl_with_progress(inp, outp, opts, &mut |_| Ok(()))
+}
+
+fn decompress_impl_with_progress(
+    inp: impl InputHelper + Clone,
+    outp: &mut impl OutputSink<DecompressError>,
+    opts: &DecompressOptions,
+    progress: &mut impl FnMut(usize) -> Result<(), DecompressError>,
+) -> Result<(), DecompressError> {
+    // with `opt-size`, the decoder is only instantiated once per kind of input,
+    // rather than once for every kind of output
+    #[cfg(feature = "opt-size")]
+    let (mut outp, mut progress): (
+        &mut dyn OutputSink<DecompressError>,
+        &mut dyn FnMut(usize) -> Result<(), DecompressError>,
+    ) = (outp, progress);
+    #[cfg(feature = "opt-size")]
+    let (outp, progress) = (&mut outp, &mut progress);
+
+    match decompress_any_level(inp.clone(), outp, opts, progress) {
+        Err(DecompressError::OutputTooSmall { written, .. }) => {
+            // find out how much space would actually have been needed
+            let mut count = CountOutput(0);
+            let _ = decompress_any_level(inp, &mut count, opts, &mut |_| Ok(()));
+            Err(DecompressError::OutputTooSmall {
+                written,
+                required: count.0,
+            })
+        }
+        res => res,
+    }
+}
+
+/// Stop decoding with [DecompressError::Cancelled] if the progress callback asked to
+#[cfg(feature = "decompress")]
+fn cancel_on_break(flow: ControlFlow<()>) -> Result<(), DecompressError> {
+    match flow {
+        ControlFlow::Continue(()) => Ok(()),
+        ControlFlow::Break(()) => Err(DecompressError::Cancelled),
+    }
+}
+
+#[cfg(feature = "decompress")]
+fn decompress_impl_to_buf(
+    inp: impl InputHelper + Clone,
+    outp: &mut [u8],
+    opts: &DecompressOptions,
+) -> Result<usize, DecompressError> {
+    let mut outp: BufOutput = outp.into();
+    decompress_impl(inp, &mut outp, opts)?;
+    Ok(outp.pos)
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+fn decompress_impl_to_vec(
+    inp: impl InputHelper + Clone,
+    capacity_hint: Option<usize>,
+    opts: &DecompressOptions,
+) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    let mut ret: VecOutput = if let Some(capacity_hint) = capacity_hint {
+        alloc::vec::Vec::with_capacity(capacity_hint)
+    } else {
+        alloc::vec::Vec::new()
     }
+    .into();
+    let inp_len = inp.remaining();
+    decompress_impl(inp, &mut LimitOutput::new(&mut ret, inp_len, opts), opts)?;
+    Ok(ret.vec)
 }
 
 /// Decompress the input into a preallocated buffer
 ///
 /// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
 pub fn decompress_to_buf(inp: &[u8], outp: &mut [u8]) -> Result<usize, DecompressError> {
+    decompress_to_buf_with_options(inp, outp, &DecompressOptions::default())
+}
+
+/// Decompress the input into a preallocated buffer, with additional options
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_to_buf_with_options(
+    inp: &[u8],
+    outp: &mut [u8],
+    opts: &DecompressOptions,
+) -> Result<usize, DecompressError> {
+    decompress_impl_to_buf(inp, outp, opts)
+}
+
+/// Decompress the input into a preallocated buffer, with additional options,
+/// periodically reporting progress
+///
+/// `progress` is called roughly every [PROGRESS_INTERVAL] bytes of input.
+/// If it returns [ControlFlow::Break], decompression stops with [DecompressError::Cancelled].
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_to_buf_with_progress(
+    inp: &[u8],
+    outp: &mut [u8],
+    opts: &DecompressOptions,
+    progress: impl FnMut(Progress) -> ControlFlow<()>,
+) -> Result<usize, DecompressError> {
+    let mut reporter = ProgressReporter::new(inp.len(), progress);
     let mut outp: BufOutput = outp.into();
-    decompress_impl(inp, &mut outp)?;
+    decompress_impl_with_progress(inp, &mut outp, opts, &mut |pos| {
+        cancel_on_break(reporter.update(pos))
+    })?;
     Ok(outp.pos)
 }
 
-#[cfg(feature = "alloc")]
+/// Decompress untrusted input into a preallocated buffer, within the given limits
+///
+/// If the output runs out of space, the rest of the input is scanned to find out how much would have been needed,
+/// within the same limits (so this does at most twice the work the limits allow,
+/// but the size reported only covers as much of the input as the limits do).
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_with_limits(
+    inp: &[u8],
+    outp: &mut [u8],
+    limits: &DecompressLimits,
+) -> Result<usize, DecompressError> {
+    let mut outp: BufOutput = outp.into();
+    match decompress_limited(inp, &mut outp, limits) {
+        Err(DecompressError::OutputTooSmall { written, .. }) => {
+            let mut count = CountOutput(0);
+            let _ = decompress_limited(inp, &mut count, limits);
+            Err(DecompressError::OutputTooSmall {
+                written,
+                required: count.0,
+            })
+        }
+        res => res.map(|()| outp.pos),
+    }
+}
+
+#[cfg(feature = "decompress")]
+fn decompress_limited(
+    inp: &[u8],
+    outp: &mut impl OutputSink<DecompressError>,
+    limits: &DecompressLimits,
+) -> Result<(), DecompressError> {
+    let max_opcodes = limits.max_opcodes.unwrap_or(usize::MAX);
+    let mut opcodes = 0;
+    decompress_any_level(
+        inp,
+        &mut BoundedOutput::new(outp, limits),
+        &DecompressOptions::default(),
+        &mut |input_offset| {
+            if opcodes == max_opcodes {
+                return Err(DecompressError::OpcodeLimitExceeded {
+                    input_offset,
+                    limit: max_opcodes,
+                });
+            }
+            opcodes += 1;
+            Ok(())
+        },
+    )
+}
+
+/// Decode the input without producing any output, calling `f` with each instruction in turn
+///
+/// This is intended for debugging, e.g. interoperability problems with other encoders.
+/// Instructions are checked as they would be when decompressing,
+/// so if an error is returned, `f` has been called with every instruction before the invalid one.
+///
+/// Returns the decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decode_tokens(inp: &[u8], f: impl FnMut(Token)) -> Result<usize, DecompressError> {
+    let input_offset = Cell::new(0);
+    let mut outp = TokenOutput {
+        input_offset: &input_offset,
+        pos: 0,
+        f,
+    };
+    decompress_any_level(inp, &mut outp, &DecompressOptions::default(), &mut |pos| {
+        input_offset.set(pos);
+        Ok(())
+    })?;
+    Ok(outp.pos)
+}
+
+/// Decompress as much of the input as possible into a preallocated buffer
+///
+/// Rather than discarding the output when a corrupt instruction is encountered,
+/// this returns the length of everything decoded up to that point along with the error.
+/// This can be used to recover data from partially-damaged inputs.
+#[cfg(feature = "decompress")]
+pub fn decompress_to_buf_lenient(
+    inp: &[u8],
+    outp: &mut [u8],
+    opts: &DecompressOptions,
+) -> LenientOutput<usize> {
+    let mut outp: BufOutput = outp.into();
+    let error = decompress_impl(inp, &mut outp, opts).err();
+    LenientOutput {
+        output: outp.pos,
+        error,
+    }
+}
+
+/// Decompress the input into a preallocated buffer which doesn't need to be initialized
+///
+/// Returns the initialized (i.e. decompressed) portion of the buffer on success, or an error otherwise
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+pub fn decompress_to_uninit_buf<'a>(
+    inp: &[u8],
+    outp: &'a mut [MaybeUninit<u8>],
+) -> Result<&'a [u8], DecompressError> {
+    decompress_to_uninit_buf_with_options(inp, outp, &DecompressOptions::default())
+}
+
+/// Decompress the input into a preallocated buffer which doesn't need to be initialized,
+/// with additional options
+///
+/// Returns the initialized (i.e. decompressed) portion of the buffer on success, or an error otherwise
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+pub fn decompress_to_uninit_buf_with_options<'a>(
+    inp: &[u8],
+    outp: &'a mut [MaybeUninit<u8>],
+    opts: &DecompressOptions,
+) -> Result<&'a [u8], DecompressError> {
+    let mut outp: UninitBufOutput = outp.into();
+    decompress_impl(inp, &mut outp, opts)?;
+    Ok(outp.into_init())
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
 /// Decompress the input into a [Vec](alloc::vec::Vec)
 ///
 /// Returns the result on success, or an error otherwise
@@ -238,20 +1081,131 @@ pub fn decompress_to_vec(
     inp: &[u8],
     capacity_hint: Option<usize>,
 ) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    decompress_to_vec_with_options(inp, capacity_hint, &DecompressOptions::default())
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress the input into a [Vec](alloc::vec::Vec), with additional options
+///
+/// Returns the result on success, or an error otherwise
+///
+/// If `capacity_hint` is provided, it will be passed to [Vec::with_capacity](alloc::vec::Vec::with_capacity)
+pub fn decompress_to_vec_with_options(
+    inp: &[u8],
+    capacity_hint: Option<usize>,
+    opts: &DecompressOptions,
+) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    decompress_impl_to_vec(inp, capacity_hint, opts)
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress the input into a [Vec](alloc::vec::Vec), with additional options,
+/// periodically reporting progress
+///
+/// `progress` is called roughly every [PROGRESS_INTERVAL] bytes of input.
+/// If it returns [ControlFlow::Break], decompression stops with [DecompressError::Cancelled].
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_to_vec_with_progress(
+    inp: &[u8],
+    capacity_hint: Option<usize>,
+    opts: &DecompressOptions,
+    progress: impl FnMut(Progress) -> ControlFlow<()>,
+) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    let mut reporter = ProgressReporter::new(inp.len(), progress);
     let mut ret: VecOutput = if let Some(capacity_hint) = capacity_hint {
         alloc::vec::Vec::with_capacity(capacity_hint)
     } else {
         alloc::vec::Vec::new()
     }
     .into();
-    decompress_impl(inp, &mut ret)?;
+    decompress_impl_with_progress(
+        inp,
+        &mut LimitOutput::new(&mut ret, inp.len(), opts),
+        opts,
+        &mut |pos| cancel_on_break(reporter.update(pos)),
+    )?;
     Ok(ret.vec)
 }
 
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress as much of the input as possible into a [Vec](alloc::vec::Vec)
+///
+/// Rather than discarding the output when a corrupt instruction is encountered,
+/// this returns everything decoded up to that point along with the error.
+/// This can be used to recover data from partially-damaged inputs.
+pub fn decompress_to_vec_lenient(
+    inp: &[u8],
+    opts: &DecompressOptions,
+) -> LenientOutput<alloc::vec::Vec<u8>> {
+    let mut ret: VecOutput = alloc::vec::Vec::new().into();
+    let error = decompress_impl(inp, &mut LimitOutput::new(&mut ret, inp.len(), opts), opts).err();
+    LenientOutput {
+        output: ret.vec,
+        error,
+    }
+}
+
+/// Decompress input split across multiple segments into a preallocated buffer
+///
+/// The segments are treated as one logical stream, so e.g. both halves of a wrapped ring buffer
+/// can be decompressed without first copying them together.
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_segments_to_buf(
+    inp: &[&[u8]],
+    outp: &mut [u8],
+) -> Result<usize, DecompressError> {
+    decompress_segments_to_buf_with_options(inp, outp, &DecompressOptions::default())
+}
+
+/// Decompress input split across multiple segments into a preallocated buffer, with additional options
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_segments_to_buf_with_options(
+    inp: &[&[u8]],
+    outp: &mut [u8],
+    opts: &DecompressOptions,
+) -> Result<usize, DecompressError> {
+    decompress_impl_to_buf(SegmentedInput::new(inp), outp, opts)
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress input split across multiple segments into a [Vec](alloc::vec::Vec)
+///
+/// Returns the result on success, or an error otherwise
+///
+/// If `capacity_hint` is provided, it will be passed to [Vec::with_capacity](alloc::vec::Vec::with_capacity)
+pub fn decompress_segments_to_vec(
+    inp: &[&[u8]],
+    capacity_hint: Option<usize>,
+) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    decompress_segments_to_vec_with_options(inp, capacity_hint, &DecompressOptions::default())
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress input split across multiple segments into a [Vec](alloc::vec::Vec), with additional options
+///
+/// Returns the result on success, or an error otherwise
+///
+/// If `capacity_hint` is provided, it will be passed to [Vec::with_capacity](alloc::vec::Vec::with_capacity)
+pub fn decompress_segments_to_vec_with_options(
+    inp: &[&[u8]],
+    capacity_hint: Option<usize>,
+    opts: &DecompressOptions,
+) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    decompress_impl_to_vec(SegmentedInput::new(inp), capacity_hint, opts)
+}
+
 #[cfg(test)]
 mod tests {
+    // (only the options are left to test without the decompressor)
+    #[cfg_attr(not(feature = "decompress"), allow(unused_imports))]
     use super::*;
 
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_buf_out_lits() {
         {
@@ -275,6 +1229,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_buf_out_backref() {
         {
@@ -285,13 +1240,20 @@ mod tests {
             // invalid, before the start
             assert_eq!(
                 outbuf.put_backref(3, 5),
-                Err(DecompressError::InvalidBackreference)
+                Err(DecompressError::InvalidBackreference {
+                    input_offset: 0,
+                    output_pos: 3,
+                    disp: 3
+                })
             );
 
             // overflow, but should still write up to limit
             assert_eq!(
                 outbuf.put_backref(1, 6),
-                Err(DecompressError::OutputTooSmall)
+                Err(DecompressError::OutputTooSmall {
+                    written: 8,
+                    required: 0
+                })
             );
 
             assert_eq!(outbuf.buf, [1, 2, 3, 2, 3, 2, 3, 2])
@@ -310,7 +1272,49 @@ mod tests {
         // note: we already tested the "hard" case of len > disp
     }
 
+    #[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+    #[test]
+    fn test_uninit_buf_out() {
+        let mut out = [MaybeUninit::uninit(); 8];
+        let mut outbuf: UninitBufOutput = (&mut out[..]).into();
+        outbuf.put_lits(&[1, 2, 3]).unwrap();
+        assert_eq!(
+            outbuf.put_backref(3, 5),
+            Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: 3,
+                disp: 3
+            })
+        );
+        assert_eq!(
+            outbuf.put_backref(1, 6),
+            Err(DecompressError::OutputTooSmall {
+                written: 8,
+                required: 0
+            })
+        );
+        assert_eq!(outbuf.into_init(), [1, 2, 3, 2, 3, 2, 3, 2]);
+
+        let mut out = [MaybeUninit::uninit(); 8];
+        let mut outbuf: UninitBufOutput = (&mut out[..]).into();
+        outbuf.put_lits(&[1, 2]).unwrap();
+        outbuf.put_backref(0, 4).unwrap();
+        assert_eq!(
+            outbuf.put_backref(0, 4),
+            Err(DecompressError::OutputTooSmall {
+                written: 8,
+                required: 0
+            })
+        );
+        assert_eq!(outbuf.into_init(), [1, 2, 2, 2, 2, 2, 2, 2]);
+
+        let mut out = [MaybeUninit::uninit(); 16];
+        let res = decompress_to_uninit_buf(&[0x01, b'A', b'B', 0x20, 0x01], &mut out).unwrap();
+        assert_eq!(res, [b'A', b'B', b'A', b'B', b'A']);
+    }
+
     #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_vec_out_lits() {
         let out = alloc::vec::Vec::new();
@@ -322,6 +1326,7 @@ mod tests {
     }
 
     #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_vec_out_backref() {
         let out = alloc::vec::Vec::new();
@@ -331,6 +1336,310 @@ mod tests {
         assert_eq!(outbuf.vec, [1, 2, 3, 2, 3, 2, 3, 2, 3]);
     }
 
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_vec_out_backref_kinds() {
+        let out = alloc::vec::Vec::new();
+        let mut outbuf: VecOutput = out.into();
+        outbuf.put_lits(&[1, 2, 3]).unwrap();
+        outbuf.put_backref(1, 6).unwrap();
+        // a run, and a copy which doesn't overlap
+        outbuf.put_backref(0, 3).unwrap();
+        outbuf.put_backref(8, 2).unwrap();
+        assert_eq!(outbuf.vec, [1, 2, 3, 2, 3, 2, 3, 2, 3, 3, 3, 3, 2, 3]);
+        assert_eq!(
+            outbuf.put_backref(14, 1),
+            Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: 14,
+                disp: 14
+            })
+        );
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_lenient() {
+        // a literal run, a valid match, then a match reaching before the start
+        let inp = [0x02, 1, 2, 3, 0x20, 0x02, 0x20, 0x10];
+        let mut out = [0u8; 16];
+        assert_eq!(
+            decompress_to_buf_lenient(&inp, &mut out, &DecompressOptions::default()),
+            LenientOutput {
+                output: 6,
+                error: Some(DecompressError::InvalidBackreference {
+                    input_offset: 6,
+                    output_pos: 6,
+                    disp: 16
+                })
+            }
+        );
+        assert_eq!(out[..6], [1, 2, 3, 1, 2, 3]);
+
+        let mut out = [0u8; 6];
+        assert_eq!(
+            decompress_to_buf_lenient(&inp[..6], &mut out, &DecompressOptions::default()),
+            LenientOutput {
+                output: 6,
+                error: None
+            }
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_vec_lenient() {
+        // truncated in the middle of a literal run
+        let inp = [0x02, 1, 2, 3, 0x20, 0x02, 0x04, 4, 5];
+        let res = decompress_to_vec_lenient(&inp, &DecompressOptions::default());
+        assert_eq!(res.output, [1, 2, 3, 1, 2, 3]);
+        assert_eq!(
+            res.error,
+            Some(DecompressError::InputTruncated { input_offset: 6 })
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_vec_max_output_len() {
+        // 3 literals then a backreference of length 264
+        let inp = [0x02, 1, 2, 3, 0xe0, 0xff, 0x00];
+        let opts = DecompressOptions {
+            max_output_len: Some(267),
+            ..Default::default()
+        };
+        let out = decompress_to_vec_with_options(&inp, None, &opts).unwrap();
+        assert_eq!(out.len(), 267);
+
+        let opts = DecompressOptions {
+            max_output_len: Some(266),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_to_vec_with_options(&inp, None, &opts),
+            Err(DecompressError::OutputLimitExceeded { limit: 266 })
+        );
+        let opts = DecompressOptions {
+            max_output_len: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_to_vec_with_options(&inp, None, &opts),
+            Err(DecompressError::OutputLimitExceeded { limit: 2 })
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_vec_max_expansion_ratio() {
+        // 3 literals then a backreference of length 264, from 7 bytes of input
+        let inp = [0x02, 1, 2, 3, 0xe0, 0xff, 0x00];
+        let opts = DecompressOptions {
+            max_expansion_ratio: Some(39),
+            ..Default::default()
+        };
+        let out = decompress_to_vec_with_options(&inp, None, &opts).unwrap();
+        assert_eq!(out.len(), 267);
+
+        let opts = DecompressOptions {
+            max_expansion_ratio: Some(38),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_to_vec_with_options(&inp, None, &opts),
+            Err(DecompressError::ExpansionRatioExceeded { ratio: 38 })
+        );
+        let res = decompress_to_vec_lenient(&inp, &opts);
+        assert_eq!(res.output, [1, 2, 3]);
+
+        // whichever limit is reached first applies
+        let opts = DecompressOptions {
+            max_output_len: Some(100),
+            max_expansion_ratio: Some(38),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_to_vec_with_options(&inp, None, &opts),
+            Err(DecompressError::OutputLimitExceeded { limit: 100 })
+        );
+        let opts = DecompressOptions {
+            max_output_len: Some(1000),
+            max_expansion_ratio: Some(38),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_to_vec_with_options(&inp, None, &opts),
+            Err(DecompressError::ExpansionRatioExceeded { ratio: 38 })
+        );
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_decompress_with_limits() {
+        // 3 literals, a backreference of length 3 and distance 3, then 2 more literals
+        let inp = [0x02, 1, 2, 3, 0x20, 0x02, 0x01, 4, 5];
+        let mut out = [0u8; 8];
+        let limits = DecompressLimits {
+            max_opcodes: Some(3),
+            max_backref_distance: Some(3),
+            max_literal_bytes: Some(5),
+        };
+        assert_eq!(decompress_with_limits(&inp, &mut out, &limits), Ok(8));
+        assert_eq!(out, [1, 2, 3, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            decompress_with_limits(&inp, &mut out, &DecompressLimits::default()),
+            Ok(8)
+        );
+
+        let check = |limits, err| {
+            let mut out = [0u8; 8];
+            assert_eq!(decompress_with_limits(&inp, &mut out, &limits), Err(err));
+        };
+        check(
+            DecompressLimits {
+                max_opcodes: Some(2),
+                ..limits.clone()
+            },
+            DecompressError::OpcodeLimitExceeded {
+                input_offset: 6,
+                limit: 2,
+            },
+        );
+        check(
+            DecompressLimits {
+                max_backref_distance: Some(2),
+                ..limits.clone()
+            },
+            DecompressError::BackreferenceTooFar {
+                input_offset: 4,
+                limit: 2,
+            },
+        );
+        check(
+            DecompressLimits {
+                max_literal_bytes: Some(4),
+                ..limits.clone()
+            },
+            DecompressError::LiteralLimitExceeded {
+                input_offset: 6,
+                limit: 4,
+            },
+        );
+
+        // the size needed is found within the same limits
+        let mut short = [0u8; 4];
+        assert_eq!(
+            decompress_with_limits(&inp, &mut short, &limits),
+            Err(DecompressError::OutputTooSmall {
+                written: 4,
+                required: 8
+            })
+        );
+        let limits = DecompressLimits {
+            max_opcodes: Some(2),
+            ..limits
+        };
+        assert_eq!(
+            decompress_with_limits(&inp, &mut short, &limits),
+            Err(DecompressError::OutputTooSmall {
+                written: 4,
+                required: 6
+            })
+        );
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_decode_tokens() {
+        // 3 literals, a backreference of length 3 and distance 3, then 2 more literals
+        let inp = [0x02, 1, 2, 3, 0x20, 0x02, 0x01, 4, 5];
+        let mut tokens = [None; 4];
+        let mut n = 0;
+        let res = decode_tokens(&inp, |t| {
+            tokens[n] = Some(t);
+            n += 1;
+        });
+        assert_eq!(res, Ok(8));
+        assert_eq!(
+            tokens,
+            [
+                Some(Token::Literals {
+                    input_offset: 0,
+                    output_pos: 0,
+                    len: 3
+                }),
+                Some(Token::Backreference {
+                    input_offset: 4,
+                    output_pos: 3,
+                    disp: 2,
+                    len: 3
+                }),
+                Some(Token::Literals {
+                    input_offset: 6,
+                    output_pos: 6,
+                    len: 2
+                }),
+                None
+            ]
+        );
+
+        // instructions before an invalid one are still listed
+        let mut n = 0;
+        assert_eq!(
+            decode_tokens(&[0x00, 1, 0x20, 5], |_| n += 1),
+            Err(DecompressError::InvalidBackreference {
+                input_offset: 2,
+                output_pos: 1,
+                disp: 5
+            })
+        );
+        assert_eq!(n, 1);
+        assert_eq!(decode_tokens(&[], |_| unreachable!()), Ok(0));
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_opcode_budget() {
+        // a million backreferences of length 3 to the previous byte, 2 bytes each
+        let mut inp = std::vec![0x00, 0x55];
+        for _ in 0..1_000_000 {
+            inp.extend_from_slice(&[0x20, 0x00]);
+        }
+        let mut out = std::vec![0u8; 1 + 3 * 1_000_000];
+        let mut limits = DecompressLimits {
+            max_opcodes: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            decompress_with_limits(&inp, &mut out, &limits),
+            Err(DecompressError::OpcodeLimitExceeded {
+                input_offset: 2000,
+                limit: 1000
+            })
+        );
+        // (also when the output is too small, where the scan for the size needed stops too)
+        assert_eq!(
+            decompress_with_limits(&inp, &mut out[..10], &limits),
+            Err(DecompressError::OutputTooSmall {
+                written: 10,
+                required: 1 + 3 * 999
+            })
+        );
+
+        limits.max_opcodes = Some(1_000_001);
+        assert_eq!(
+            decompress_with_limits(&inp, &mut out, &limits),
+            Ok(out.len())
+        );
+        assert!(out.iter().all(|&b| b == 0x55));
+    }
+
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv1_manual_lits() {
         let mut out = [0u8; 5];
@@ -339,6 +1648,7 @@ mod tests {
         assert_eq!(out, [b'A', b'B', b'C', b'D', b'E']);
     }
 
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv1_manual_short_match() {
         let mut out = [0u8; 5];
@@ -347,6 +1657,7 @@ mod tests {
         assert_eq!(out, [b'A', b'B', b'A', b'B', b'A']);
     }
 
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv1_manual_long_match() {
         let mut out = [0u8; 11];
@@ -359,7 +1670,9 @@ mod tests {
     }
 
     #[cfg(feature = "std")]
+    #[cfg(feature = "decompress")]
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_lv1_against_ref() {
         extern crate std;
 
@@ -368,7 +1681,7 @@ mod tests {
 
         let inp = std::fs::read(inp_fn).unwrap();
 
-        let mut reference = crate::wasmtester::FastLZWasm::new();
+        let mut reference = crate::TestReference::new();
         let ref_ = reference.fastlz_compress_level(1, &inp);
         std::println!("{:02x?}", &ref_[..8]);
 
@@ -376,6 +1689,91 @@ mod tests {
         assert_eq!(inp, out);
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_error_offsets() {
+        let mut out = [0u8; 8];
+        assert_eq!(
+            decompress_to_buf(&[0x01, b'A', b'B', 0x20, 0x05], &mut out),
+            Err(DecompressError::InvalidBackreference {
+                input_offset: 3,
+                output_pos: 2,
+                disp: 5
+            })
+        );
+        assert_eq!(
+            decompress_to_buf(&[0x21, b'A', b'B', 0x02, b'C'], &mut out),
+            Err(DecompressError::InputTruncated { input_offset: 3 })
+        );
+        assert_eq!(
+            decompress_segments_to_buf(&[&[0x01, b'A'], &[b'B', 0xe0, 0x00]], &mut out),
+            Err(DecompressError::InputTruncated { input_offset: 3 })
+        );
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_output_too_small_required() {
+        let mut out = [0u8; 4];
+        assert_eq!(
+            decompress_to_buf(&[0x01, b'A', b'B', 0x40, 0x01, 0x00, b'C'], &mut out),
+            Err(DecompressError::OutputTooSmall {
+                written: 4,
+                required: 7
+            })
+        );
+        assert_eq!(
+            decompress_segments_to_buf(&[&[0x01, b'A'], &[b'B', 0x40, 0x01, 0x00, b'C']], &mut out),
+            Err(DecompressError::OutputTooSmall {
+                written: 4,
+                required: 7
+            })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "compress")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_progress() {
+        // poorly-compressible data
+        let inp: std::vec::Vec<u8> = random_bytes().take(300_000).collect();
+        let comp = crate::CompressState::new()
+            .compress_to_vec(&inp, CompressionLevel::Level2)
+            .unwrap();
+        let opts = DecompressOptions::default();
+
+        let mut reports = std::vec::Vec::new();
+        let decomp = decompress_to_vec_with_progress(&comp, None, &opts, |p| {
+            assert_eq!(p.input_len, comp.len());
+            reports.push(p.input_processed);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(decomp, inp);
+        assert_eq!(reports.len(), comp.len() / PROGRESS_INTERVAL);
+        assert!(reports.is_sorted());
+
+        let mut out = std::vec![0u8; inp.len()];
+        let mut calls = 0;
+        assert_eq!(
+            decompress_to_buf_with_progress(&comp, &mut out, &opts, |p| {
+                calls += 1;
+                if p.input_processed > 2 * PROGRESS_INTERVAL {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }),
+            Err(DecompressError::Cancelled)
+        );
+        assert_eq!(calls, 2);
+    }
+
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv2_manual_short_match() {
         let mut out = [0u8; 5];
@@ -384,6 +1782,8 @@ mod tests {
         assert_eq!(out, [b'A', b'B', b'A', b'B', b'A']);
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv2_manual_long_match() {
         let mut out = [0u8; 11];
@@ -395,6 +1795,8 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
     fn test_lv2_manual_verylong_match() {
         let mut out = [0u8; 266];
@@ -406,7 +1808,10 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
+    #[allow(clippy::needless_range_loop, clippy::if_same_then_else)]
     fn test_lv2_manual_verylong_disp() {
         let mut out = [0u8; 0x2004];
         let len = decompress_to_buf(
@@ -433,8 +1838,83 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_lv2_far_backref_at_end() {
+        let inp = [
+            0x21, b'A', 0x00, 0xE0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x15, 0x00, 0x3F, 0xFF, 0x00, 0x00,
+            0x00, b'Z',
+        ];
+        let mut out = [0u8; 0x2004];
+        let mut opts = DecompressOptions::default();
+
+        // lenient by default
+        let len = decompress_to_buf_with_options(&inp[..inp.len() - 2], &mut out, &opts).unwrap();
+        assert_eq!(len, 0x2003);
+
+        opts.strict_l2_end = true;
+        assert_eq!(
+            decompress_to_buf_with_options(&inp[..inp.len() - 2], &mut out, &opts),
+            Err(DecompressError::FarBackreferenceAtEnd)
+        );
+        let len = decompress_to_buf_with_options(&inp, &mut out, &opts).unwrap();
+        assert_eq!(len, 0x2004);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use serde::de::{value, IntoDeserializer};
+        use serde::Deserialize;
+
+        let de: value::StrDeserializer<value::Error> =
+            "InvalidCompressionLevel".into_deserializer();
+        assert_eq!(
+            DecompressError::deserialize(de),
+            Ok(DecompressError::InvalidCompressionLevel)
+        );
+    }
+
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn test_segmented_input() {
+        let inp = [
+            0x21, b'A', 0x00, 0xE0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x15, 0x00, 0x3F, 0xFF, 0x00, 0x00,
+            0x02, b'X', b'Y', b'Z',
+        ];
+        let mut expected = [0u8; 0x2006];
+        let expected_len = decompress_to_buf(&inp, &mut expected).unwrap();
+
+        let mut out = [0u8; 0x2006];
+        for i in 0..=inp.len() {
+            for j in i..=inp.len() {
+                let len =
+                    decompress_segments_to_buf(&[&inp[..i], &inp[i..j], &[], &inp[j..]], &mut out)
+                        .unwrap();
+                assert_eq!(len, expected_len);
+                assert_eq!(out, expected);
+            }
+        }
+
+        // literals truncated across a segment boundary
+        assert_eq!(
+            decompress_segments_to_buf(&[&[0x02, b'X'], b"Y"], &mut out),
+            Err(DecompressError::InputTruncated { input_offset: 0 })
+        );
+        assert_eq!(decompress_segments_to_buf(&[&[], &[]], &mut out), Ok(0));
+    }
+
     #[cfg(feature = "std")]
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "decompress")]
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_lv2_against_ref() {
         extern crate std;
 
@@ -443,7 +1923,7 @@ mod tests {
 
         let inp = std::fs::read(inp_fn).unwrap();
 
-        let mut reference = crate::wasmtester::FastLZWasm::new();
+        let mut reference = crate::TestReference::new();
         let ref_ = reference.fastlz_compress_level(2, &inp);
         std::println!("{:02x?}", &ref_[..8]);
 
@@ -451,3 +1931,77 @@ mod tests {
         assert_eq!(inp, out);
     }
 }
+
+/// Model-checking harnesses, run with [Kani](https://github.com/model-checking/kani) (`cargo kani`)
+///
+/// For every input up to `MAX_INP` bytes and every output buffer up to `MAX_OUT` bytes,
+/// these prove that decompression never panics (so every index and slice is in bounds
+/// and nothing overflows), that the unsafe output paths never touch memory outside of
+/// the output buffer, and that the reported length fits within the buffer.
+#[cfg(all(kani, feature = "decompress"))]
+mod verification {
+    use super::*;
+
+    /// Longest input checked
+    const MAX_INP: usize = 8;
+    /// Largest output buffer checked
+    const MAX_OUT: usize = 16;
+
+    /// A prefix of arbitrary length of `buf`
+    fn any_prefix<T>(buf: &mut [T]) -> &mut [T] {
+        let len = kani::any_where(|&len| len <= buf.len());
+        &mut buf[..len]
+    }
+
+    #[kani::proof]
+    #[kani::unwind(18)]
+    fn check_decompress_to_buf() {
+        let mut inp: [u8; MAX_INP] = kani::any();
+        let mut out = [0u8; MAX_OUT];
+        let out = any_prefix(&mut out);
+        if let Ok(len) = decompress_to_buf(any_prefix(&mut inp), out) {
+            assert!(len <= out.len());
+        }
+    }
+
+    #[cfg(feature = "unsafe-perf")]
+    #[kani::proof]
+    #[kani::unwind(18)]
+    fn check_decompress_to_uninit_buf() {
+        let mut inp: [u8; MAX_INP] = kani::any();
+        let mut out = [MaybeUninit::uninit(); MAX_OUT];
+        let out = any_prefix(&mut out);
+        let out_len = out.len();
+        if let Ok(init) = decompress_to_uninit_buf(any_prefix(&mut inp), out) {
+            assert!(init.len() <= out_len);
+        }
+    }
+
+    #[kani::proof]
+    #[kani::unwind(18)]
+    fn check_decompress_to_buf_lenient() {
+        let mut inp: [u8; MAX_INP] = kani::any();
+        let opts = DecompressOptions {
+            strict_l2_end: kani::any(),
+            ..Default::default()
+        };
+        let mut out = [0u8; MAX_OUT];
+        let out = any_prefix(&mut out);
+        let res = decompress_to_buf_lenient(any_prefix(&mut inp), out, &opts);
+        assert!(res.output <= out.len());
+    }
+
+    #[kani::proof]
+    #[kani::unwind(18)]
+    fn check_decompress_segments_to_buf() {
+        let inp: [u8; MAX_INP] = kani::any();
+        let split = kani::any_where(|&split| split <= MAX_INP);
+        let (a, b) = inp.split_at(split);
+        let b = &b[..kani::any_where(|&len| len <= b.len())];
+        let mut out = [0u8; MAX_OUT];
+        let out = any_prefix(&mut out);
+        if let Ok(len) = decompress_segments_to_buf(&[a, b], out) {
+            assert!(len <= out.len());
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Cheap prediction of how well data will compress
+
+/// Size of each window of the input which is examined
+const WINDOW_SZ: usize = 8192;
+/// Maximum number of windows which are examined
+const MAX_WINDOWS: usize = 8;
+
+const PROBE_HTAB_LOG2: u32 = 12;
+
+/// Order-0 entropy (in bits per byte) above which data is considered incompressible without probing
+const MAX_ENTROPY: f32 = 7.9;
+
+/// Ratio achieved when the entire input has to be stored as literals
+const LITERAL_RATIO: f32 = 32.0 / 33.0;
+
+/// Fixed-point log2 of `x`, with 16 fractional bits
+fn log2_fixed(x: u32) -> u64 {
+    debug_assert!(x > 0);
+    let int = x.ilog2();
+    // normalize to [1, 2) with 31 fractional bits, then extract one bit at a time by squaring
+    let mut m = (x as u64) << (31 - int);
+    let mut frac = 0;
+    for _ in 0..16 {
+        m = (m * m) >> 31;
+        frac <<= 1;
+        if m >= 2 << 31 {
+            m >>= 1;
+            frac |= 1;
+        }
+    }
+    ((int as u64) << 16) | frac
+}
+
+/// Predict the ratio (size of the input divided by size of the output) that compression will achieve
+///
+/// Up to 64 KiB of the input is sampled, spread evenly across it.
+/// If the bytes are close to uniformly distributed (e.g. media or already-compressed files),
+/// the data is assumed to be incompressible. Otherwise the samples are searched for matches
+/// using a smaller and simpler hash table than the real compressor.
+///
+/// Matches further apart than the sample windows cannot be found, so this tends to underestimate
+/// the ratio of highly repetitive inputs. It is intended for quickly deciding whether to bother
+/// compressing at all, e.g. skipping anything predicted to be below 1.1.
+///
+/// An empty input is considered to have a ratio of 1.
+pub fn estimate_compressibility(inp: &[u8]) -> f32 {
+    if inp.is_empty() {
+        return 1.0;
+    }
+
+    let nwindows = usize::min(inp.len().div_ceil(WINDOW_SZ), MAX_WINDOWS);
+    let stride = if nwindows > 1 {
+        (inp.len() - WINDOW_SZ) / (nwindows - 1)
+    } else {
+        0
+    };
+    let windows = (0..nwindows).map(|i| {
+        let start = i * stride;
+        &inp[start..usize::min(start + WINDOW_SZ, inp.len())]
+    });
+
+    let mut hist = [0u32; 256];
+    let mut total = 0u32;
+    for window in windows.clone() {
+        for &b in window {
+            hist[b as usize] += 1;
+        }
+        total += window.len() as u32;
+    }
+
+    // entropy * total = total * log2(total) - sum(count * log2(count))
+    let mut sum = 0;
+    for &c in &hist {
+        if c != 0 {
+            sum += c as u64 * log2_fixed(c);
+        }
+    }
+    let entropy_total = (total as u64 * log2_fixed(total)).saturating_sub(sum);
+    let entropy = entropy_total as f32 / (total as f32 * 65536.0);
+    if entropy > MAX_ENTROPY {
+        return LITERAL_RATIO;
+    }
+
+    let mut est_size = 0;
+    for window in windows {
+        est_size += probe_window(window);
+    }
+    total as f32 / est_size as f32
+}
+
+/// Search for matches within a window, returning the estimated compressed size
+fn probe_window(window: &[u8]) -> usize {
+    let mut htab = [0u16; 1 << PROBE_HTAB_LOG2];
+    // the first byte is always a literal
+    let mut lits: usize = 1;
+    let mut size = 0;
+
+    let mut pos = 1;
+    while pos + 4 <= window.len() {
+        let v = u32::from_le_bytes(window[pos..pos + 4].try_into().unwrap()) & 0xffffff;
+        let hash = (v.wrapping_mul(2654435769) >> (32 - PROBE_HTAB_LOG2)) as usize;
+        let ref_pos = htab[hash] as usize;
+        htab[hash] = pos as u16;
+
+        if window[ref_pos..ref_pos + 3] == window[pos..pos + 3] {
+            let len = 3 + window[ref_pos + 3..]
+                .iter()
+                .zip(&window[pos + 3..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            // literal opcodes, then a (typically) two byte backreference
+            size += lits + lits.div_ceil(32) + 2;
+            lits = 0;
+            pos += len;
+        } else {
+            lits += 1;
+            pos += 1;
+        }
+    }
+    lits += window.len() - pos;
+    size + lits + lits.div_ceil(32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log2_fixed() {
+        assert_eq!(log2_fixed(1), 0);
+        assert_eq!(log2_fixed(2), 1 << 16);
+        assert_eq!(log2_fixed(1024), 10 << 16);
+        // log2(3) = 1.5849625
+        assert!(log2_fixed(3).abs_diff(103872) <= 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_estimate() {
+        extern crate std;
+        use crate::util::random_bytes;
+        use crate::{CompressState, CompressionLevel};
+
+        assert_eq!(estimate_compressibility(&[]), 1.0);
+        assert!(estimate_compressibility(&[0; 100_000]) > 10.0);
+
+        let random: std::vec::Vec<u8> = random_bytes().take(100_000).collect();
+        assert_eq!(estimate_compressibility(&random), LITERAL_RATIO);
+
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        let actual = src.len() as f32
+            / CompressState::new()
+                .compress_to_vec(&src, CompressionLevel::Level1)
+                .unwrap()
+                .len() as f32;
+        let estimate = estimate_compressibility(&src);
+        assert!(estimate > 1.5);
+        assert!(estimate > actual * 0.75 && estimate < actual * 1.25);
+    }
+}
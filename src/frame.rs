@@ -0,0 +1,191 @@
+//! Size-prefixed frames
+//!
+//! A frame is a 4-byte little-endian uncompressed length followed by a normal FastLZ stream.
+//! Knowing the size up front lets the decompressor allocate its output exactly once.
+
+#[cfg(feature = "compress")]
+use crate::compress::*;
+#[cfg(feature = "decompress")]
+use crate::decompress::*;
+#[cfg(feature = "compress")]
+use crate::level::CompressionLevel;
+#[cfg(feature = "decompress")]
+use crate::util::saturating_usize;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+/// Number of bytes in the frame header
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Upper bound on the number of output bytes a single input byte can expand to
+///
+/// (Each extended length byte of a level 2 backreference adds up to 255 bytes of output.)
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+const MAX_EXPANSION: usize = 256;
+
+/// Read the uncompressed size stored in a frame header
+///
+/// Returns the uncompressed size along with the compressed payload following the header
+///
+/// (On 16-bit targets, sizes which don't fit in a `usize` are returned as `usize::MAX`,
+/// which decompressing the payload can then never match.)
+#[cfg(feature = "decompress")]
+pub fn frame_decompressed_len(inp: &[u8]) -> Result<(usize, &[u8]), DecompressError> {
+    let (hdr, payload) = inp
+        .split_first_chunk::<FRAME_HEADER_LEN>()
+        .ok_or(DecompressError::InputTruncated { input_offset: 0 })?;
+    Ok((saturating_usize(u32::from_le_bytes(*hdr)), payload))
+}
+
+#[cfg(feature = "compress")]
+impl<T: HashTableStorage> GenericCompressState<T> {
+    /// Compress the input into a preallocated buffer, prefixed with a frame header
+    ///
+    /// Returns the total framed size on success, or an error otherwise
+    pub fn compress_framed_to_buf(
+        &mut self,
+        inp: &[u8],
+        outp: &mut [u8],
+        level: CompressionLevel,
+    ) -> Result<usize, CompressError> {
+        let hdr = u32::try_from(inp.len())
+            .map_err(|_| CompressError::InputTooLarge)?
+            .to_le_bytes();
+        if outp.len() < FRAME_HEADER_LEN {
+            return Err(CompressError::OutputTooSmall {
+                written: 0,
+                input_processed: 0,
+                remaining: FRAME_HEADER_LEN + compress_bound(inp.len()),
+            });
+        }
+        outp[..FRAME_HEADER_LEN].copy_from_slice(&hdr);
+        let len = self
+            .compress_to_buf(inp, &mut outp[FRAME_HEADER_LEN..], level)
+            .map_err(|e| match e {
+                CompressError::OutputTooSmall {
+                    written,
+                    input_processed,
+                    remaining,
+                } => CompressError::OutputTooSmall {
+                    written: FRAME_HEADER_LEN + written,
+                    input_processed,
+                    remaining,
+                },
+                e => e,
+            })?;
+        Ok(FRAME_HEADER_LEN + len)
+    }
+
+    #[cfg(feature = "alloc")]
+    /// Compress the input into a [Vec](alloc::vec::Vec), prefixed with a frame header
+    ///
+    /// Returns the result on success, or an error otherwise
+    pub fn compress_framed_to_vec(
+        &mut self,
+        inp: &[u8],
+        level: CompressionLevel,
+    ) -> Result<alloc::vec::Vec<u8>, CompressError> {
+        let hdr = u32::try_from(inp.len())
+            .map_err(|_| CompressError::InputTooLarge)?
+            .to_le_bytes();
+        let mut ret = alloc::vec::Vec::new();
+        ret.extend_from_slice(&hdr);
+        ret.extend_from_slice(&self.compress_to_vec(inp, level)?);
+        Ok(ret)
+    }
+}
+
+#[cfg(feature = "decompress")]
+fn check_frame_size(expected: usize, actual: usize) -> Result<(), DecompressError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(DecompressError::FrameSizeMismatch)
+    }
+}
+
+/// Decompress a framed input into a preallocated buffer
+///
+/// Returns the actual decompressed size on success, or an error otherwise
+#[cfg(feature = "decompress")]
+pub fn decompress_framed_to_buf(inp: &[u8], outp: &mut [u8]) -> Result<usize, DecompressError> {
+    let (expected, payload) = frame_decompressed_len(inp)?;
+    let len = decompress_to_buf(payload, outp)?;
+    check_frame_size(expected, len)?;
+    Ok(len)
+}
+
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+/// Decompress a framed input into a [Vec](alloc::vec::Vec)
+///
+/// The output is allocated exactly once, using the size stored in the frame header.
+/// (Sizes that the payload cannot possibly expand to are not trusted.)
+///
+/// Returns the result on success, or an error otherwise
+pub fn decompress_framed_to_vec(inp: &[u8]) -> Result<alloc::vec::Vec<u8>, DecompressError> {
+    let (expected, payload) = frame_decompressed_len(inp)?;
+    let capacity = usize::min(expected, payload.len().saturating_mul(MAX_EXPANSION));
+    let ret = decompress_to_vec(payload, Some(capacity))?;
+    check_frame_size(expected, ret.len())?;
+    Ok(ret)
+}
+
+#[cfg(all(test, feature = "decompress"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_header() {
+        assert_eq!(
+            frame_decompressed_len(&[0x05, 0x00, 0x00, 0x00, 0x04]),
+            Ok((5, &[0x04][..]))
+        );
+        assert_eq!(
+            frame_decompressed_len(&[0x05, 0x00, 0x00]),
+            Err(DecompressError::InputTruncated { input_offset: 0 })
+        );
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn test_framed_buf_roundtrip() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut state = CompressState::new();
+        let mut comp = [0u8; 12];
+        let len = state
+            .compress_framed_to_buf(&inp, &mut comp, CompressionLevel::Level1)
+            .unwrap();
+        assert_eq!(len, comp.len());
+        assert_eq!(comp[..4], [10, 0, 0, 0]);
+
+        let mut out = [0u8; 10];
+        let len = decompress_framed_to_buf(&comp, &mut out).unwrap();
+        assert_eq!(len, out.len());
+        assert_eq!(out, inp);
+    }
+
+    #[test]
+    fn test_framed_size_mismatch() {
+        let mut out = [0u8; 8];
+        assert_eq!(
+            decompress_framed_to_buf(&[0x03, 0x00, 0x00, 0x00, 0x01, 1, 2], &mut out),
+            Err(DecompressError::FrameSizeMismatch)
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[cfg(feature = "level2")]
+    #[cfg(feature = "compress")]
+    #[test]
+    fn test_framed_vec_exact_alloc() {
+        let inp = [7u8; 1000];
+        let mut state = CompressState::new();
+        let comp = state
+            .compress_framed_to_vec(&inp, CompressionLevel::Level2)
+            .unwrap();
+        let out = decompress_framed_to_vec(&comp).unwrap();
+        assert_eq!(out, inp);
+        assert_eq!(out.capacity(), inp.len());
+    }
+}
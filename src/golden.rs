@@ -0,0 +1,298 @@
+//! Checked-in test vectors, pinning the format independently of the reference the other tests use
+//!
+//! Each vector is an input `tests/vectors/<name>.raw` along with its compressed form,
+//! `<name>.lv1` or `<name>.lv2`. Most were produced by the reference C encoder,
+//! and have to be reproduced exactly with [CompressOptions::c_compatible].
+//! The rest are written by hand, for streams which the reference never produces.
+//!
+//! The output of the other compression options is pinned too, by its hash. None of this
+//! should depend on the target, which can be checked on big-endian and 32-bit targets with e.g.
+//! `cross test --target powerpc64-unknown-linux-gnu` or `cross test --target i686-unknown-linux-gnu`.
+
+extern crate std;
+
+use crate::*;
+
+struct Vector {
+    name: &'static str,
+    level: CompressionLevel,
+    raw: &'static [u8],
+    compressed: &'static [u8],
+}
+
+macro_rules! vector {
+    ($name:literal, $ext:literal, $level:ident) => {
+        Vector {
+            name: concat!($name, ".", $ext),
+            level: CompressionLevel::$level,
+            raw: include_bytes!(concat!("../tests/vectors/", $name, ".raw")),
+            compressed: include_bytes!(concat!("../tests/vectors/", $name, ".", $ext)),
+        }
+    };
+}
+
+/// Output of the reference encoder
+const REFERENCE: &[Vector] = &[
+    // (no opcodes at all, so the same at either level)
+    vector!("empty", "lv1", Level1),
+    vector!("tiny", "lv1", Level1),
+    vector!("literals", "lv1", Level1),
+    vector!("text", "lv1", Level1),
+    // matches split at the maximum level 1 length
+    vector!("run", "lv1", Level1),
+    vector!("exact_264", "lv1", Level1),
+    // 8192 bytes back is just out of reach of the level 1 encoder (though not of the format)
+    vector!("distance_8192", "lv1", Level1),
+    vector!("far", "lv1", Level1),
+    vector!("far_long", "lv1", Level1),
+    #[cfg(feature = "level2")]
+    vector!("tiny", "lv2", Level2),
+    #[cfg(feature = "level2")]
+    vector!("literals", "lv2", Level2),
+    #[cfg(feature = "level2")]
+    vector!("text", "lv2", Level2),
+    // extended length
+    #[cfg(feature = "level2")]
+    vector!("run", "lv2", Level2),
+    #[cfg(feature = "level2")]
+    vector!("exact_264", "lv2", Level2),
+    // the shortest extended displacement (with both extra bytes 0)
+    #[cfg(feature = "level2")]
+    vector!("distance_8192", "lv2", Level2),
+    // extended displacement
+    #[cfg(feature = "level2")]
+    vector!("far", "lv2", Level2),
+    // extended length and displacement in the same match
+    #[cfg(feature = "level2")]
+    vector!("far_long", "lv2", Level2),
+];
+
+/// Valid streams which the reference encoder does not produce
+#[cfg(feature = "decompress")]
+const HAND_WRITTEN: &[Vector] = &[
+    // single literals and a short overlapping match, where the encoder would use longer runs
+    vector!("split_ops", "lv1", Level1),
+    // the farthest level 1 match the format allows
+    vector!("max_distance", "lv1", Level1),
+];
+
+#[cfg(feature = "decompress")]
+#[test]
+fn test_golden_decompress() {
+    for v in REFERENCE.iter().chain(HAND_WRITTEN) {
+        if !v.compressed.is_empty() {
+            assert_eq!(
+                CompressionLevel::of_compressed(v.compressed),
+                Ok(v.level),
+                "{}",
+                v.name
+            );
+        }
+        let mut out = std::vec![0u8; v.raw.len()];
+        assert_eq!(
+            decompress_to_buf(v.compressed, &mut out),
+            Ok(v.raw.len()),
+            "{}",
+            v.name
+        );
+        assert!(out == v.raw, "{} decompressed incorrectly", v.name);
+    }
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn test_golden_compress() {
+    let opts = CompressOptions {
+        c_compatible: true,
+        ..Default::default()
+    };
+    let mut state = CompressState::new();
+    for v in REFERENCE {
+        let comp = state
+            .compress_to_vec_with_options(v.raw, v.level, &opts)
+            .unwrap();
+        assert!(comp == v.compressed, "{} compressed incorrectly", v.name);
+    }
+}
+
+/// FNV-1a, to keep the expected outputs of [test_golden_compress_options] short
+#[cfg(feature = "compress")]
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn test_golden_compress_options() {
+    let mut inp = std::vec::Vec::new();
+    // (each input appears at both levels, but those at level 2 might not be built)
+    for v in REFERENCE
+        .iter()
+        .filter(|v| v.level == CompressionLevel::Level1)
+    {
+        inp.extend_from_slice(v.raw);
+    }
+    // and some text-like input, with matches at all sorts of distances
+    const WORDS: &[&[u8]] = &[
+        b"the ",
+        b"quick ",
+        b"brown ",
+        b"fox ",
+        b"jumps ",
+        b"over ",
+        b"lazy ",
+        b"dog",
+        b". ",
+        b"compression ",
+        b"level ",
+        b"match ",
+        b"literal ",
+        b"and ",
+        b"of ",
+        b"0x",
+        b"\n",
+    ];
+    let mut x: u32 = 1;
+    while inp.len() < 150_000 {
+        x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+        inp.extend_from_slice(WORDS[(x >> 16) as usize % WORDS.len()]);
+    }
+
+    let cases: &[(&str, CompressionLevel, CompressOptions, usize, u64)] = &[
+        (
+            "default",
+            CompressionLevel::Level1,
+            CompressOptions::default(),
+            77986,
+            0x39cc7883cc82b170,
+        ),
+        (
+            "lazy",
+            CompressionLevel::Level1,
+            CompressOptions {
+                lazy_matching: true,
+                ..Default::default()
+            },
+            77922,
+            0xd4852c7023b105f8,
+        ),
+        (
+            "two entry buckets",
+            CompressionLevel::Level1,
+            CompressOptions {
+                two_entry_buckets: true,
+                ..Default::default()
+            },
+            76826,
+            0xe8c6f4e999317aa9,
+        ),
+        (
+            "acceleration",
+            CompressionLevel::Level1,
+            CompressOptions {
+                acceleration: 4,
+                ..Default::default()
+            },
+            78188,
+            0x15e0dca7ae893f67,
+        ),
+        (
+            "hash chains",
+            CompressionLevel::Level1,
+            CompressOptions {
+                max_chain: 16,
+                ..Default::default()
+            },
+            69753,
+            0xcbb726c3bac1c384,
+        ),
+        (
+            "optimal parse",
+            CompressionLevel::Level1,
+            CompressOptions {
+                max_chain: 16,
+                optimal_parse: true,
+                ..Default::default()
+            },
+            66822,
+            0xc37e9e19e521aebd,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "default",
+            CompressionLevel::Level2,
+            CompressOptions::default(),
+            75559,
+            0xf958ed3023de2767,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "lazy",
+            CompressionLevel::Level2,
+            CompressOptions {
+                lazy_matching: true,
+                ..Default::default()
+            },
+            75475,
+            0x28dd9245745e5222,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "hash chains",
+            CompressionLevel::Level2,
+            CompressOptions {
+                max_chain: 16,
+                ..Default::default()
+            },
+            67875,
+            0x319a50d3d4a03554,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "optimal parse",
+            CompressionLevel::Level2,
+            CompressOptions {
+                max_chain: 16,
+                optimal_parse: true,
+                ..Default::default()
+            },
+            64446,
+            0x58bb8d2e02305b13,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "sampled",
+            CompressionLevel::Default,
+            CompressOptions {
+                sample_default_level: true,
+                ..Default::default()
+            },
+            77986,
+            0x39cc7883cc82b170,
+        ),
+        #[cfg(feature = "level2")]
+        (
+            "best",
+            CompressionLevel::Best,
+            CompressOptions::default(),
+            75559,
+            0xf958ed3023de2767,
+        ),
+    ];
+
+    let mut state = CompressState::new();
+    for (name, level, opts, len, hash) in cases {
+        let comp = state
+            .compress_to_vec_with_options(&inp, *level, opts)
+            .unwrap();
+        assert_eq!(
+            (comp.len(), fnv1a(&comp)),
+            (*len, *hash),
+            "{} {:?}",
+            name,
+            level
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use crate::accel::common_prefix_len;
+use crate::util::*;
+
+/// Furthest distance behind the current position that the compressor will ever read from
+pub const MAX_LOOKBACK: usize = saturating_usize(8191 + 65535 + 1);
+
+/// Source of uncompressed bytes which cannot be exposed as one contiguous slice
+///
+/// This allows compressing e.g. paged external flash or a split ring buffer.
+/// The compressor requests small windows of bytes on demand.
+/// Once a read starting at position `p` has been requested, no later read will start before
+/// `p - MAX_LOOKBACK`, so only that much history needs to be kept available.
+/// (The exceptions are [CompressionLevel::Best](crate::CompressionLevel::Best),
+/// which reads through the entire input multiple times,
+/// [CompressOptions::sample_default_level](crate::CompressOptions::sample_default_level),
+/// which samples the input before starting over from the beginning,
+/// `CompressOptions::optimal_parse`, which looks for matches throughout a block of the input
+/// before going back to decide which to use, and the `paranoid` feature, which reads through
+/// the input again afterwards to check the output.)
+pub trait InputSource {
+    /// Total length of the input
+    fn len(&self) -> usize;
+    /// Whether the input is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Fill `buf` with the bytes starting at `pos`
+    ///
+    /// The requested range will always be within the input.
+    fn read(&mut self, pos: usize, buf: &mut [u8]);
+}
+
+/// Internal abstraction over the input to the compressor (slice vs [InputSource])
+pub(crate) trait CompressInput {
+    /// Whether literals must be emitted eagerly to keep lookback bounded
+    const EAGER_LITS: bool;
+
+    fn len(&self) -> usize;
+    /// Read 4 bytes as a little-endian integer, or None if there aren't 4 bytes left
+    fn peek4(&mut self, pos: usize) -> Option<u32>;
+    /// Count the number of identical bytes (up to `max`) starting at `a` and `b`
+    fn match_len(&mut self, a: usize, b: usize, max: usize) -> usize;
+    /// Output the bytes from `start` to `end` as literals
+    fn put_lits<E>(
+        &mut self,
+        start: usize,
+        end: usize,
+        outp: &mut impl OutputSink<E>,
+    ) -> Result<(), E>;
+}
+
+impl CompressInput for &[u8] {
+    const EAGER_LITS: bool = false;
+
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+    fn peek4(&mut self, pos: usize) -> Option<u32> {
+        // skipping ahead may go past the end
+        let ret = u32::from_le_bytes(*self.get(pos..)?.first_chunk::<4>()?);
+        Some(ret)
+    }
+    fn match_len(&mut self, a: usize, b: usize, max: usize) -> usize {
+        // (the compressor only asks for what is there, but clamp rather than risk a panic)
+        let a = self.get(a..).unwrap_or_default();
+        let b = self.get(b..).unwrap_or_default();
+        common_prefix_len(a.get(..max).unwrap_or(a), b)
+    }
+    fn put_lits<E>(
+        &mut self,
+        start: usize,
+        end: usize,
+        outp: &mut impl OutputSink<E>,
+    ) -> Result<(), E> {
+        debug_assert!(start <= end && end <= self.len());
+        outp.put_lits(self.get(start..end).unwrap_or_default())
+    }
+}
+
+/// Treats a list of slices as one logical input
+impl InputSource for &[&[u8]] {
+    fn len(&self) -> usize {
+        self.iter().map(|s| s.len()).sum()
+    }
+    fn read(&mut self, mut pos: usize, mut buf: &mut [u8]) {
+        for seg in self.iter() {
+            if buf.is_empty() {
+                break;
+            }
+            if pos >= seg.len() {
+                pos -= seg.len();
+                continue;
+            }
+            let n = usize::min(buf.len(), seg.len() - pos);
+            buf[..n].copy_from_slice(&seg[pos..pos + n]);
+            buf = &mut buf[n..];
+            pos = 0;
+        }
+    }
+}
+
+/// Adapts an [InputSource] to the compressor by reading small chunks at a time
+pub(crate) struct SourceInput<'a, S: ?Sized> {
+    src: &'a mut S,
+    len: usize,
+}
+impl<'a, S: InputSource + ?Sized> SourceInput<'a, S> {
+    pub(crate) fn new(src: &'a mut S) -> Self {
+        let len = src.len();
+        Self { src, len }
+    }
+}
+
+impl<S: InputSource + ?Sized> CompressInput for SourceInput<'_, S> {
+    const EAGER_LITS: bool = true;
+
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn peek4(&mut self, pos: usize) -> Option<u32> {
+        if pos + 4 > self.len {
+            return None;
+        }
+        let mut buf = [0; 4];
+        self.src.read(pos, &mut buf);
+        Some(u32::from_le_bytes(buf))
+    }
+    fn match_len(&mut self, mut a: usize, mut b: usize, max: usize) -> usize {
+        let mut abuf = [0; 16];
+        let mut bbuf = [0; 16];
+        let mut ret = 0;
+        while ret < max {
+            let n = usize::min(max - ret, 16);
+            self.src.read(a, &mut abuf[..n]);
+            self.src.read(b, &mut bbuf[..n]);
+            let same = common_prefix_len(&abuf[..n], &bbuf[..n]);
+            ret += same;
+            if same < n {
+                break;
+            }
+            a += n;
+            b += n;
+        }
+        ret
+    }
+    fn put_lits<E>(
+        &mut self,
+        mut start: usize,
+        end: usize,
+        outp: &mut impl OutputSink<E>,
+    ) -> Result<(), E> {
+        // the encoder splits literal runs into chunks of 32 anyways,
+        // so doing the same here doesn't change the output
+        let mut buf = [0; 32];
+        while start < end {
+            let n = usize::min(end - start, 32);
+            self.src.read(start, &mut buf[..n]);
+            outp.put_lits(&buf[..n])?;
+            start += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+// (some loops over levels only have one without level 2)
+#[cfg_attr(not(feature = "level2"), allow(clippy::single_element_loop))]
+mod tests {
+    use super::*;
+    // (only the tests with std compress anything)
+    #[cfg_attr(not(feature = "std"), allow(unused_imports))]
+    use crate::*;
+
+    #[test]
+    fn test_peek4_byte_order() {
+        // (little-endian on every target, as the hashes and so the output depend on it)
+        let data = [1u8, 2, 3, 4, 5];
+        let mut slice = &data[..];
+        assert_eq!(slice.peek4(1), Some(0x05040302));
+        assert_eq!(slice.peek4(2), None);
+
+        let (a, b) = data.split_at(2);
+        let mut segs: &[&[u8]] = &[a, b];
+        let mut source = SourceInput::new(&mut segs);
+        assert_eq!(source.peek4(0), Some(0x04030201));
+        assert_eq!(source.peek4(1), Some(0x05040302));
+        assert_eq!(source.peek4(2), None);
+    }
+
+    #[cfg(feature = "std")]
+    extern crate std;
+    #[cfg(feature = "std")]
+    use std::vec::Vec;
+
+    /// Checks that reads stay within the promised window
+    #[cfg(feature = "std")]
+    struct Tracked<'a> {
+        data: &'a [u8],
+        furthest: usize,
+    }
+    #[cfg(feature = "std")]
+    impl InputSource for Tracked<'_> {
+        fn len(&self) -> usize {
+            self.data.len()
+        }
+        fn read(&mut self, pos: usize, buf: &mut [u8]) {
+            // (except that `paranoid` reads through the input again afterwards)
+            assert!(cfg!(feature = "paranoid") || pos + MAX_LOOKBACK >= self.furthest);
+            self.furthest = usize::max(self.furthest, pos);
+            buf.copy_from_slice(&self.data[pos..pos + buf.len()]);
+        }
+    }
+    #[cfg(feature = "std")]
+    struct VecSink(Vec<u8>);
+    #[cfg(feature = "std")]
+    impl ByteSink for VecSink {
+        type Error = CompressError;
+
+        fn put_buf(&mut self, buf: &[u8]) -> Result<(), CompressError> {
+            self.0.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_source_matches_slice() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        let mut far = Vec::new();
+        for i in 0..16 {
+            far.extend_from_slice(&src[i * 64..i * 64 + 1000]);
+            far.extend((0..70000u32).map(|x| (x * 7 + i as u32) as u8));
+        }
+
+        let mut state = CompressState::new();
+        for opts in [
+            CompressOptions::default(),
+            CompressOptions {
+                c_compatible: true,
+                ..Default::default()
+            },
+            CompressOptions {
+                acceleration: 4,
+                ..Default::default()
+            },
+            CompressOptions {
+                two_entry_buckets: true,
+                ..Default::default()
+            },
+            CompressOptions {
+                lazy_matching: true,
+                ..Default::default()
+            },
+            CompressOptions {
+                max_chain: 16,
+                lazy_matching: true,
+                ..Default::default()
+            },
+        ] {
+            for inp in [&src[..], &far[..], &src[..5], &src[..40]] {
+                for level in [
+                    CompressionLevel::Level1,
+                    #[cfg(feature = "level2")]
+                    CompressionLevel::Level2,
+                ] {
+                    let expected = state
+                        .compress_to_vec_with_options(inp, level, &opts)
+                        .unwrap();
+                    let mut source = Tracked {
+                        data: inp,
+                        furthest: 0,
+                    };
+                    let mut out = VecSink(Vec::new());
+                    state
+                        .compress_source_to_sink_with_options(&mut source, &mut out, level, &opts)
+                        .unwrap();
+                    assert_eq!(out.0, expected);
+                }
+            }
+        }
+    }
+
+    /// Level 2 rejects far matches shorter than 5 bytes, and checking this must not read ahead
+    /// of the position being compressed (which would put the next candidates out of the window)
+    #[cfg(all(feature = "std", feature = "level2"))]
+    #[test]
+    fn test_source_rejected_far_match() {
+        // a far match at `q` which is rejected for being too short,
+        // and a match as far away as possible at `q + 1`
+        let q = 80_000;
+        let mut inp = std::vec![0u8; q + 100];
+        inp[0] = 1;
+        inp[q + 1 - MAX_LOOKBACK..][..4].copy_from_slice(b"bcZW");
+        inp[q - 9000..][..5].copy_from_slice(b"abcXY");
+        inp[q..][..5].copy_from_slice(b"abcZZ");
+
+        let mut state = CompressState::new();
+        for opts in [
+            CompressOptions::default(),
+            CompressOptions {
+                c_compatible: true,
+                ..Default::default()
+            },
+        ] {
+            let level = CompressionLevel::Level2;
+            let expected = state
+                .compress_to_vec_with_options(&inp, level, &opts)
+                .unwrap();
+            let mut source = Tracked {
+                data: &inp,
+                furthest: 0,
+            };
+            let mut out = VecSink(Vec::new());
+            state
+                .compress_source_to_sink_with_options(&mut source, &mut out, level, &opts)
+                .unwrap();
+            assert_eq!(out.0, expected);
+        }
+    }
+
+    /// Inputs of 4 GiB or more, where the positions stored in the hash table wrap around
+    #[cfg(all(target_pointer_width = "64", feature = "std", feature = "decompress"))]
+    #[cfg_attr(debug_assertions, ignore = "takes minutes without optimizations")]
+    #[test]
+    fn test_source_over_4gib() {
+        extern crate std;
+
+        // some unique bytes, 4 GiB of zeros, then the same bytes again,
+        // which are stored in the hash table at the same (wrapped) positions as the first copy
+        const PATTERN_LEN: usize = 1000;
+        const LEN: usize = (1 << 32) + 2 * PATTERN_LEN;
+        struct Huge;
+        impl InputSource for Huge {
+            fn len(&self) -> usize {
+                LEN
+            }
+            fn read(&mut self, pos: usize, buf: &mut [u8]) {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    let pos = pos + i;
+                    let p = if pos < PATTERN_LEN {
+                        pos
+                    } else {
+                        pos.wrapping_sub(LEN - PATTERN_LEN)
+                    };
+                    *b = if p < PATTERN_LEN {
+                        let x = (p as u32 + 1).wrapping_mul(0x9e3779b1);
+                        ((x ^ (x >> 15)).wrapping_mul(0x85ebca6b) >> 24) as u8
+                    } else {
+                        0
+                    };
+                }
+            }
+        }
+
+        let mut state = CompressState::new();
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let mut comp = std::vec::Vec::new();
+            state
+                .compress_source_to_sink(&mut Huge, &mut ExtendSink(&mut comp), level)
+                .unwrap();
+            // every backreference is within the output (without needing 4 GiB to decompress into)
+            assert_eq!(
+                decompress_to_buf(&comp, &mut []),
+                Err(DecompressError::OutputTooSmall {
+                    written: 0,
+                    required: LEN
+                })
+            );
+            // and the second copy, too far away to refer back to, is stored as literals
+            let mut tail = [0; 8];
+            Huge.read(LEN - tail.len(), &mut tail);
+            assert!(comp.ends_with(&tail), "{:?}", level);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Adapters for [std::io]
+
+extern crate std;
+
+use std::io::{self, IoSlice, Write};
+use std::vec::Vec;
+
+use crate::compress::*;
+
+/// Number of bytes collected before they are written out
+const BATCH_SIZE: usize = 8192;
+
+impl From<CompressError> for io::Error {
+    fn from(e: CompressError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// [ByteSink] which streams compressed output to an [io::Write]
+///
+/// The many small opcodes and literal runs produced by the compressor are collected into batches,
+/// which are then passed to the writer together using [Write::write_vectored].
+/// This keeps the number of system calls low when e.g. writing to a socket.
+///
+/// Compressing into this sink flushes it once all of the output has been produced.
+pub struct WriteSink<W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+}
+impl<W: Write> WriteSink<W> {
+    /// Wrap the given writer
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+    /// Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+    /// Unwrap the underlying writer
+    ///
+    /// Any output which has not been flushed (i.e. because an error occurred) is discarded.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+impl<W: Write> ByteSink for WriteSink<W> {
+    type Error = io::Error;
+
+    fn putc(&mut self, c: u8) -> Result<(), io::Error> {
+        if self.pending.len() == BATCH_SIZE {
+            self.inner.write_all(&self.pending)?;
+            self.pending.clear();
+        }
+        self.pending.push(c);
+        Ok(())
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        if self.pending.len() + buf.len() <= BATCH_SIZE {
+            self.pending.extend_from_slice(buf);
+            return Ok(());
+        }
+
+        // write both at once rather than copying `buf`
+        let mut bufs = [IoSlice::new(&self.pending), IoSlice::new(buf)];
+        write_all_vectored(&mut self.inner, &mut bufs)?;
+        self.pending.clear();
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.write_all(&self.pending)?;
+        self.pending.clear();
+        self.inner.flush()
+    }
+}
+
+/// Equivalent of the unstable [Write::write_all_vectored]
+fn write_all_vectored(w: &mut impl Write, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match w.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::CompressionLevel;
+
+    /// Records all writes, and only accepts a limited amount at a time
+    struct Socket {
+        data: Vec<u8>,
+        calls: usize,
+        flushed: bool,
+    }
+    impl Write for Socket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut n = 0;
+            for buf in bufs {
+                let len = usize::min(buf.len(), 5000 - n);
+                self.data.extend_from_slice(&buf[..len]);
+                n += len;
+            }
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_sink() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let inp = std::fs::read(d.join("src/compress.rs")).unwrap();
+
+        let mut state = CompressState::new();
+        let expected = state
+            .compress_to_vec(&inp, CompressionLevel::Level1)
+            .unwrap();
+
+        let mut sink = WriteSink::new(Socket {
+            data: Vec::new(),
+            calls: 0,
+            flushed: false,
+        });
+        state
+            .compress_to_sink(&inp, &mut sink, CompressionLevel::Level1)
+            .unwrap();
+        let socket = sink.into_inner();
+        assert_eq!(socket.data, expected);
+        assert!(socket.flushed);
+        // each batch takes two calls to write
+        assert!(socket.calls <= expected.len() / 4096 + 4);
+    }
+}
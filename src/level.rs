@@ -0,0 +1,103 @@
+//! Compression levels, shared between compression and decompression
+
+use core::fmt;
+
+use crate::decompress::DecompressError;
+
+/// An invalid level number or level bits
+///
+/// When parsing compressed data, this becomes [DecompressError::InvalidCompressionLevel].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InvalidLevelError;
+impl fmt::Display for InvalidLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid compression level")
+    }
+}
+impl core::error::Error for InvalidLevelError {}
+impl From<InvalidLevelError> for DecompressError {
+    fn from(_: InvalidLevelError) -> Self {
+        DecompressError::InvalidCompressionLevel
+    }
+}
+
+/// Compression level
+///
+/// This is `#[non_exhaustive]` so that features (such as `level2`) and new ways of choosing the level
+/// can add variants without breaking code which matches on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum CompressionLevel {
+    /// Default compression (i.e. level 1 if the input is less than 64 KiB, level 2 otherwise)
+    ///
+    /// This rule can be changed with [CompressOptions::default_level_policy](crate::CompressOptions::default_level_policy).
+    /// Without the `level2` feature, this is always level 1.
+    #[default]
+    Default,
+    /// Level 1
+    Level1,
+    /// Level 2
+    #[cfg(feature = "level2")]
+    Level2,
+    /// Whichever of level 1 or level 2 produces the smaller output
+    ///
+    /// This is slow, as the input is compressed with both levels before producing output.
+    /// Without the `level2` feature, this is always level 1.
+    Best,
+}
+
+impl CompressionLevel {
+    /// Mask for the bits of the first byte of a compressed stream which indicate the level
+    pub const HEADER_MASK: u8 = 0b111_00000;
+
+    /// Get the bits indicating this level in the first byte of a compressed stream
+    ///
+    /// Returns `None` for [CompressionLevel::Default] and [CompressionLevel::Best],
+    /// as these do not correspond to one specific level.
+    pub const fn into_header_bits(self) -> Option<u8> {
+        match self {
+            CompressionLevel::Level1 => Some(0b000_00000),
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2 => Some(0b001_00000),
+            _ => None,
+        }
+    }
+
+    /// Get the level indicated by the first byte of a compressed stream
+    ///
+    /// Bits outside of [HEADER_MASK](Self::HEADER_MASK) are ignored.
+    /// Without the `level2` feature, level 2 is reported as invalid.
+    pub const fn from_header_bits(b: u8) -> Result<Self, InvalidLevelError> {
+        match b >> 5 {
+            0 => Ok(CompressionLevel::Level1),
+            #[cfg(feature = "level2")]
+            1 => Ok(CompressionLevel::Level2),
+            _ => Err(InvalidLevelError),
+        }
+    }
+
+    /// Get the level that the given compressed data was compressed with
+    pub fn of_compressed(inp: &[u8]) -> Result<Self, DecompressError> {
+        let b = inp
+            .first()
+            .ok_or(DecompressError::InputTruncated { input_offset: 0 })?;
+        Ok(Self::from_header_bits(*b)?)
+    }
+}
+
+/// Converts a level number (1 or 2), as used by the C API
+impl TryFrom<u8> for CompressionLevel {
+    type Error = InvalidLevelError;
+
+    fn try_from(level: u8) -> Result<Self, InvalidLevelError> {
+        match level {
+            1 => Ok(CompressionLevel::Level1),
+            #[cfg(feature = "level2")]
+            2 => Ok(CompressionLevel::Level2),
+            _ => Err(InvalidLevelError),
+        }
+    }
+}
@@ -1,14 +1,27 @@
 #![no_std]
+#![cfg_attr(feature = "portable-simd", feature(portable_simd))]
+// (the C reference is development-only, and can't be called without unsafe code)
+#![cfg_attr(
+    not(any(feature = "unsafe-perf", feature = "c-reference")),
+    forbid(unsafe_code)
+)]
+// with neither half of the codec enabled, only the shared types are left
+#![cfg_attr(
+    not(any(feature = "compress", feature = "decompress")),
+    allow(dead_code, unused_imports)
+)]
 
 //! This crate is a pure-Rust reimplementation of [FastLZ](https://github.com/ariya/FastLZ).
 //!
 //! This crate uses the same fundamental algorithm as the original C code,
 //! namely using a hash table keyed off of the next three bytes to try to find backreferences.
 //! Just like FastLZ (and unlike "traditional" implementations of DEFLATE such as gzip),
-//! no chaining is used in the hashtable, only a single entry per hash key.
+//! by default no chaining is used in the hashtable, only a single entry per hash key.
+//! Slower modes which search further for better matches can be enabled in [CompressOptions].
 //!
-//! This crate does not generate bit-identical output, but output should be fully compatible
-//! with other decoders, at least for compression level 1.
+//! This crate does not generate bit-identical output by default, but output should be fully compatible
+//! with other decoders, at least for compression level 1. If byte-for-byte identical output is needed
+//! (e.g. for reproducible builds), see [CompressOptions::c_compatible].
 //!
 //! Compression level 2 is not formally documented, but this crate implements it as follows:
 //! * If `opc[7:5] == 0b000`, copy `opc[4:0] + 1` of the following literals
@@ -20,19 +33,150 @@
 //!     * If the initial `disp` is all 1 bits, `disp` += the next two bytes as a big-endian integer
 //! * A file is, for some reason, not permitted to end on a backreference requiring extended displacement bytes
 //!
+//! Level 2 support is enabled by the default `level2` feature. Without it, level 2 code is left out
+//! entirely (e.g. for bootloaders which only ever see level 1 streams), and level 2 streams are rejected.
+//!
+//! Similarly, the compressor (including its hash table) is only built with the default `compress` feature,
+//! so boot ROMs and loaders which only need to unpack data can leave it out. Conversely, devices which only
+//! ever send compressed data can leave out the decompressor by disabling the default `decompress` feature.
+//!
+//! The crate also works on targets with a 16-bit `usize` (e.g. AVR and MSP430), where the default
+//! hash table is smaller (see [DEFAULT_HTAB_SZ]) and level 2 displacements which can't be addressed
+//! are rejected as invalid like any other backreference before the start of the output.
+//!
+//! Conversely, on 64-bit hosts inputs of 4 GiB or more are compressed as usual: the positions stored
+//! in the hash table wrap around, but every candidate match is checked against the input and against
+//! the maximum displacement of the level before it is used, so the window simply slides along.
+//! The frame format can only record sizes below 4 GiB, so compressing such inputs into a frame
+//! fails with `CompressError::InputTooLarge` instead.
+//!
+//! Decompressing into a buffer cannot panic, whatever the input. Neither can compressing into one,
+//! in builds without the `alloc` feature (with it, some [CompressOptions] need allocations,
+//! which panic if they fail). This is checked by `tests/no_panic.rs`.
+//!
+//! The exception is the `paranoid` feature, intended for integration testing, which checks every
+//! compressed output by decompressing it again before returning it, and panics if it doesn't match
+//! the input. The decompressed output is compared against the input as it is produced, so no extra
+//! buffer is needed, except for output written to a [ByteSink], which is only checked with `alloc`.
+//!
+//! By default, this crate contains no unsafe code at all (it is built with `#![forbid(unsafe_code)]`).
+//! The `unsafe-perf` feature enables faster paths which need it: SIMD intrinsics, and writing into
+//! uninitialized memory (which is also needed for functions such as `decompress_to_uninit_buf`).
+//!
 //! Like the original code, this crate does not support "streaming" compression.
 //! It only operates on full input.
+//!
+//! Raw FastLZ streams do not record their uncompressed size. For convenience, this crate also
+//! supports a simple "frame" format consisting of a 4-byte little-endian uncompressed size
+//! followed by the compressed stream (see [decompress_framed_to_vec]).
 
+#[cfg(feature = "compress")]
 mod compress;
-pub use compress::{CompressError, CompressState, CompressionLevel};
+#[cfg(feature = "compress")]
+pub use compress::{
+    compress_bound, ByteSink, CompressError, CompressOptions, CompressOutcome, CompressState,
+    CompressStats, GenericCompressState, HashTableStorage, DEFAULT_HTAB_SZ, STATS_BUCKETS,
+};
+
+mod level;
+pub use level::{CompressionLevel, InvalidLevelError};
+
+#[cfg(feature = "compress")]
+mod estimate;
+#[cfg(feature = "compress")]
+pub use estimate::estimate_compressibility;
+
+#[cfg(feature = "compress")]
+mod input;
+#[cfg(feature = "paranoid")]
+mod paranoid;
+#[cfg(feature = "compress")]
+pub use input::{InputSource, MAX_LOOKBACK};
+
+mod progress;
+pub use progress::{Progress, PROGRESS_INTERVAL};
 
 mod decompress;
-#[cfg(feature = "alloc")]
-pub use decompress::decompress_to_vec;
-pub use decompress::{decompress_to_buf, DecompressError};
+pub use decompress::DecompressError;
+#[cfg(feature = "decompress")]
+pub use decompress::{
+    decode_tokens, decompress_segments_to_buf, decompress_segments_to_buf_with_options,
+    decompress_to_buf, decompress_to_buf_lenient, decompress_to_buf_with_options,
+    decompress_to_buf_with_progress, decompress_with_limits, DecompressLimits, DecompressOptions,
+    LenientOutput, Token,
+};
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+pub use decompress::{
+    decompress_segments_to_vec, decompress_segments_to_vec_with_options, decompress_to_vec,
+    decompress_to_vec_lenient, decompress_to_vec_with_options, decompress_to_vec_with_progress,
+};
+#[cfg(all(feature = "unsafe-perf", feature = "decompress"))]
+pub use decompress::{decompress_to_uninit_buf, decompress_to_uninit_buf_with_options};
+
+mod frame;
+#[cfg(all(feature = "alloc", feature = "decompress"))]
+pub use frame::decompress_framed_to_vec;
+pub use frame::FRAME_HEADER_LEN;
+#[cfg(feature = "decompress")]
+pub use frame::{decompress_framed_to_buf, frame_decompressed_len};
 
+#[cfg(any(feature = "compress", feature = "decompress"))]
+mod selftest;
+#[cfg(any(feature = "compress", feature = "decompress"))]
+pub use selftest::{self_test, SelfTestError};
+
+#[cfg(all(feature = "std", feature = "compress", feature = "decompress"))]
+mod roundtrip;
+#[cfg(all(feature = "std", feature = "compress", feature = "decompress"))]
+pub use roundtrip::{verify_roundtrip, RoundtripError, RoundtripReport};
+
+#[cfg(all(feature = "std", feature = "compress"))]
+mod io;
+#[cfg(all(feature = "std", feature = "compress"))]
+pub use io::WriteSink;
+
+mod containers;
+#[cfg(feature = "compress")]
+pub use containers::ExtendSink;
+#[cfg(all(feature = "arrayvec", feature = "decompress"))]
+pub use containers::{decompress_to_arrayvec, decompress_to_arrayvec_with_options};
+#[cfg(all(feature = "bytes", feature = "decompress"))]
+pub use containers::{decompress_to_bytes_mut, decompress_to_bytes_mut_with_options};
+#[cfg(feature = "decompress")]
+pub use containers::{decompress_to_extend, decompress_to_extend_with_options};
+#[cfg(all(feature = "heapless", feature = "decompress"))]
+pub use containers::{decompress_to_heapless_vec, decompress_to_heapless_vec_with_options};
+
+mod accel;
 mod util;
 
-#[cfg(feature = "std")]
+#[cfg(all(feature = "std", feature = "compress", feature = "decompress"))]
 #[cfg(test)]
+mod proptests;
+
+#[cfg(all(feature = "std", any(feature = "compress", feature = "decompress")))]
+#[cfg(test)]
+mod golden;
+
+#[cfg(all(feature = "std", feature = "decompress"))]
+#[cfg(test)]
+mod regressions;
+
+#[cfg(feature = "c-reference")]
+#[doc(hidden)]
+pub mod c_reference;
+
+#[cfg(feature = "reference-tester")]
+pub mod wasmtester;
+#[cfg(all(feature = "std", not(feature = "c-reference")))]
+#[cfg(all(test, not(feature = "reference-tester")))]
 mod wasmtester;
+
+/// The C reference code which the tests compare against
+#[cfg(all(feature = "std", feature = "c-reference"))]
+#[cfg(test)]
+use c_reference::FastLZNative as TestReference;
+/// The C reference code which the tests compare against
+#[cfg(all(feature = "std", not(feature = "c-reference")))]
+#[cfg(test)]
+use wasmtester::FastLZWasm as TestReference;
@@ -0,0 +1,192 @@
+//! Checking compressed output by decompressing it again, with the `paranoid` feature
+//!
+//! The decompressed output is compared against the input as it is produced rather than stored,
+//! so this doesn't need any memory proportional to the input.
+
+#[cfg(feature = "alloc")]
+use crate::compress::ByteSink;
+use crate::compress::CompressOptions;
+use crate::decompress::{decompress_impl, DecompressError, DecompressOptions};
+use crate::input::CompressInput;
+use crate::util::*;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+/// Decompressed output, checked against the input which was compressed
+struct VerifyOutput<'a, I> {
+    inp: &'a mut I,
+    pos: usize,
+}
+impl<I: CompressInput> VerifyOutput<'_, I> {
+    fn mismatch(&self, len: usize) -> ! {
+        panic!(
+            "compressed output does not decompress to the input ({} bytes at {})",
+            len, self.pos
+        );
+    }
+}
+impl<I: CompressInput> OutputSink<DecompressError> for VerifyOutput<'_, I> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), DecompressError> {
+        let mut expected = ExpectLits(lits);
+        let same = lits.len() <= self.inp.len() - self.pos
+            && self
+                .inp
+                .put_lits(self.pos, self.pos + lits.len(), &mut expected)
+                .is_ok()
+            && expected.0.is_empty();
+        if !same {
+            self.mismatch(lits.len());
+        }
+        self.pos += lits.len();
+        Ok(())
+    }
+
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), DecompressError> {
+        if disp >= self.pos {
+            return Err(DecompressError::InvalidBackreference {
+                input_offset: 0,
+                output_pos: self.pos,
+                disp,
+            });
+        }
+        // everything before `pos` matches the input, so the input can be copied from
+        // (overlapping copies included, as each byte only depends on ones already compared)
+        let same = len <= self.inp.len() - self.pos
+            && self.inp.match_len(self.pos - disp - 1, self.pos, len) == len;
+        if !same {
+            self.mismatch(len);
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Literals read from the input, compared against the ones which were decompressed
+struct ExpectLits<'a>(&'a [u8]);
+impl OutputSink<()> for ExpectLits<'_> {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), ()> {
+        match self.0.split_at_checked(lits.len()) {
+            Some((expected, rest)) if expected == lits => {
+                self.0 = rest;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn put_backref(&mut self, _disp: usize, _len: usize) -> Result<(), ()> {
+        Err(())
+    }
+}
+
+/// Decompress `comp` and check that it matches the input, panicking if it doesn't
+///
+/// This reads through the input again from the beginning.
+pub(crate) fn verify_compressed(
+    inp: &mut impl CompressInput,
+    comp: &[u8],
+    compress_opts: &CompressOptions,
+) {
+    let opts = DecompressOptions {
+        strict_l2_end: !compress_opts.allow_far_match_at_end,
+        ..Default::default()
+    };
+    let mut outp = VerifyOutput { inp, pos: 0 };
+    if let Err(e) = decompress_impl(comp, &mut outp, &opts) {
+        panic!("compressed output does not decompress: {}", e);
+    }
+    if outp.pos != outp.inp.len() {
+        panic!(
+            "compressed output decompresses to {} bytes rather than {}",
+            outp.pos,
+            outp.inp.len()
+        );
+    }
+}
+
+/// Passes output through to another sink, keeping a copy to be checked afterwards
+#[cfg(feature = "alloc")]
+pub(crate) struct TeeSink<'a, O: ?Sized> {
+    pub inner: &'a mut O,
+    pub copy: alloc::vec::Vec<u8>,
+}
+#[cfg(feature = "alloc")]
+impl<'a, O: ByteSink + ?Sized> TeeSink<'a, O> {
+    pub fn new(inner: &'a mut O) -> Self {
+        Self {
+            inner,
+            copy: alloc::vec::Vec::new(),
+        }
+    }
+}
+#[cfg(feature = "alloc")]
+impl<O: ByteSink + ?Sized> ByteSink for TeeSink<'_, O> {
+    type Error = O::Error;
+
+    fn putc(&mut self, c: u8) -> Result<(), O::Error> {
+        self.copy.push(c);
+        self.inner.putc(c)
+    }
+    fn put_buf(&mut self, buf: &[u8]) -> Result<(), O::Error> {
+        self.copy.extend_from_slice(buf);
+        self.inner.put_buf(buf)
+    }
+    fn flush(&mut self) -> Result<(), O::Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+// (some loops over levels only have one without level 2)
+#[cfg_attr(not(feature = "level2"), allow(clippy::single_element_loop))]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn verify(inp: &[u8], comp: &[u8]) {
+        verify_compressed(&mut &inp[..], comp, &CompressOptions::default());
+    }
+
+    #[test]
+    fn test_verify_accepts_roundtrip() {
+        let inp = [1, 2, 3, 1, 2, 3, 1, 2, 3, 1, 2, 3, 4];
+        let mut comp = [0; 64];
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+        ] {
+            let len = CompressState::new()
+                .compress_to_buf(&inp, &mut comp, level)
+                .unwrap();
+            verify(&inp, &comp[..len]);
+        }
+    }
+
+    #[test]
+    #[should_panic = "does not decompress to the input"]
+    fn test_verify_rejects_wrong_literal() {
+        // a literal run of 3 bytes, then a backreference copying all of them
+        verify(&[1, 2, 3, 1, 2, 3], &[2, 1, 2, 4, 0x20, 2]);
+    }
+
+    #[test]
+    #[should_panic = "does not decompress to the input"]
+    fn test_verify_rejects_wrong_backref() {
+        verify(&[1, 2, 3, 1, 2, 4], &[2, 1, 2, 3, 0x20, 2]);
+    }
+
+    #[test]
+    #[should_panic = "decompresses to 3 bytes rather than 4"]
+    fn test_verify_rejects_truncated() {
+        verify(&[1, 2, 3, 4], &[2, 1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic = "does not decompress"]
+    fn test_verify_rejects_invalid() {
+        // a literal, then a backreference before the start of the output
+        verify(&[1, 2, 3], &[0, 1, 0x20, 5]);
+    }
+}
@@ -0,0 +1,54 @@
+//! Progress reporting for long-running operations
+
+use core::ops::ControlFlow;
+
+use crate::util::saturating_usize;
+
+/// Number of input bytes processed between calls to a progress callback
+///
+/// (64 KiB, or 4 KiB on 16-bit targets, where inputs can't be much bigger than that.)
+pub const PROGRESS_INTERVAL: usize = if usize::BITS < 32 {
+    4 * 1024
+} else {
+    saturating_usize(64 * 1024)
+};
+
+/// Progress of an operation, passed to a progress callback
+///
+/// For compression the input is the uncompressed data, and for decompression it is the compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Number of input bytes which have been processed so far
+    pub input_processed: usize,
+    /// Total number of input bytes
+    pub input_len: usize,
+}
+
+/// Invokes a progress callback each time another [PROGRESS_INTERVAL] bytes have been processed
+pub(crate) struct ProgressReporter<F> {
+    callback: F,
+    input_len: usize,
+    next: usize,
+}
+impl<F: FnMut(Progress) -> ControlFlow<()>> ProgressReporter<F> {
+    pub fn new(input_len: usize, callback: F) -> Self {
+        Self {
+            callback,
+            input_len,
+            next: PROGRESS_INTERVAL,
+        }
+    }
+
+    /// Returns [ControlFlow::Break] if the operation should be cancelled
+    pub fn update(&mut self, input_processed: usize) -> ControlFlow<()> {
+        if input_processed < self.next {
+            return ControlFlow::Continue(());
+        }
+        self.next = input_processed + PROGRESS_INTERVAL;
+        (self.callback)(Progress {
+            input_processed,
+            input_len: self.input_len,
+        })
+    }
+}
@@ -0,0 +1,189 @@
+//! Property-based tests over structured inputs, using only the public API
+
+extern crate std;
+use std::vec::Vec;
+
+use proptest::prelude::*;
+
+use crate::*;
+
+const LEVELS: &[CompressionLevel] = &[
+    CompressionLevel::Level1,
+    #[cfg(feature = "level2")]
+    CompressionLevel::Level2,
+    CompressionLevel::Default,
+    CompressionLevel::Best,
+];
+
+/// Piece of an input, chosen to exercise the different kinds of instructions
+#[derive(Clone, Debug)]
+enum Segment {
+    /// A single byte repeated
+    Run(u8, usize),
+    /// A short pattern repeated, which is matched at a short distance overlapping itself
+    Periodic(Vec<u8>, usize),
+    /// Incompressible bytes
+    Random(Vec<u8>),
+    /// A copy of earlier input, from up to beyond the reach of level 1
+    Repeat { dist: usize, len: usize },
+}
+
+fn segment() -> impl Strategy<Value = Segment> {
+    prop_oneof![
+        (any::<u8>(), 1..600usize).prop_map(|(c, len)| Segment::Run(c, len)),
+        (prop::collection::vec(any::<u8>(), 2..20), 1..600usize)
+            .prop_map(|(pattern, len)| Segment::Periodic(pattern, len)),
+        prop::collection::vec(any::<u8>(), 1..300).prop_map(Segment::Random),
+        (1..80_000usize, 3..600usize).prop_map(|(dist, len)| Segment::Repeat { dist, len }),
+    ]
+}
+
+fn build(segments: &[Segment]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for segment in segments {
+        match segment {
+            Segment::Run(c, len) => ret.resize(ret.len() + len, *c),
+            Segment::Periodic(pattern, len) => {
+                ret.extend(pattern.iter().cycle().take(*len));
+            }
+            Segment::Random(bytes) => ret.extend_from_slice(bytes),
+            Segment::Repeat { dist, len } => {
+                // (only as far back as there is anything)
+                if !ret.is_empty() {
+                    let src = ret.len() - usize::min(*dist, ret.len());
+                    for i in 0..*len {
+                        ret.push(ret[src + i]);
+                    }
+                }
+            }
+        }
+    }
+    ret
+}
+
+fn input() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(segment(), 0..40).prop_map(|segments| build(&segments))
+}
+
+/// Inputs with lengths just either side of where [CompressionLevel::Default] switches level
+#[cfg(feature = "level2")]
+fn boundary_input() -> impl Strategy<Value = Vec<u8>> {
+    (
+        prop::collection::vec(segment(), 1..40),
+        65536 - 64..65536 + 64usize,
+    )
+        .prop_map(|(segments, len)| {
+            let mut ret = build(&segments);
+            // make up the length by repeating what was generated (if anything was)
+            if ret.is_empty() {
+                ret.push(0);
+            }
+            while ret.len() < len {
+                ret.extend_from_within(..usize::min(ret.len(), len - ret.len()));
+            }
+            ret.truncate(len);
+            ret
+        })
+}
+
+fn options() -> impl Strategy<Value = CompressOptions> {
+    (
+        any::<bool>(),
+        any::<bool>(),
+        any::<bool>(),
+        prop_oneof![Just(0usize), 1..8usize],
+        any::<bool>(),
+        1..4usize,
+    )
+        .prop_map(
+            |(
+                c_compatible,
+                two_entry_buckets,
+                lazy_matching,
+                max_chain,
+                optimal_parse,
+                acceleration,
+            )| {
+                CompressOptions {
+                    c_compatible,
+                    two_entry_buckets,
+                    lazy_matching,
+                    max_chain,
+                    optimal_parse,
+                    acceleration,
+                    ..Default::default()
+                }
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn roundtrip(inp in input(), level in prop::sample::select(LEVELS), opts in options()) {
+        let mut state = CompressState::new();
+        let comp = state.compress_to_vec_with_options(&inp, level, &opts).unwrap();
+        prop_assert!(comp.len() <= compress_bound(inp.len()));
+        prop_assert_eq!(decompress_to_vec(&comp, Some(inp.len())).unwrap(), inp);
+    }
+
+    #[test]
+    fn compress_bound_is_enough(inp in input(), level in prop::sample::select(LEVELS)) {
+        let mut state = CompressState::new();
+        let mut out = std::vec![0u8; compress_bound(inp.len())];
+        let len = state.compress_to_buf(&inp, &mut out, level).unwrap();
+        prop_assert_eq!(&out[..len], &state.compress_to_vec(&inp, level).unwrap()[..]);
+    }
+
+    #[test]
+    fn truncated_output(
+        inp in input(),
+        level in prop::sample::select(LEVELS),
+        opts in options(),
+        frac in 0.0..1.0f64,
+    ) {
+        let mut state = CompressState::new();
+        let expected = state.compress_to_vec_with_options(&inp, level, &opts).unwrap();
+        let short_len = (expected.len() as f64 * frac) as usize;
+        prop_assume!(short_len < expected.len());
+
+        let mut out = std::vec![0u8; short_len];
+        let Err(CompressError::OutputTooSmall {
+            written,
+            input_processed,
+            remaining,
+        }) = state.compress_to_buf_with_options(&inp, &mut out, level, &opts)
+        else {
+            panic!("compression should have failed");
+        };
+        prop_assert_eq!(written, short_len);
+        prop_assert!(written + remaining >= expected.len());
+
+        // the input which was processed can be recovered from the truncated output
+        let mut decomp = std::vec![0u8; input_processed];
+        let _ = decompress_to_buf(&out, &mut decomp);
+        prop_assert_eq!(&decomp[..], &inp[..input_processed]);
+
+        // and a single retry is sufficient
+        let mut out = std::vec![0u8; written + remaining];
+        let len = state.compress_to_buf_with_options(&inp, &mut out, level, &opts).unwrap();
+        prop_assert_eq!(&out[..len], &expected[..]);
+    }
+}
+
+#[cfg(feature = "level2")]
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn roundtrip_around_default_level_switch(inp in boundary_input()) {
+        let mut state = CompressState::new();
+        let comp = state.compress_to_vec(&inp, CompressionLevel::Default).unwrap();
+        let expected_level = if inp.len() < 65536 {
+            CompressionLevel::Level1
+        } else {
+            CompressionLevel::Level2
+        };
+        prop_assert_eq!(CompressionLevel::of_compressed(&comp), Ok(expected_level));
+        prop_assert_eq!(decompress_to_vec(&comp, Some(inp.len())).unwrap(), inp);
+    }
+}
@@ -0,0 +1,89 @@
+//! Replays every file under `tests/corpus/` through the decoder and the roundtrip path
+//!
+//! Inputs found by fuzzing (or anything else that once caused a problem) can be dropped into
+//! that directory (or a subdirectory of it) to be checked from then on. Each file is treated
+//! both as a compressed stream, which only has to be decoded consistently without panicking,
+//! and as data, which has to survive compression at every level.
+
+extern crate std;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+
+use crate::*;
+
+fn corpus_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            corpus_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn corpus() -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = Vec::new();
+    corpus_files(
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/corpus")),
+        &mut files,
+    );
+    files.sort();
+    files
+        .into_iter()
+        .map(|path| {
+            let data = std::fs::read(&path).unwrap();
+            (path, data)
+        })
+        .collect()
+}
+
+/// Same checks as the `decompress` fuzz target
+#[cfg(feature = "decompress")]
+#[test]
+fn test_corpus_decompress() {
+    for (path, data) in corpus() {
+        // (so that a panic inside the decoder can be traced back to its input)
+        std::println!("{}", path.display());
+
+        let vec = decompress_to_vec(&data, None);
+        let mut buf = std::vec![0; 1 << 16];
+        let res = decompress_to_buf(&data, &mut buf);
+        match (vec, res) {
+            (Ok(vec), Ok(len)) => assert_eq!(vec[..], buf[..len]),
+            (Ok(vec), Err(DecompressError::OutputTooSmall { .. })) => {
+                assert!(vec.len() > buf.len())
+            }
+            (Err(_), Err(_)) => {}
+            (vec, res) => panic!(
+                "{}: Vec gave {:?}, buffer gave {:?}",
+                path.display(),
+                vec.map(|v| v.len()),
+                res
+            ),
+        }
+    }
+}
+
+/// Same checks as the `roundtrip` fuzz target
+#[cfg(all(feature = "compress", feature = "decompress"))]
+#[test]
+fn test_corpus_roundtrip() {
+    let mut state = CompressState::new();
+    for (path, data) in corpus() {
+        std::println!("{}", path.display());
+
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+            CompressionLevel::Default,
+            CompressionLevel::Best,
+        ] {
+            let comp = state.compress_to_vec(&data, level).unwrap();
+            assert!(comp.len() <= compress_bound(data.len()));
+            let decomp = decompress_to_vec(&comp, Some(data.len())).unwrap();
+            assert!(decomp == data, "{}: {:?}", path.display(), level);
+        }
+    }
+}
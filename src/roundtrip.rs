@@ -0,0 +1,177 @@
+//! One-call compress/decompress/compare check, for users' own test suites
+
+use core::fmt;
+use std::time::{Duration, Instant};
+
+use crate::compress::*;
+use crate::decompress::*;
+use crate::level::CompressionLevel;
+
+extern crate std;
+
+/// Round-trip failures
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum RoundtripError {
+    /// Compressing the input failed
+    Compress(CompressError),
+    /// Decompressing the compressed output failed
+    Decompress(DecompressError),
+    /// The decompressed output is not the same as the input
+    Mismatch {
+        /// Offset of the first byte which differs
+        ///
+        /// If the output is longer than the input, this is the length of the input.
+        offset: usize,
+    },
+}
+impl fmt::Display for RoundtripError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoundtripError::Compress(e) => write!(f, "compression failed: {}", e),
+            RoundtripError::Decompress(e) => write!(f, "decompression failed: {}", e),
+            RoundtripError::Mismatch { offset } => {
+                write!(
+                    f,
+                    "decompressed output differs from the input at {}",
+                    offset
+                )
+            }
+        }
+    }
+}
+impl core::error::Error for RoundtripError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            RoundtripError::Compress(e) => Some(e),
+            RoundtripError::Decompress(e) => Some(e),
+            RoundtripError::Mismatch { .. } => None,
+        }
+    }
+}
+impl From<CompressError> for RoundtripError {
+    fn from(e: CompressError) -> Self {
+        RoundtripError::Compress(e)
+    }
+}
+impl From<DecompressError> for RoundtripError {
+    fn from(e: DecompressError) -> Self {
+        RoundtripError::Decompress(e)
+    }
+}
+
+/// Details about a successful round trip
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct RoundtripReport {
+    /// Size of the input
+    pub input_len: usize,
+    /// Size of the compressed output
+    pub compressed_len: usize,
+    /// The level which was actually used
+    ///
+    /// This is never [CompressionLevel::Default] or [CompressionLevel::Best].
+    pub level_used: CompressionLevel,
+    /// Size of the input divided by size of the output (i.e. higher is better)
+    ///
+    /// An empty input is considered to have a ratio of 1.
+    pub ratio: f64,
+    /// Time taken to compress
+    pub compress_time: Duration,
+    /// Time taken to decompress
+    pub decompress_time: Duration,
+}
+
+/// Compress the input, decompress it again, and check that the result matches
+///
+/// This is intended as a quick sanity check in downstream test suites,
+/// e.g. for checking that representative data round-trips and compresses as well as expected.
+/// The timings only cover compressing and decompressing, not allocating buffers,
+/// but are of a single run, so they are only a rough guide.
+///
+/// Returns a [RoundtripReport] on success, or an error describing which step failed
+pub fn verify_roundtrip(
+    inp: &[u8],
+    level: CompressionLevel,
+) -> Result<RoundtripReport, RoundtripError> {
+    let mut state = CompressState::new_boxed();
+    let mut comp = std::vec![0; compress_bound(inp.len())];
+    let start = Instant::now();
+    let outcome = state.compress_to_buf_ex(inp, &mut comp, level)?;
+    let compress_time = start.elapsed();
+    let comp = &comp[..outcome.written];
+
+    let mut out = std::vec![0; inp.len()];
+    let start = Instant::now();
+    let res = decompress_to_buf(comp, &mut out);
+    let decompress_time = start.elapsed();
+    let written = match res {
+        Ok(len) => len,
+        // (the decompressed output would have been longer than the input)
+        Err(DecompressError::OutputTooSmall { written, .. }) => written + 1,
+        Err(e) => return Err(e.into()),
+    };
+
+    let common = usize::min(written, inp.len());
+    if let Some(offset) = inp.iter().zip(&out[..common]).position(|(a, b)| a != b) {
+        return Err(RoundtripError::Mismatch { offset });
+    }
+    if written != inp.len() {
+        return Err(RoundtripError::Mismatch { offset: common });
+    }
+
+    Ok(RoundtripReport {
+        input_len: inp.len(),
+        compressed_len: outcome.written,
+        level_used: outcome.level_used,
+        ratio: outcome.ratio,
+        compress_time,
+        decompress_time,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let src = std::fs::read(d.join("src/compress.rs")).unwrap();
+        for level in [
+            CompressionLevel::Level1,
+            #[cfg(feature = "level2")]
+            CompressionLevel::Level2,
+            CompressionLevel::Default,
+        ] {
+            let report = verify_roundtrip(&src, level).unwrap();
+            let comp = CompressState::new().compress_to_vec(&src, level).unwrap();
+            assert_eq!(report.input_len, src.len());
+            assert_eq!(report.compressed_len, comp.len());
+            assert_eq!(
+                report.level_used,
+                CompressionLevel::of_compressed(&comp).unwrap()
+            );
+            assert!(report.ratio > 1.0);
+        }
+
+        let report = verify_roundtrip(&[], CompressionLevel::Level1).unwrap();
+        assert_eq!(report.compressed_len, 0);
+        assert_eq!(report.ratio, 1.0);
+    }
+
+    #[test]
+    fn test_roundtrip_error_source() {
+        use core::error::Error;
+        use std::string::ToString;
+
+        let e: RoundtripError = CompressError::InputTooLarge.into();
+        assert_eq!(
+            e.source().unwrap().to_string(),
+            CompressError::InputTooLarge.to_string()
+        );
+        assert!(RoundtripError::Mismatch { offset: 0 }.source().is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Known-answer self-test, for checking the codec on the target it actually runs on
+
+use core::fmt;
+
+#[cfg(feature = "compress")]
+use crate::compress::*;
+#[cfg(feature = "decompress")]
+use crate::decompress::*;
+use crate::level::CompressionLevel;
+
+/// Self-test failures
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum SelfTestError {
+    /// Compressing a built-in vector did not produce the expected output
+    Compress {
+        /// Index of the failing vector
+        vector: usize,
+    },
+    /// Decompressing a built-in vector did not produce the expected output
+    Decompress {
+        /// Index of the failing vector
+        vector: usize,
+    },
+}
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfTestError::Compress { vector } => {
+                write!(f, "self-test vector {} compressed incorrectly", vector)
+            }
+            SelfTestError::Decompress { vector } => {
+                write!(f, "self-test vector {} decompressed incorrectly", vector)
+            }
+        }
+    }
+}
+impl core::error::Error for SelfTestError {}
+
+/// Number of hash table entries used for compressing the vectors
+///
+/// (Small, to keep the stack usage down. The expected output depends on this.)
+#[cfg(feature = "compress")]
+const HTAB_SZ: usize = 256;
+
+/// Size of the buffers on the stack, which all of the vectors fit in
+const BUF_SZ: usize = 512;
+
+struct Vector {
+    #[cfg_attr(not(feature = "compress"), allow(dead_code))]
+    level: CompressionLevel,
+    raw: &'static [u8],
+    compressed: &'static [u8],
+}
+
+const FOX: &[u8] =
+    b"The quick brown fox jumps over the lazy dog. The quick brown fox jumps over the lazy dog.";
+
+const VECTORS: &[Vector] = &[
+    // too short for any matches
+    Vector {
+        level: CompressionLevel::Level1,
+        raw: b"abc",
+        compressed: &[0x02, b'a', b'b', b'c'],
+    },
+    // literal runs, then a long match
+    Vector {
+        level: CompressionLevel::Level1,
+        raw: FOX,
+        compressed: &[
+            0x1f, 0x54, 0x68, 0x65, 0x20, 0x71, 0x75, 0x69, 0x63, 0x6b, 0x20, 0x62, 0x72, 0x6f,
+            0x77, 0x6e, 0x20, 0x66, 0x6f, 0x78, 0x20, 0x6a, 0x75, 0x6d, 0x70, 0x73, 0x20, 0x6f,
+            0x76, 0x65, 0x72, 0x20, 0x74, 0x0c, 0x68, 0x65, 0x20, 0x6c, 0x61, 0x7a, 0x79, 0x20,
+            0x64, 0x6f, 0x67, 0x2e, 0x20, 0xe0, 0x23, 0x2c,
+        ],
+    },
+    // a run, split into several matches
+    Vector {
+        level: CompressionLevel::Level1,
+        raw: &[0x55; 300],
+        compressed: &[0x00, 0x55, 0xe0, 0xfd, 0x00, 0xe0, 0x1c, 0x00],
+    },
+    #[cfg(feature = "level2")]
+    Vector {
+        level: CompressionLevel::Level2,
+        raw: FOX,
+        compressed: &[
+            0x3f, 0x54, 0x68, 0x65, 0x20, 0x71, 0x75, 0x69, 0x63, 0x6b, 0x20, 0x62, 0x72, 0x6f,
+            0x77, 0x6e, 0x20, 0x66, 0x6f, 0x78, 0x20, 0x6a, 0x75, 0x6d, 0x70, 0x73, 0x20, 0x6f,
+            0x76, 0x65, 0x72, 0x20, 0x74, 0x0c, 0x68, 0x65, 0x20, 0x6c, 0x61, 0x7a, 0x79, 0x20,
+            0x64, 0x6f, 0x67, 0x2e, 0x20, 0xe0, 0x23, 0x2c,
+        ],
+    },
+    // a run, as a single match with an extended length
+    #[cfg(feature = "level2")]
+    Vector {
+        level: CompressionLevel::Level2,
+        raw: &[b'z'; 300],
+        compressed: &[0x20, b'z', 0xe0, 0xff, 0x23, 0x00],
+    },
+];
+
+/// Run a handful of built-in vectors through whichever of the compressor and decompressor are enabled
+///
+/// This is intended for e.g. firmware to check at boot that the codec works correctly
+/// as built for the target. It does not allocate, and only needs a few KiB of stack
+/// (compression uses a smaller hash table than the default, which the expected output is for).
+pub fn self_test() -> Result<(), SelfTestError> {
+    for (vector, v) in VECTORS.iter().enumerate() {
+        #[cfg(feature = "compress")]
+        {
+            let mut state = CompressState::<HTAB_SZ>::new_sized();
+            let mut out = [0u8; BUF_SZ];
+            match state.compress_to_buf(v.raw, &mut out, v.level) {
+                Ok(len) if out[..len] == *v.compressed => {}
+                _ => return Err(SelfTestError::Compress { vector }),
+            }
+        }
+
+        #[cfg(feature = "decompress")]
+        {
+            let mut out = [0u8; BUF_SZ];
+            match decompress_to_buf(v.compressed, &mut out) {
+                Ok(len) if out[..len] == *v.raw => {}
+                _ => return Err(SelfTestError::Decompress { vector }),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        assert_eq!(self_test(), Ok(()));
+        for v in VECTORS {
+            assert!(v.raw.len() <= BUF_SZ && v.compressed.len() <= BUF_SZ);
+            assert_eq!(CompressionLevel::of_compressed(v.compressed), Ok(v.level));
+        }
+    }
+}
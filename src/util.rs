@@ -1,3 +1,6 @@
+#[cfg(feature = "unsafe-perf")]
+use core::mem::MaybeUninit;
+
 #[cfg(feature = "alloc")]
 extern crate alloc;
 
@@ -17,6 +20,14 @@ pub trait OutputSink<ErrTy> {
     /// Copy `len` bytes, which as usual for LZ77 may exceed `disp`.
     fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), ErrTy>;
 }
+impl<ErrTy, T: OutputSink<ErrTy> + ?Sized> OutputSink<ErrTy> for &mut T {
+    fn put_lits(&mut self, lits: &[u8]) -> Result<(), ErrTy> {
+        (**self).put_lits(lits)
+    }
+    fn put_backref(&mut self, disp: usize, len: usize) -> Result<(), ErrTy> {
+        (**self).put_backref(disp, len)
+    }
+}
 
 /// Borrowed slice of bytes
 pub struct BufOutput<'a> {
@@ -28,15 +39,80 @@ impl<'a> From<&'a mut [u8]> for BufOutput<'a> {
         Self { pos: 0, buf }
     }
 }
+impl BufOutput<'_> {
+    /// Split the buffer into what has been written and the space left after it
+    pub fn split(&mut self) -> (&mut [u8], &mut [u8]) {
+        // (clamped so that this can't panic, whatever `pos` is)
+        self.buf.split_at_mut(usize::min(self.pos, self.buf.len()))
+    }
+}
+
+/// Borrowed slice of possibly-uninitialized bytes
+///
+/// Everything before `pos` is always initialized
+#[cfg(feature = "unsafe-perf")]
+pub struct UninitBufOutput<'a> {
+    pub pos: usize,
+    pub buf: &'a mut [MaybeUninit<u8>],
+}
+#[cfg(feature = "unsafe-perf")]
+impl<'a> From<&'a mut [MaybeUninit<u8>]> for UninitBufOutput<'a> {
+    fn from(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        Self { pos: 0, buf }
+    }
+}
+#[cfg(feature = "unsafe-perf")]
+impl<'a> UninitBufOutput<'a> {
+    /// Split the buffer into what has been written and the space left after it
+    pub fn split(&mut self) -> (&mut [MaybeUninit<u8>], &mut [MaybeUninit<u8>]) {
+        // (clamped so that this can't panic, whatever `pos` is)
+        self.buf.split_at_mut(usize::min(self.pos, self.buf.len()))
+    }
+
+    /// Get the initialized portion of the buffer
+    pub fn into_init(self) -> &'a [u8] {
+        // SAFETY: everything before `pos` has been written,
+        // and `MaybeUninit<u8>` has the same layout as `u8`
+        unsafe { core::slice::from_raw_parts(self.buf.as_ptr().cast::<u8>(), self.pos) }
+    }
+}
+
+/// Output which only counts the number of bytes
+pub struct CountOutput(pub usize);
 
 /// Owned Vec of bytes
-#[cfg(feature = "alloc")]
+#[cfg(all(feature = "alloc", feature = "decompress"))]
 pub struct VecOutput {
     pub vec: alloc::vec::Vec<u8>,
 }
-#[cfg(feature = "alloc")]
+#[cfg(all(feature = "alloc", feature = "decompress"))]
 impl From<alloc::vec::Vec<u8>> for VecOutput {
     fn from(vec: alloc::vec::Vec<u8>) -> Self {
         Self { vec }
     }
 }
+
+/// Convert one of the format's limits to a usize, saturating on 16-bit targets
+///
+/// (Limits which don't fit are further than anything on those targets can reach anyway.)
+pub const fn saturating_usize(x: u32) -> usize {
+    if x as u64 > usize::MAX as u64 {
+        usize::MAX
+    } else {
+        x as usize
+    }
+}
+
+/// Pseudo-random bytes for tests, which are the same on every run
+///
+/// (A simple LCG, of which only the top byte is used, since the lower bits repeat quickly.)
+#[cfg(all(test, feature = "compress"))]
+// (unused by some builds without std, as most of the tests using it need std)
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub fn random_bytes() -> impl Iterator<Item = u8> {
+    let mut x = 1u32;
+    core::iter::repeat_with(move || {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        (x >> 24) as u8
+    })
+}
@@ -1,20 +1,38 @@
+//! The reference C implementation, run as WebAssembly, for checking other code against
+//!
+//! This is public with the `reference-tester` feature, so that downstream crates
+//! (e.g. other encoders or bindings) can compare their output against the C code in their own tests.
+//! The C code is run with the wasmi interpreter, so it is slow.
+
 extern crate std;
 
 const WASM_PAGE_SZ: usize = 65536;
 
+/// The C reference implementation compiled to WebAssembly
+pub const FASTLZ_WASM: &[u8] = include_bytes!("../testtool/fastlz.wasm");
+
+/// An instance of the reference C code
 pub struct FastLZWasm {
     module: wasmi::Module,
     linker: wasmi::Linker<()>,
     store: wasmi::Store<()>,
 }
+impl Default for FastLZWasm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl FastLZWasm {
+    /// Load the reference built by `testtool/build-wasm.sh`, which is embedded into this crate
     pub fn new() -> Self {
+        Self::from_wasm(FASTLZ_WASM)
+    }
+
+    /// Load a different build of the reference
+    pub fn from_wasm(wasm: &[u8]) -> Self {
         let engine = wasmi::Engine::default();
 
-        let srcdir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        let wasm_fn = srcdir.join("testtool/fastlz.wasm");
-        let wasm_f = std::fs::File::open(wasm_fn).unwrap();
-        let module = wasmi::Module::new_streaming(&engine, wasm_f).unwrap();
+        let module = wasmi::Module::new(&engine, wasm).unwrap();
 
         let linker: wasmi::Linker<()> = wasmi::Linker::new(&engine);
 
@@ -27,6 +45,14 @@ impl FastLZWasm {
         }
     }
 
+    /// Compress with `fastlz_compress_level` at the given level (1 or 2), returning the output
+    ///
+    /// (As with the C code, the input must not be empty.)
+    #[cfg(any(
+        feature = "decompress",
+        feature = "level2",
+        feature = "reference-tester"
+    ))]
     pub fn fastlz_compress_level<'s>(&'s mut self, level: u32, input_data: &[u8]) -> &'s [u8] {
         let instance = self
             .linker
@@ -47,7 +73,7 @@ impl FastLZWasm {
         //                                                ^ expanded memory size
 
         let to_alloc_bytes = input_data.len() + input_data.len() * 16;
-        let to_alloc_pages = (to_alloc_bytes + WASM_PAGE_SZ - 1) / WASM_PAGE_SZ;
+        let to_alloc_pages = to_alloc_bytes.div_ceil(WASM_PAGE_SZ);
 
         let cur_mem_sz_pages = mem.grow(&mut self.store, to_alloc_pages as u32).unwrap();
         let cur_mem_sz_bytes = cur_mem_sz_pages as usize * WASM_PAGE_SZ;
@@ -76,6 +102,10 @@ impl FastLZWasm {
         out_data
     }
 
+    /// Decompress with `fastlz_decompress`, returning the output
+    ///
+    /// The output is empty if the C code rejects the input.
+    #[cfg(any(feature = "compress", feature = "reference-tester"))]
     pub fn fastlz_decompress<'s>(&'s mut self, input_data: &[u8]) -> &'s [u8] {
         let instance = self
             .linker
@@ -91,7 +121,7 @@ impl FastLZWasm {
 
         // assume max expansion factor of 1024
         let to_alloc_bytes = input_data.len() + input_data.len() * 1024;
-        let to_alloc_pages = (to_alloc_bytes + WASM_PAGE_SZ - 1) / WASM_PAGE_SZ;
+        let to_alloc_pages = to_alloc_bytes.div_ceil(WASM_PAGE_SZ);
 
         let cur_mem_sz_pages = mem.grow(&mut self.store, to_alloc_pages as u32).unwrap();
         let cur_mem_sz_bytes = cur_mem_sz_pages as usize * WASM_PAGE_SZ;
@@ -0,0 +1,160 @@
+//! Checks that the buffer-based entry points cannot panic, whatever their input
+//!
+//! Each call is wrapped in a guard whose destructor refers to a symbol which does not exist,
+//! so this only links if the optimizer has proven that nothing in the call can unwind.
+//! That needs the whole program optimized together, so this is only built when asked for:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg no_panic" cargo test --profile no-panic \
+//!     --no-default-features --features compress,decompress,level2 --test no_panic
+//! ```
+//!
+//! (Add `unsafe-perf` to also check the functions which need it. With `alloc`, the compressor allocates for some options, and allocation failure panics.
+//! Any callbacks given in the options are of course not covered either.)
+#![cfg(no_panic)]
+
+use core::hint::black_box;
+#[cfg(feature = "unsafe-perf")]
+use core::mem::MaybeUninit;
+
+use fastlz_rs::*;
+
+struct NoPanic;
+impl Drop for NoPanic {
+    fn drop(&mut self) {
+        extern "C" {
+            #[link_name = "\n\nERROR: a function checked by tests/no_panic.rs may panic\n\n"]
+            fn trigger() -> !;
+        }
+        // SAFETY: never actually called, as this is only dropped while unwinding,
+        // and (if this links) nothing can unwind
+        unsafe { trigger() }
+    }
+}
+
+/// Evaluate the expression, failing to link if it could panic
+macro_rules! no_panic {
+    ($e:expr) => {{
+        let guard = NoPanic;
+        let ret = $e;
+        core::mem::forget(guard);
+        ret
+    }};
+}
+
+#[cfg(feature = "decompress")]
+const STREAMS: &[&[u8]] = &[
+    &[],
+    &[0x02, b'a', b'b', b'c'],
+    &[0x00, 0x55, 0xe0, 0xfd, 0x00, 0xe0, 0x1c, 0x00],
+    &[0x20, b'z', 0xe0, 0xff, 0x23, 0x00],
+    // invalid: backreference before the start, truncated, overlong level 2 lengths
+    &[0x00, 0x55, 0x20, 0x05],
+    &[0x1f, 0x54],
+    &[
+        0x20, b'z', 0xe0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ],
+];
+
+#[cfg(feature = "decompress")]
+#[inline(never)]
+fn decompress_all(inp: &[u8], outp: &mut [u8], strict: bool) {
+    let mut opts = DecompressOptions::default();
+    opts.strict_l2_end = strict;
+    let _ = no_panic!(decompress_to_buf(inp, outp));
+    let _ = no_panic!(decompress_to_buf_lenient(inp, outp, &opts));
+    let _ = no_panic!(decompress_to_buf_with_options(inp, outp, &opts));
+    let (a, b) = inp.split_at(inp.len() / 2);
+    let _ = no_panic!(decompress_segments_to_buf(&[a, b], outp));
+    let mut limits = DecompressLimits::default();
+    limits.max_opcodes = Some(2);
+    limits.max_backref_distance = Some(2);
+    limits.max_literal_bytes = Some(2);
+    let _ = no_panic!(decompress_with_limits(inp, outp, &limits));
+    #[cfg(feature = "unsafe-perf")]
+    {
+        let mut uninit = [MaybeUninit::uninit(); 512];
+        let uninit = &mut uninit[..outp.len()];
+        let _ = no_panic!(decompress_to_uninit_buf(inp, uninit).map(|out| out.len()));
+        let _ = no_panic!(
+            decompress_to_uninit_buf_with_options(inp, uninit, &opts).map(|out| out.len())
+        );
+    }
+}
+
+#[cfg(feature = "decompress")]
+#[test]
+fn decompress() {
+    for stream in STREAMS {
+        for len in [0, 1, 3, 300, 512] {
+            let mut outp = [0u8; 512];
+            for strict in [false, true] {
+                decompress_all(
+                    black_box(stream),
+                    black_box(&mut outp[..len]),
+                    black_box(strict),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "compress")]
+#[inline(never)]
+fn compress_all(
+    state: &mut CompressState<256>,
+    inp: &[u8],
+    outp: &mut [u8],
+    level: CompressionLevel,
+    options: u32,
+) {
+    // (built here rather than passed in, as a `default_level_policy` could do anything)
+    let mut opts = CompressOptions::default();
+    opts.c_compatible = options & 1 != 0;
+    opts.two_entry_buckets = options & 2 != 0;
+    opts.lazy_matching = options & 4 != 0;
+    opts.sample_default_level = options & 8 != 0;
+    opts.acceleration = (options >> 4) as usize;
+    let _ = no_panic!(state.compress_to_buf_with_options(inp, outp, level, &opts));
+    #[cfg(feature = "unsafe-perf")]
+    {
+        let mut uninit = [MaybeUninit::uninit(); 1100];
+        let uninit = &mut uninit[..outp.len()];
+        let _ = no_panic!(state
+            .compress_to_uninit_buf_with_options(inp, uninit, level, &opts)
+            .map(|out| out.len()));
+    }
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn compress() {
+    let levels = [
+        CompressionLevel::Level1,
+        #[cfg(feature = "level2")]
+        CompressionLevel::Level2,
+        CompressionLevel::Default,
+        CompressionLevel::Best,
+    ];
+    let mut inp = [0u8; 1000];
+    for (i, c) in inp.iter_mut().enumerate() {
+        *c = (i % 7 + i / 100) as u8;
+    }
+    let mut state = CompressState::<256>::new_sized();
+    for level in levels {
+        for options in 0..32 {
+            for len in [0, 3, 50, 1000] {
+                let mut outp = [0u8; 1100];
+                for out_len in [0, 1, len / 2, 1100] {
+                    compress_all(
+                        &mut state,
+                        black_box(&inp[..len]),
+                        black_box(&mut outp[..out_len]),
+                        black_box(level),
+                        black_box(options),
+                    );
+                }
+            }
+        }
+    }
+}